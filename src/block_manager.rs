@@ -15,11 +15,12 @@ use thinp::io_engine::{IoEngine, BLOCK_SIZE};
 
 //-------------------------------
 
-/// Core store is an io_engine that keeps it's data in ram.
+/// Core store is an io_engine that keeps it's data in ram.  Blocks
+/// are shared copy-on-write between snapshots.
 /// FIXME: move to thinp since might be useful for tests there?
 pub struct CoreEngine {
     nr_blocks: u64,
-    blocks: Mutex<BTreeMap<u64, Vec<u8>>>,
+    blocks: Mutex<BTreeMap<u64, Arc<Vec<u8>>>>,
 }
 
 impl CoreEngine {
@@ -45,6 +46,16 @@ impl CoreEngine {
         let blocks = self.blocks.lock().unwrap();
         blocks.len()
     }
+
+    /// Takes a copy of the engine, the blocks themselves are only
+    /// copied when one side writes to them.
+    pub fn snapshot(&self) -> Self {
+        let blocks = self.blocks.lock().unwrap();
+        CoreEngine {
+            nr_blocks: self.nr_blocks,
+            blocks: Mutex::new(blocks.clone()),
+        }
+    }
 }
 
 impl io_engine::IoEngine for CoreEngine {
@@ -84,11 +95,11 @@ impl io_engine::IoEngine for CoreEngine {
 
         let mut blocks = self.blocks.lock().unwrap();
         if let Some(bytes) = blocks.get_mut(&block.loc) {
-            bytes.copy_from_slice(&block.get_data());
+            Arc::make_mut(bytes).copy_from_slice(&block.get_data());
             Ok(())
         } else {
             // Block isn't present, so we'll create it.
-            blocks.insert(block.loc, block.get_data().to_vec());
+            blocks.insert(block.loc, Arc::new(block.get_data().to_vec()));
             Ok(())
         }
     }
@@ -165,13 +176,14 @@ impl Guest for GBlock {
 
 //-------------------------------
 
+#[derive(Clone)]
 pub enum Lock {
     Read { count: usize, guest_ptr: Addr },
     Write { validator: Addr },
 }
 
 pub struct BlockManager {
-    pub engine: Arc<dyn IoEngine + Sync + Send>,
+    pub engine: Arc<CoreEngine>,
     pub locks: BTreeMap<u64, Lock>,
 
     pub nr_read_locks: u64,
@@ -179,7 +191,7 @@ pub struct BlockManager {
 }
 
 impl BlockManager {
-    pub fn new(engine: Arc<dyn IoEngine + Sync + Send>) -> Self {
+    pub fn new(engine: Arc<CoreEngine>) -> Self {
         BlockManager {
             engine,
             locks: BTreeMap::new(),
@@ -188,6 +200,17 @@ impl BlockManager {
         }
     }
 
+    /// Copies the lock table and the engine.  The locks refer to guest
+    /// memory, so this is only meaningful alongside a vm snapshot.
+    pub fn snapshot(&self) -> Self {
+        BlockManager {
+            engine: Arc::new(self.engine.snapshot()),
+            locks: self.locks.clone(),
+            nr_read_locks: self.nr_read_locks,
+            nr_write_locks: self.nr_write_locks,
+        }
+    }
+
     /*
         fn v_check(&self, vm: &mut VM, guest_ptr: Addr, v_ptr: Addr) -> Result<()> {
             use Reg::*;
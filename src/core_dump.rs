@@ -0,0 +1,256 @@
+use crate::decode::Reg;
+use crate::memory::*;
+use crate::vm::VM;
+
+use std::io::{self, Write};
+
+//-------------------------------
+
+// Writes ELF core files, so a failed test can be examined with gdb.
+// See include/uapi/linux/elf.h.
+
+const ET_CORE: u16 = 4;
+const EM_RISCV: u16 = 243;
+
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+const NT_PRSTATUS: u32 = 1;
+
+// e_phnum only has 16 bits, and every heap block is a mapping of its
+// own.  With more than this many program headers, e_phnum is set to
+// PN_XNUM and the real count goes in the sh_info of section header 0.
+const PN_XNUM: usize = 0xffff;
+
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+const SHDR_SIZE: usize = 64;
+
+// struct elf_prstatus on riscv64.  pr_reg is a user_regs_struct: the
+// pc followed by x1-x31.
+const PRSTATUS_SIZE: usize = 376;
+const PR_CURSIG_OFFSET: usize = 12;
+const PR_REG_OFFSET: usize = 112;
+
+fn prstatus(vm: &VM, signal: u8) -> Vec<u8> {
+    let mut desc = vec![0u8; PRSTATUS_SIZE];
+    desc[PR_CURSIG_OFFSET..(PR_CURSIG_OFFSET + 2)].copy_from_slice(&(signal as u16).to_le_bytes());
+
+    let mut regs = vec![vm.reg(Reg::PC)];
+    for r in 1..32u32 {
+        regs.push(vm.reg(Reg::from(r)));
+    }
+    for (i, v) in regs.iter().enumerate() {
+        let offset = PR_REG_OFFSET + i * 8;
+        desc[offset..(offset + 8)].copy_from_slice(&v.to_le_bytes());
+    }
+    desc
+}
+
+fn pad4(bytes: &mut Vec<u8>) {
+    let len = (bytes.len() + 3) & !3;
+    bytes.resize(len, 0);
+}
+
+fn note(name: &str, kind: u32, desc: &[u8]) -> Vec<u8> {
+    let mut note = Vec::new();
+    note.extend_from_slice(&(name.len() as u32 + 1).to_le_bytes());
+    note.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+    note.extend_from_slice(&kind.to_le_bytes());
+    note.extend_from_slice(name.as_bytes());
+    note.push(0);
+    pad4(&mut note);
+    note.extend_from_slice(desc);
+    pad4(&mut note);
+    note
+}
+
+fn flags(perms: u8) -> u32 {
+    let mut flags = 0;
+    if perms & PERM_READ != 0 {
+        flags |= PF_R;
+    }
+    if perms & PERM_WRITE != 0 {
+        flags |= PF_W;
+    }
+    if perms & PERM_EXEC != 0 {
+        flags |= PF_X;
+    }
+    flags
+}
+
+fn phdr(out: &mut Vec<u8>, kind: u32, flags: u32, offset: u64, vaddr: u64, size: u64, align: u64) {
+    out.extend_from_slice(&kind.to_le_bytes());
+    out.extend_from_slice(&flags.to_le_bytes());
+    out.extend_from_slice(&offset.to_le_bytes());
+    out.extend_from_slice(&vaddr.to_le_bytes());
+    out.extend_from_slice(&0u64.to_le_bytes());
+    out.extend_from_slice(&size.to_le_bytes());
+    out.extend_from_slice(&size.to_le_bytes());
+    out.extend_from_slice(&align.to_le_bytes());
+}
+
+// The null section header that holds the program header count when
+// e_phnum is PN_XNUM.
+fn shdr_xnum(out: &mut Vec<u8>, nr_phdrs: usize) {
+    out.extend_from_slice(&[0; 44]);
+    out.extend_from_slice(&(nr_phdrs as u32).to_le_bytes());
+    out.extend_from_slice(&[0; 16]);
+}
+
+/// Writes a core file holding the registers, and a PT_LOAD segment for
+/// each mapped region of memory.  'signal' is what gdb will say killed
+/// the guest.
+pub fn write_core<W: Write>(vm: &VM, signal: u8, out: &mut W) -> io::Result<()> {
+    let notes = note("CORE", NT_PRSTATUS, &prstatus(vm, signal));
+    let mappings: Vec<(Addr, u8, &[u8])> = vm.mem.mappings().collect();
+    let nr_phdrs = mappings.len() + 1;
+    let xnum = nr_phdrs >= PN_XNUM;
+
+    // The section header, if any, goes after the program headers.
+    let phdrs_end = EHDR_SIZE + nr_phdrs * PHDR_SIZE;
+    let (shoff, shnum, headers_end) = if xnum {
+        (phdrs_end, 1u16, phdrs_end + SHDR_SIZE)
+    } else {
+        (0, 0, phdrs_end)
+    };
+
+    let mut hdr = Vec::with_capacity(headers_end);
+    hdr.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1]);
+    hdr.resize(16, 0);
+    hdr.extend_from_slice(&ET_CORE.to_le_bytes());
+    hdr.extend_from_slice(&EM_RISCV.to_le_bytes());
+    hdr.extend_from_slice(&1u32.to_le_bytes());
+    hdr.extend_from_slice(&0u64.to_le_bytes());
+    hdr.extend_from_slice(&(EHDR_SIZE as u64).to_le_bytes());
+    hdr.extend_from_slice(&(shoff as u64).to_le_bytes());
+    hdr.extend_from_slice(&0u32.to_le_bytes());
+    hdr.extend_from_slice(&(EHDR_SIZE as u16).to_le_bytes());
+    hdr.extend_from_slice(&(PHDR_SIZE as u16).to_le_bytes());
+    hdr.extend_from_slice(&(nr_phdrs.min(PN_XNUM) as u16).to_le_bytes());
+    hdr.extend_from_slice(&(if xnum { SHDR_SIZE as u16 } else { 0 }).to_le_bytes());
+    hdr.extend_from_slice(&shnum.to_le_bytes());
+    hdr.extend_from_slice(&0u16.to_le_bytes());
+
+    // The notes come straight after the headers, then the memory.
+    let mut offset = headers_end as u64;
+    phdr(&mut hdr, PT_NOTE, 0, offset, 0, notes.len() as u64, 4);
+    offset += notes.len() as u64;
+    for (begin, perms, bytes) in &mappings {
+        let len = bytes.len() as u64;
+        phdr(&mut hdr, PT_LOAD, flags(*perms), offset, begin.0, len, 1);
+        offset += len;
+    }
+    if xnum {
+        shdr_xnum(&mut hdr, nr_phdrs);
+    }
+
+    out.write_all(&hdr)?;
+    out.write_all(&notes)?;
+    for (_, _, bytes) in &mappings {
+        out.write_all(bytes)?;
+    }
+    Ok(())
+}
+
+//-------------------------------
+
+#[cfg(test)]
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+#[cfg(test)]
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
+
+#[cfg(test)]
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u32_at(bytes, offset) as u64 | ((u32_at(bytes, offset + 4) as u64) << 32)
+}
+
+#[test]
+fn test_write_core() {
+    use crate::vm::vm_with_code;
+
+    // addi a0,a0,1; ebreak
+    let mut vm = vm_with_code(&[0x00150513, 0x00100073]);
+    let ptr = vm.mem.alloc(16).unwrap();
+    vm.mem.write(ptr, &[0xaa; 16], PERM_WRITE).unwrap();
+    vm.set_pc(Addr(0x1004));
+
+    let mut core = Vec::new();
+    write_core(&vm, 5, &mut core).unwrap();
+
+    assert_eq!(&core[0..4], b"\x7fELF");
+    assert_eq!(u16_at(&core, 16), ET_CORE);
+    assert_eq!(u16_at(&core, 18), EM_RISCV);
+
+    let phoff = u64_at(&core, 32) as usize;
+    let phnum = u16_at(&core, 56) as usize;
+    let mappings: Vec<(Addr, u8, &[u8])> = vm.mem.mappings().collect();
+    assert_eq!(phnum, mappings.len() + 1);
+
+    // The notes, with the registers.
+    let note = phoff;
+    assert_eq!(u32_at(&core, note), PT_NOTE);
+    let desc = u64_at(&core, note + 8) as usize + 20;
+    assert_eq!(u32_at(&core, desc - 12), NT_PRSTATUS);
+    assert_eq!(u16_at(&core, desc + PR_CURSIG_OFFSET), 5);
+    assert_eq!(u64_at(&core, desc + PR_REG_OFFSET), 0x1004);
+
+    // One PT_LOAD per mapping.
+    for (i, (begin, perms, bytes)) in mappings.iter().enumerate() {
+        let phdr = phoff + (i + 1) * PHDR_SIZE;
+        assert_eq!(u32_at(&core, phdr), PT_LOAD);
+        assert_eq!(u32_at(&core, phdr + 4), flags(*perms));
+        assert_eq!(u64_at(&core, phdr + 16), begin.0);
+        assert_eq!(u64_at(&core, phdr + 32), bytes.len() as u64);
+
+        let offset = u64_at(&core, phdr + 8) as usize;
+        assert_eq!(&core[offset..(offset + bytes.len())], *bytes);
+    }
+
+    // The code, then the heap block.
+    assert_eq!(u32_at(&core, phoff + PHDR_SIZE + 4), PF_X);
+    let i = mappings
+        .iter()
+        .position(|(begin, _, _)| *begin == ptr)
+        .unwrap();
+    assert_eq!(u32_at(&core, phoff + (i + 1) * PHDR_SIZE + 4), PF_R | PF_W);
+}
+
+#[test]
+fn test_write_core_xnum() {
+    let mut vm = VM::new(Memory::new(Addr(0x100000), Addr(0x100000 + (1 << 24))));
+    for _ in 0..PN_XNUM {
+        vm.mem.alloc(8).unwrap();
+    }
+
+    let mut core = Vec::new();
+    write_core(&vm, 5, &mut core).unwrap();
+
+    // The real count is in section header 0.
+    assert_eq!(u16_at(&core, 56) as usize, PN_XNUM);
+    let shoff = u64_at(&core, 40) as usize;
+    assert_eq!(u16_at(&core, 58) as usize, SHDR_SIZE);
+    assert_eq!(u16_at(&core, 60), 1);
+    assert_eq!(u32_at(&core, shoff + 44) as usize, PN_XNUM + 1);
+
+    // The last PT_LOAD is the last heap block.
+    let (begin, _, _) = vm.mem.mappings().last().unwrap();
+    let phdr = u64_at(&core, 32) as usize + PN_XNUM * PHDR_SIZE;
+    assert_eq!(u32_at(&core, phdr), PT_LOAD);
+    assert_eq!(u64_at(&core, phdr + 16), begin.0);
+    assert!(u64_at(&core, phdr + 8) as usize > shoff);
+}
@@ -0,0 +1,353 @@
+use crate::decode::{Inst, Reg};
+
+//-------------------------------
+
+/// Broad classes of instruction, which a cost model may charge
+/// differently.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InstClass {
+    Alu,
+    Load,
+    Store,
+    Atomic,
+    Mul,
+    Div,
+    Branch,
+    Jump,
+    System,
+}
+
+impl InstClass {
+    pub fn of(inst: &Inst) -> Self {
+        use Inst::*;
+        use InstClass::*;
+
+        match inst {
+            JAL { .. } | JALR { .. } => Jump,
+            BEQ { .. } | BNE { .. } | BLT { .. } | BGE { .. } | BLTU { .. } | BGEU { .. } => Branch,
+            LB { .. }
+            | LH { .. }
+            | LW { .. }
+            | LD { .. }
+            | LBU { .. }
+            | LHU { .. }
+            | LWU { .. } => Load,
+            SB { .. } | SH { .. } | SW { .. } | SD { .. } => Store,
+            MUL { .. } | MULH { .. } | MULHSU { .. } | MULHU { .. } | MULW { .. } => Mul,
+            DIV { .. }
+            | DIVU { .. }
+            | DIVW { .. }
+            | DIVUW { .. }
+            | REM { .. }
+            | REMU { .. }
+            | REMW { .. }
+            | REMUW { .. } => Div,
+            LRW { .. }
+            | SCW { .. }
+            | AMOSWAPW { .. }
+            | AMOADDW { .. }
+            | AMOXORW { .. }
+            | AMOANDW { .. }
+            | AMOORW { .. }
+            | AMOMINW { .. }
+            | AMOMAXW { .. }
+            | AMOMINUW { .. }
+            | AMOMAXUW { .. }
+            | LRD { .. }
+            | SCD { .. }
+            | AMOSWAPD { .. }
+            | AMOADDD { .. }
+            | AMOXORD { .. }
+            | AMOANDD { .. }
+            | AMOORD { .. }
+            | AMOMIND { .. }
+            | AMOMAXD { .. }
+            | AMOMINUD { .. }
+            | AMOMAXUD { .. } => Atomic,
+            FENCE
+            | FENCEI
+            | ECALL
+            | EBREAK
+            | WFI
+            | SFENCEVMA { .. }
+            | CSRRW { .. }
+            | CSRRS { .. }
+            | CSRRC { .. }
+            | CSRRWI { .. }
+            | CSRRSI { .. }
+            | CSRRCI { .. }
+            | CBOCLEAN { .. }
+            | CBOFLUSH { .. }
+            | CBOINVAL { .. } => System,
+            _ => Alu,
+        }
+    }
+}
+
+/// A data access made by an instruction, or by a stub on the guest's
+/// behalf.
+#[derive(Clone, Copy, Debug)]
+pub struct Access {
+    pub addr: u64,
+    pub len: u64,
+    pub write: bool,
+}
+
+impl Access {
+    /// The access an instruction is about to make, given the registers
+    /// before it executes.  None if it doesn't touch memory.
+    pub fn of(inst: &Inst, reg: &dyn Fn(Reg) -> u64) -> Option<Self> {
+        use Inst::*;
+
+        let (base, imm, len, write) = match *inst {
+            LB { rs, imm, .. } | LBU { rs, imm, .. } => (rs, imm, 1, false),
+            LH { rs, imm, .. } | LHU { rs, imm, .. } => (rs, imm, 2, false),
+            LW { rs, imm, .. } | LWU { rs, imm, .. } => (rs, imm, 4, false),
+            LD { rs, imm, .. } => (rs, imm, 8, false),
+            SB { rs1, imm, .. } => (rs1, imm, 1, true),
+            SH { rs1, imm, .. } => (rs1, imm, 2, true),
+            SW { rs1, imm, .. } => (rs1, imm, 4, true),
+            SD { rs1, imm, .. } => (rs1, imm, 8, true),
+            LRW { rs, .. } => (rs, 0, 4, false),
+            LRD { rs, .. } => (rs, 0, 8, false),
+            SCW { rs1, .. }
+            | AMOSWAPW { rs1, .. }
+            | AMOADDW { rs1, .. }
+            | AMOXORW { rs1, .. }
+            | AMOANDW { rs1, .. }
+            | AMOORW { rs1, .. }
+            | AMOMINW { rs1, .. }
+            | AMOMAXW { rs1, .. }
+            | AMOMINUW { rs1, .. }
+            | AMOMAXUW { rs1, .. } => (rs1, 0, 4, true),
+            SCD { rs1, .. }
+            | AMOSWAPD { rs1, .. }
+            | AMOADDD { rs1, .. }
+            | AMOXORD { rs1, .. }
+            | AMOANDD { rs1, .. }
+            | AMOORD { rs1, .. }
+            | AMOMIND { rs1, .. }
+            | AMOMAXD { rs1, .. }
+            | AMOMINUD { rs1, .. }
+            | AMOMAXUD { rs1, .. } => (rs1, 0, 8, true),
+            _ => return None,
+        };
+
+        Some(Access {
+            addr: reg(base).wrapping_add(imm as i64 as u64),
+            len,
+            write,
+        })
+    }
+}
+
+//-------------------------------
+
+/// Estimates the cycles the guest would take on real hardware.  The vm
+/// consults this for every instruction it executes, and stubs for the
+/// work they do on the guest's behalf, see VM::charge().
+pub trait CostModel {
+    /// Cycles taken by an instruction, not counting any data access.
+    /// 'taken' says whether a branch was taken.
+    fn instr(&mut self, class: InstClass, taken: bool) -> u64;
+
+    /// Extra cycles taken by a data access, eg, a cache miss.
+    fn access(&mut self, _access: &Access) -> u64 {
+        0
+    }
+
+    /// Whether access() should be called.  Working out the address of
+    /// every load and store slows the vm down, so models that don't
+    /// care should say so.
+    fn models_memory(&self) -> bool {
+        false
+    }
+}
+
+/// Cycles for each class of instruction.  The default charges one
+/// cycle for everything, so cycles match instructions.
+#[derive(Clone, Debug)]
+pub struct Weights {
+    pub alu: u64,
+    pub load: u64,
+    pub store: u64,
+    pub atomic: u64,
+    pub mul: u64,
+    pub div: u64,
+    pub branch: u64,
+    pub branch_taken: u64,
+    pub jump: u64,
+    pub system: u64,
+}
+
+impl Default for Weights {
+    fn default() -> Self {
+        Weights {
+            alu: 1,
+            load: 1,
+            store: 1,
+            atomic: 1,
+            mul: 1,
+            div: 1,
+            branch: 1,
+            branch_taken: 1,
+            jump: 1,
+            system: 1,
+        }
+    }
+}
+
+impl Weights {
+    /// Rough figures for a simple in-order core.
+    pub fn in_order() -> Self {
+        Weights {
+            alu: 1,
+            load: 2,
+            store: 1,
+            atomic: 4,
+            mul: 3,
+            div: 20,
+            branch: 1,
+            branch_taken: 3,
+            jump: 2,
+            system: 5,
+        }
+    }
+
+    pub fn get(&self, class: InstClass, taken: bool) -> u64 {
+        use InstClass::*;
+
+        match class {
+            Alu => self.alu,
+            Load => self.load,
+            Store => self.store,
+            Atomic => self.atomic,
+            Mul => self.mul,
+            Div => self.div,
+            Branch if taken => self.branch_taken,
+            Branch => self.branch,
+            Jump => self.jump,
+            System => self.system,
+        }
+    }
+}
+
+/// A set associative data cache with LRU replacement.  Each line
+/// missed costs 'miss_penalty' cycles.  Writes allocate.
+pub struct Cache {
+    line_shift: u32,
+    nr_sets: u64,
+    ways: usize,
+    miss_penalty: u64,
+
+    // Line addresses, nr_sets * ways of them, most recently used first
+    // within each set.
+    lines: Vec<Option<u64>>,
+
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl Cache {
+    /// 'line_size' and 'nr_sets' must be powers of two.
+    pub fn new(line_size: u64, nr_sets: u64, ways: usize, miss_penalty: u64) -> Self {
+        assert!(line_size.is_power_of_two() && nr_sets.is_power_of_two() && ways > 0);
+        Cache {
+            line_shift: line_size.trailing_zeros(),
+            nr_sets,
+            ways,
+            miss_penalty,
+            lines: vec![None; nr_sets as usize * ways],
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    // Touches a single line, returning true if it was a hit.
+    fn touch(&mut self, line: u64) -> bool {
+        let set = (line & (self.nr_sets - 1)) as usize;
+        let ways = &mut self.lines[(set * self.ways)..((set + 1) * self.ways)];
+        let (hit, pos) = match ways.iter().position(|l| *l == Some(line)) {
+            Some(pos) => (true, pos),
+            None => (false, self.ways - 1),
+        };
+        ways[pos] = Some(line);
+        ways[..=pos].rotate_right(1);
+        hit
+    }
+
+    /// Cycles lost to misses for an access.
+    pub fn access(&mut self, access: &Access) -> u64 {
+        if access.len == 0 {
+            return 0;
+        }
+
+        let first = access.addr >> self.line_shift;
+        let last = (access.addr + access.len - 1) >> self.line_shift;
+        let mut cycles = 0;
+        for line in first..=last {
+            if self.touch(line) {
+                self.hits += 1;
+            } else {
+                self.misses += 1;
+                cycles += self.miss_penalty;
+            }
+        }
+        cycles
+    }
+}
+
+/// Charges instructions by class, with an optional data cache.
+#[derive(Default)]
+pub struct SimpleCost {
+    pub weights: Weights,
+    pub cache: Option<Cache>,
+}
+
+impl CostModel for SimpleCost {
+    fn instr(&mut self, class: InstClass, taken: bool) -> u64 {
+        self.weights.get(class, taken)
+    }
+
+    fn access(&mut self, access: &Access) -> u64 {
+        match &mut self.cache {
+            Some(cache) => cache.access(access),
+            None => 0,
+        }
+    }
+
+    fn models_memory(&self) -> bool {
+        self.cache.is_some()
+    }
+}
+
+//-------------------------------
+
+#[test]
+fn test_cache() {
+    // 2 sets of 2 ways, 64 byte lines.
+    let mut cache = Cache::new(64, 2, 2, 10);
+    let read = |addr, len| Access {
+        addr,
+        len,
+        write: false,
+    };
+
+    assert_eq!(cache.access(&read(0, 8)), 10);
+    assert_eq!(cache.access(&read(8, 8)), 0);
+
+    // Spans two lines, one of which is cached.
+    assert_eq!(cache.access(&read(60, 8)), 10);
+
+    // Lines 0 and 2 share a set, as does 4, which evicts line 0 as
+    // it's least recently used.
+    assert_eq!(cache.access(&read(128, 8)), 10);
+    assert_eq!(cache.access(&read(256, 8)), 10);
+    assert_eq!(cache.access(&read(128, 8)), 0);
+    assert_eq!(cache.access(&read(0, 8)), 10);
+
+    assert_eq!(cache.hits, 3);
+    assert_eq!(cache.misses, 5);
+}
+
+//-------------------------------
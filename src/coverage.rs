@@ -0,0 +1,221 @@
+use crate::dwarf::LineTable;
+use crate::loader::Module;
+use crate::memory::{Addr, Memory};
+
+use anyhow::Result;
+use elf::types::STT_FUNC;
+use std::collections::BTreeMap;
+use std::io::Write;
+
+//-------------------------------
+
+/// Execution counts for instruction addresses.
+pub type Hits = BTreeMap<u64, u64>;
+
+pub fn merge_hits(total: &mut Hits, hits: &Hits) {
+    for (addr, count) in hits {
+        *total.entry(*addr).or_insert(0) += count;
+    }
+}
+
+//-------------------------------
+
+struct Function {
+    name: String,
+    begin: u64,
+    instrs: Vec<u64>,
+}
+
+// Walks the instructions in a function, stepping over compressed
+// instructions correctly.
+fn function_instrs(mem: &Memory, begin: u64, size: u64) -> Vec<u64> {
+    let mut instrs = Vec::new();
+    let mut addr = begin;
+    while addr < begin + size {
+        let mut bytes = [0u8; 2];
+        if mem.read(Addr(addr), &mut bytes, 0).is_err() {
+            break;
+        }
+        instrs.push(addr);
+        addr += if (bytes[0] & 3) == 3 { 4 } else { 2 };
+    }
+    instrs
+}
+
+/// Instructions hit for a single function.
+pub struct FunctionCoverage {
+    pub name: String,
+    pub hit: usize,
+    pub total: usize,
+}
+
+#[derive(Default)]
+struct FileCoverage {
+    // line -> count
+    lines: BTreeMap<u32, u64>,
+
+    // name -> (line, count)
+    functions: BTreeMap<String, (u32, u64)>,
+}
+
+/// The static information needed to turn instruction hits into
+/// coverage reports.  This is the same for every fixture, so can be
+/// built once and used with the hits from many tests.
+pub struct CoverageMap {
+    functions: Vec<Function>,
+    lines: LineTable,
+}
+
+impl CoverageMap {
+    pub fn new(module: &Module, mem: &Memory) -> Result<Self> {
+        let mut functions = Vec::new();
+        for (name, sym) in &module.symbols {
+            if sym.symtype == STT_FUNC && sym.shndx != 0 && sym.size > 0 {
+                functions.push(Function {
+                    name: name.clone(),
+                    begin: sym.value,
+                    instrs: function_instrs(mem, sym.value, sym.size),
+                });
+            }
+        }
+
+        let lines = LineTable::new(&module.debug_sections()?)?;
+        Ok(CoverageMap { functions, lines })
+    }
+
+    /// Instructions hit against total instructions for each function.
+    pub fn summary(&self, hits: &Hits) -> Vec<FunctionCoverage> {
+        self.functions
+            .iter()
+            .map(|f| FunctionCoverage {
+                name: f.name.clone(),
+                hit: f.instrs.iter().filter(|i| hits.contains_key(i)).count(),
+                total: f.instrs.len(),
+            })
+            .collect()
+    }
+
+    pub fn write_summary<W: Write>(&self, hits: &Hits, w: &mut W) -> Result<()> {
+        let mut hit = 0;
+        let mut total = 0;
+
+        writeln!(
+            w,
+            "{:<50} {:>6} {:>6} {:>6}",
+            "function", "hit", "total", "%"
+        )?;
+        for f in self.summary(hits) {
+            writeln!(
+                w,
+                "{:<50} {:>6} {:>6} {:>6.1}",
+                f.name,
+                f.hit,
+                f.total,
+                percent(f.hit, f.total)
+            )?;
+            hit += f.hit;
+            total += f.total;
+        }
+        writeln!(
+            w,
+            "{:<50} {:>6} {:>6} {:>6.1}",
+            "TOTAL",
+            hit,
+            total,
+            percent(hit, total)
+        )?;
+        Ok(())
+    }
+
+    fn file_coverage(&self, hits: &Hits) -> BTreeMap<usize, FileCoverage> {
+        let mut files: BTreeMap<usize, FileCoverage> = BTreeMap::new();
+
+        for f in &self.functions {
+            if let Some((file, line)) = self.lines.lookup(f.begin) {
+                let count = hits.get(&f.begin).cloned().unwrap_or(0);
+                files
+                    .entry(file)
+                    .or_default()
+                    .functions
+                    .insert(f.name.clone(), (line, count));
+            }
+
+            // A line may have several instructions, we take the highest count.
+            for i in &f.instrs {
+                if let Some((file, line)) = self.lines.lookup(*i) {
+                    let count = hits.get(i).cloned().unwrap_or(0);
+                    let e = files
+                        .entry(file)
+                        .or_default()
+                        .lines
+                        .entry(line)
+                        .or_insert(0);
+                    *e = u64::max(*e, count);
+                }
+            }
+        }
+
+        files
+    }
+
+    /// Writes an lcov tracefile, which genhtml and friends understand.
+    pub fn write_lcov<W: Write>(&self, test_name: &str, hits: &Hits, w: &mut W) -> Result<()> {
+        // Test names may only contain letters, digits and underscores.
+        let test_name: String = test_name
+            .trim_matches('/')
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+
+        for (file, cov) in self.file_coverage(hits) {
+            writeln!(w, "TN:{}", test_name)?;
+            writeln!(w, "SF:{}", self.lines.file(file))?;
+
+            for (name, (line, _)) in &cov.functions {
+                writeln!(w, "FN:{},{}", line, name)?;
+            }
+            for (name, (_, count)) in &cov.functions {
+                writeln!(w, "FNDA:{},{}", count, name)?;
+            }
+            writeln!(w, "FNF:{}", cov.functions.len())?;
+            writeln!(
+                w,
+                "FNH:{}",
+                cov.functions.values().filter(|(_, c)| *c > 0).count()
+            )?;
+
+            for (line, count) in &cov.lines {
+                writeln!(w, "DA:{},{}", line, count)?;
+            }
+            writeln!(w, "LF:{}", cov.lines.len())?;
+            writeln!(w, "LH:{}", cov.lines.values().filter(|c| **c > 0).count())?;
+            writeln!(w, "end_of_record")?;
+        }
+
+        Ok(())
+    }
+}
+
+fn percent(n: usize, total: usize) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        (n as f64 * 100.0) / total as f64
+    }
+}
+
+//-------------------------------
+
+#[test]
+fn test_merge_hits() {
+    let mut total = Hits::new();
+    let mut hits = Hits::new();
+    hits.insert(0x100, 2);
+    hits.insert(0x104, 1);
+    merge_hits(&mut total, &hits);
+    merge_hits(&mut total, &hits);
+    assert_eq!(total.get(&0x100), Some(&4));
+    assert_eq!(total.get(&0x104), Some(&2));
+}
+
+//-------------------------------
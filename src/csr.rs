@@ -0,0 +1,172 @@
+use std::collections::BTreeMap;
+
+//-------------------------------
+
+// Supervisor level CSRs, these are the only ones a kernel module touches.
+pub const CSR_SSTATUS: u32 = 0x100;
+pub const CSR_SIE: u32 = 0x104;
+pub const CSR_STVEC: u32 = 0x105;
+pub const CSR_SCOUNTEREN: u32 = 0x106;
+pub const CSR_SSCRATCH: u32 = 0x140;
+pub const CSR_SEPC: u32 = 0x141;
+pub const CSR_SCAUSE: u32 = 0x142;
+pub const CSR_STVAL: u32 = 0x143;
+pub const CSR_SIP: u32 = 0x144;
+pub const CSR_SATP: u32 = 0x180;
+
+// Unprivileged counters, read only.
+pub const CSR_CYCLE: u32 = 0xc00;
+pub const CSR_TIME: u32 = 0xc01;
+pub const CSR_INSTRET: u32 = 0xc02;
+
+// sstatus fields
+pub const SSTATUS_SIE: u64 = 1 << 1;
+pub const SSTATUS_SPIE: u64 = 1 << 5;
+pub const SSTATUS_SPP: u64 = 1 << 8;
+pub const SSTATUS_SUM: u64 = 1 << 18;
+pub const SSTATUS_MXR: u64 = 1 << 19;
+
+// UXL is hard wired to 64bit.
+const SSTATUS_UXL_64: u64 = 2 << 32;
+const SSTATUS_WRITABLE: u64 = SSTATUS_SIE | SSTATUS_SPIE | SSTATUS_SPP | SSTATUS_SUM | SSTATUS_MXR;
+
+pub fn csr_name(csr: u32) -> Option<&'static str> {
+    let name = match csr {
+        CSR_SSTATUS => "sstatus",
+        CSR_SIE => "sie",
+        CSR_STVEC => "stvec",
+        CSR_SCOUNTEREN => "scounteren",
+        CSR_SSCRATCH => "sscratch",
+        CSR_SEPC => "sepc",
+        CSR_SCAUSE => "scause",
+        CSR_STVAL => "stval",
+        CSR_SIP => "sip",
+        CSR_SATP => "satp",
+        CSR_CYCLE => "cycle",
+        CSR_TIME => "time",
+        CSR_INSTRET => "instret",
+        _ => return None,
+    };
+    Some(name)
+}
+
+//-------------------------------
+
+/// The control and status registers of our single hart.  We run as
+/// if in supervisor mode, with interrupts enabled, but no interrupt
+/// is ever delivered.
+#[derive(Clone)]
+pub struct CsrFile {
+    sstatus: u64,
+
+    // Registers with no side effects, we just remember what was written.
+    plain: BTreeMap<u32, u64>,
+}
+
+impl Default for CsrFile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CsrFile {
+    pub fn new() -> Self {
+        let mut plain = BTreeMap::new();
+        for csr in &[
+            CSR_SIE,
+            CSR_STVEC,
+            CSR_SCOUNTEREN,
+            CSR_SSCRATCH,
+            CSR_SEPC,
+            CSR_SCAUSE,
+            CSR_STVAL,
+            CSR_SIP,
+            CSR_SATP,
+        ] {
+            plain.insert(*csr, 0);
+        }
+
+        CsrFile {
+            sstatus: SSTATUS_UXL_64 | SSTATUS_SIE,
+            plain,
+        }
+    }
+
+    /// Reads a csr.  'instrs' is the number of instructions retired
+    /// so far, which drives the counters; time is measured in
+    /// instructions too, so it's deterministic.  Returns None if the
+    /// csr isn't implemented.
+    pub fn read(&self, csr: u32, instrs: u64) -> Option<u64> {
+        match csr {
+            CSR_SSTATUS => Some(self.sstatus),
+            CSR_CYCLE | CSR_TIME | CSR_INSTRET => Some(instrs),
+            _ => self.plain.get(&csr).cloned(),
+        }
+    }
+
+    /// Returns false if the csr isn't implemented, or is read only.
+    pub fn write(&mut self, csr: u32, v: u64) -> bool {
+        match csr {
+            CSR_SSTATUS => {
+                self.sstatus = (self.sstatus & !SSTATUS_WRITABLE) | (v & SSTATUS_WRITABLE);
+                true
+            }
+            _ => {
+                if let Some(old) = self.plain.get_mut(&csr) {
+                    *old = v;
+                    true
+                } else {
+                    false
+                }
+            }
+        }
+    }
+
+    pub fn interrupts_enabled(&self) -> bool {
+        (self.sstatus & SSTATUS_SIE) != 0
+    }
+
+    pub fn set_interrupts_enabled(&mut self, enabled: bool) {
+        if enabled {
+            self.sstatus |= SSTATUS_SIE;
+        } else {
+            self.sstatus &= !SSTATUS_SIE;
+        }
+    }
+}
+
+//-------------------------------
+
+#[test]
+fn test_sstatus_sie() {
+    let mut csrs = CsrFile::new();
+    assert!(csrs.interrupts_enabled());
+
+    // local_irq_save()
+    let old = csrs.read(CSR_SSTATUS, 0).unwrap();
+    assert!(csrs.write(CSR_SSTATUS, old & !SSTATUS_SIE));
+    assert!(!csrs.interrupts_enabled());
+
+    // local_irq_restore()
+    assert!(csrs.write(
+        CSR_SSTATUS,
+        csrs.read(CSR_SSTATUS, 0).unwrap() | (old & SSTATUS_SIE)
+    ));
+    assert!(csrs.interrupts_enabled());
+
+    // UXL isn't writeable
+    assert!(csrs.write(CSR_SSTATUS, 0));
+    assert_eq!(csrs.read(CSR_SSTATUS, 0).unwrap(), SSTATUS_UXL_64);
+}
+
+#[test]
+fn test_counters_read_only() {
+    let mut csrs = CsrFile::new();
+    assert_eq!(csrs.read(CSR_INSTRET, 1234), Some(1234));
+    assert_eq!(csrs.read(CSR_CYCLE, 1234), Some(1234));
+    assert!(!csrs.write(CSR_CYCLE, 0));
+    assert!(!csrs.write(0x300, 0));
+    assert_eq!(csrs.read(0x300, 0), None);
+}
+
+//-------------------------------
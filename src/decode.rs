@@ -1,3 +1,5 @@
+use crate::csr::csr_name;
+
 use std::fmt;
 
 //-------------------------------
@@ -106,7 +108,7 @@ fn sign_extend(x: i32, nbits: u32) -> i32 {
     x.wrapping_shl(n).wrapping_shr(n)
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub enum Inst {
     LUI { rd: Reg, imm: i32 },
     AUIPC { rd: Reg, imm: i32 },
@@ -186,6 +188,61 @@ pub enum Inst {
 
     ECALL,
     EBREAK,
+    WFI,
+    SFENCEVMA { rs1: Reg, rs2: Reg },
+
+    // Zicsr
+    CSRRW { rd: Reg, rs: Reg, csr: u32 },
+    CSRRS { rd: Reg, rs: Reg, csr: u32 },
+    CSRRC { rd: Reg, rs: Reg, csr: u32 },
+    CSRRWI { rd: Reg, uimm: u32, csr: u32 },
+    CSRRSI { rd: Reg, uimm: u32, csr: u32 },
+    CSRRCI { rd: Reg, uimm: u32, csr: u32 },
+
+    // Zba
+    SH1ADD { rd: Reg, rs1: Reg, rs2: Reg },
+    SH2ADD { rd: Reg, rs1: Reg, rs2: Reg },
+    SH3ADD { rd: Reg, rs1: Reg, rs2: Reg },
+    ADDUW { rd: Reg, rs1: Reg, rs2: Reg },
+    SH1ADDUW { rd: Reg, rs1: Reg, rs2: Reg },
+    SH2ADDUW { rd: Reg, rs1: Reg, rs2: Reg },
+    SH3ADDUW { rd: Reg, rs1: Reg, rs2: Reg },
+    SLLIUW { rd: Reg, rs: Reg, shamt: u32 },
+
+    // Zbb
+    ANDN { rd: Reg, rs1: Reg, rs2: Reg },
+    ORN { rd: Reg, rs1: Reg, rs2: Reg },
+    XNOR { rd: Reg, rs1: Reg, rs2: Reg },
+    CLZ { rd: Reg, rs: Reg },
+    CLZW { rd: Reg, rs: Reg },
+    CTZ { rd: Reg, rs: Reg },
+    CTZW { rd: Reg, rs: Reg },
+    CPOP { rd: Reg, rs: Reg },
+    CPOPW { rd: Reg, rs: Reg },
+    MAX { rd: Reg, rs1: Reg, rs2: Reg },
+    MAXU { rd: Reg, rs1: Reg, rs2: Reg },
+    MIN { rd: Reg, rs1: Reg, rs2: Reg },
+    MINU { rd: Reg, rs1: Reg, rs2: Reg },
+    SEXTB { rd: Reg, rs: Reg },
+    SEXTH { rd: Reg, rs: Reg },
+    ZEXTH { rd: Reg, rs: Reg },
+    ROL { rd: Reg, rs1: Reg, rs2: Reg },
+    ROLW { rd: Reg, rs1: Reg, rs2: Reg },
+    ROR { rd: Reg, rs1: Reg, rs2: Reg },
+    RORW { rd: Reg, rs1: Reg, rs2: Reg },
+    RORI { rd: Reg, rs: Reg, shamt: u32 },
+    RORIW { rd: Reg, rs: Reg, shamt: u32 },
+    ORCB { rd: Reg, rs: Reg },
+    REV8 { rd: Reg, rs: Reg },
+
+    // Zicond
+    CZEROEQZ { rd: Reg, rs1: Reg, rs2: Reg },
+    CZERONEZ { rd: Reg, rs1: Reg, rs2: Reg },
+
+    // Zicbom
+    CBOCLEAN { rs: Reg },
+    CBOFLUSH { rs: Reg },
+    CBOINVAL { rs: Reg },
 
     // atomics
     LRW { rd: Reg, rs: Reg },
@@ -319,6 +376,100 @@ impl fmt::Display for Inst {
 
             ECALL => write!(f, "ecall"),
             EBREAK => write!(f, "ebreak"),
+            WFI => write!(f, "wfi"),
+            SFENCEVMA { rs1, rs2 } => write!(f, "sfence.vma\t{},{}", rs1, rs2),
+
+            CSRRW { rd, rs, csr } => {
+                if *rd == Zero {
+                    write!(f, "csrw\t{},{}", CsrName(*csr), rs)
+                } else {
+                    write!(f, "csrrw\t{},{},{}", rd, CsrName(*csr), rs)
+                }
+            }
+            CSRRS { rd, rs, csr } => {
+                if *rs == Zero {
+                    write!(f, "csrr\t{},{}", rd, CsrName(*csr))
+                } else if *rd == Zero {
+                    write!(f, "csrs\t{},{}", CsrName(*csr), rs)
+                } else {
+                    write!(f, "csrrs\t{},{},{}", rd, CsrName(*csr), rs)
+                }
+            }
+            CSRRC { rd, rs, csr } => {
+                if *rd == Zero {
+                    write!(f, "csrc\t{},{}", CsrName(*csr), rs)
+                } else {
+                    write!(f, "csrrc\t{},{},{}", rd, CsrName(*csr), rs)
+                }
+            }
+            CSRRWI { rd, uimm, csr } => {
+                if *rd == Zero {
+                    write!(f, "csrwi\t{},{}", CsrName(*csr), uimm)
+                } else {
+                    write!(f, "csrrwi\t{},{},{}", rd, CsrName(*csr), uimm)
+                }
+            }
+            CSRRSI { rd, uimm, csr } => {
+                if *rd == Zero {
+                    write!(f, "csrsi\t{},{}", CsrName(*csr), uimm)
+                } else {
+                    write!(f, "csrrsi\t{},{},{}", rd, CsrName(*csr), uimm)
+                }
+            }
+            CSRRCI { rd, uimm, csr } => {
+                if *rd == Zero {
+                    write!(f, "csrci\t{},{}", CsrName(*csr), uimm)
+                } else {
+                    write!(f, "csrrci\t{},{},{}", rd, CsrName(*csr), uimm)
+                }
+            }
+
+            SH1ADD { rd, rs1, rs2 } => write!(f, "sh1add\t{},{},{}", rd, rs1, rs2),
+            SH2ADD { rd, rs1, rs2 } => write!(f, "sh2add\t{},{},{}", rd, rs1, rs2),
+            SH3ADD { rd, rs1, rs2 } => write!(f, "sh3add\t{},{},{}", rd, rs1, rs2),
+            ADDUW { rd, rs1, rs2 } => {
+                if *rs2 == Zero {
+                    write!(f, "zext.w\t{},{}", rd, rs1)
+                } else {
+                    write!(f, "add.uw\t{},{},{}", rd, rs1, rs2)
+                }
+            }
+            SH1ADDUW { rd, rs1, rs2 } => write!(f, "sh1add.uw\t{},{},{}", rd, rs1, rs2),
+            SH2ADDUW { rd, rs1, rs2 } => write!(f, "sh2add.uw\t{},{},{}", rd, rs1, rs2),
+            SH3ADDUW { rd, rs1, rs2 } => write!(f, "sh3add.uw\t{},{},{}", rd, rs1, rs2),
+            SLLIUW { rd, rs, shamt } => write!(f, "slli.uw\t{},{},{}", rd, rs, shamt),
+
+            ANDN { rd, rs1, rs2 } => write!(f, "andn\t{},{},{}", rd, rs1, rs2),
+            ORN { rd, rs1, rs2 } => write!(f, "orn\t{},{},{}", rd, rs1, rs2),
+            XNOR { rd, rs1, rs2 } => write!(f, "xnor\t{},{},{}", rd, rs1, rs2),
+            CLZ { rd, rs } => write!(f, "clz\t{},{}", rd, rs),
+            CLZW { rd, rs } => write!(f, "clzw\t{},{}", rd, rs),
+            CTZ { rd, rs } => write!(f, "ctz\t{},{}", rd, rs),
+            CTZW { rd, rs } => write!(f, "ctzw\t{},{}", rd, rs),
+            CPOP { rd, rs } => write!(f, "cpop\t{},{}", rd, rs),
+            CPOPW { rd, rs } => write!(f, "cpopw\t{},{}", rd, rs),
+            MAX { rd, rs1, rs2 } => write!(f, "max\t{},{},{}", rd, rs1, rs2),
+            MAXU { rd, rs1, rs2 } => write!(f, "maxu\t{},{},{}", rd, rs1, rs2),
+            MIN { rd, rs1, rs2 } => write!(f, "min\t{},{},{}", rd, rs1, rs2),
+            MINU { rd, rs1, rs2 } => write!(f, "minu\t{},{},{}", rd, rs1, rs2),
+            SEXTB { rd, rs } => write!(f, "sext.b\t{},{}", rd, rs),
+            SEXTH { rd, rs } => write!(f, "sext.h\t{},{}", rd, rs),
+            ZEXTH { rd, rs } => write!(f, "zext.h\t{},{}", rd, rs),
+            ROL { rd, rs1, rs2 } => write!(f, "rol\t{},{},{}", rd, rs1, rs2),
+            ROLW { rd, rs1, rs2 } => write!(f, "rolw\t{},{},{}", rd, rs1, rs2),
+            ROR { rd, rs1, rs2 } => write!(f, "ror\t{},{},{}", rd, rs1, rs2),
+            RORW { rd, rs1, rs2 } => write!(f, "rorw\t{},{},{}", rd, rs1, rs2),
+            RORI { rd, rs, shamt } => write!(f, "rori\t{},{},{}", rd, rs, shamt),
+            RORIW { rd, rs, shamt } => write!(f, "roriw\t{},{},{}", rd, rs, shamt),
+            ORCB { rd, rs } => write!(f, "orc.b\t{},{}", rd, rs),
+            REV8 { rd, rs } => write!(f, "rev8\t{},{}", rd, rs),
+
+            CZEROEQZ { rd, rs1, rs2 } => write!(f, "czero.eqz\t{},{},{}", rd, rs1, rs2),
+            CZERONEZ { rd, rs1, rs2 } => write!(f, "czero.nez\t{},{},{}", rd, rs1, rs2),
+
+            CBOCLEAN { rs } => write!(f, "cbo.clean\t0({})", rs),
+            CBOFLUSH { rs } => write!(f, "cbo.flush\t0({})", rs),
+            CBOINVAL { rs } => write!(f, "cbo.inval\t0({})", rs),
 
             LRW { rd, rs } => write!(f, "lr.w {},{}", rd, rs),
             SCW { rd, rs1, rs2 } => write!(f, "sc.w {},{},({})", rd, rs1, rs2),
@@ -346,6 +497,95 @@ impl fmt::Display for Inst {
     }
 }
 
+// Displays a csr by name if we know it, otherwise by number.
+struct CsrName(u32);
+
+impl fmt::Display for CsrName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match csr_name(self.0) {
+            Some(name) => write!(f, "{}", name),
+            None => write!(f, "0x{:x}", self.0),
+        }
+    }
+}
+
+//-------------------------------
+
+/// Optional extensions beyond RV64GC.  Recent compilers will emit
+/// these when the kernel is configured for them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Extension {
+    Zba,
+    Zbb,
+    Zicond,
+    Zicbom,
+}
+
+impl fmt::Display for Extension {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Extension::*;
+        let name = match self {
+            Zba => "zba",
+            Zbb => "zbb",
+            Zicond => "zicond",
+            Zicbom => "zicbom",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl Inst {
+    /// Returns the optional extension this instruction belongs to, if any.
+    pub fn extension(&self) -> Option<Extension> {
+        use Extension::*;
+        use Inst::*;
+
+        match self {
+            SH1ADD { .. }
+            | SH2ADD { .. }
+            | SH3ADD { .. }
+            | ADDUW { .. }
+            | SH1ADDUW { .. }
+            | SH2ADDUW { .. }
+            | SH3ADDUW { .. }
+            | SLLIUW { .. } => Some(Zba),
+
+            ANDN { .. }
+            | ORN { .. }
+            | XNOR { .. }
+            | CLZ { .. }
+            | CLZW { .. }
+            | CTZ { .. }
+            | CTZW { .. }
+            | CPOP { .. }
+            | CPOPW { .. }
+            | MAX { .. }
+            | MAXU { .. }
+            | MIN { .. }
+            | MINU { .. }
+            | SEXTB { .. }
+            | SEXTH { .. }
+            | ZEXTH { .. }
+            | ROL { .. }
+            | ROLW { .. }
+            | ROR { .. }
+            | RORW { .. }
+            | RORI { .. }
+            | RORIW { .. }
+            | ORCB { .. }
+            | REV8 { .. } => Some(Zbb),
+
+            CZEROEQZ { .. } | CZERONEZ { .. } => Some(Zicond),
+
+            CBOCLEAN { .. } | CBOFLUSH { .. } | CBOINVAL { .. } => Some(Zicbom),
+
+            _ => None,
+        }
+    }
+}
+
+//-------------------------------
+
 /// There are 6 instruction encodings (see spec 2.3)
 #[derive(Debug)]
 struct RType {
@@ -685,12 +925,32 @@ fn decode_32bit_instr(bits: u32) -> Option<Inst> {
                 0b001 => {
                     let mode = (inst.imm >> 6) & 0b111111;
                     let shamt = inst.imm & 0b111111;
-                    match mode {
-                        0b000000 => SLLI {
+                    match (mode, shamt) {
+                        (0b000000, _) => SLLI {
                             rd: inst.rd,
                             rs: inst.rs,
                             shamt,
                         },
+                        (0b011000, 0b000000) => CLZ {
+                            rd: inst.rd,
+                            rs: inst.rs,
+                        },
+                        (0b011000, 0b000001) => CTZ {
+                            rd: inst.rd,
+                            rs: inst.rs,
+                        },
+                        (0b011000, 0b000010) => CPOP {
+                            rd: inst.rd,
+                            rs: inst.rs,
+                        },
+                        (0b011000, 0b000100) => SEXTB {
+                            rd: inst.rd,
+                            rs: inst.rs,
+                        },
+                        (0b011000, 0b000101) => SEXTH {
+                            rd: inst.rd,
+                            rs: inst.rs,
+                        },
                         _ => {
                             return None;
                         }
@@ -710,6 +970,19 @@ fn decode_32bit_instr(bits: u32) -> Option<Inst> {
                             rs: inst.rs,
                             shamt: shamt as u32,
                         },
+                        0b011000 => RORI {
+                            rd: inst.rd,
+                            rs: inst.rs,
+                            shamt: shamt as u32,
+                        },
+                        0b001010 if shamt == 0b000111 => ORCB {
+                            rd: inst.rd,
+                            rs: inst.rs,
+                        },
+                        0b011010 if shamt == 0b111000 => REV8 {
+                            rd: inst.rd,
+                            rs: inst.rs,
+                        },
                         _ => {
                             return None;
                         }
@@ -814,6 +1087,76 @@ fn decode_32bit_instr(bits: u32) -> Option<Inst> {
                     rs1: inst.rs1,
                     rs2: inst.rs2,
                 },
+                (0b0010000, 0b010) => SH1ADD {
+                    rd: inst.rd,
+                    rs1: inst.rs1,
+                    rs2: inst.rs2,
+                },
+                (0b0010000, 0b100) => SH2ADD {
+                    rd: inst.rd,
+                    rs1: inst.rs1,
+                    rs2: inst.rs2,
+                },
+                (0b0010000, 0b110) => SH3ADD {
+                    rd: inst.rd,
+                    rs1: inst.rs1,
+                    rs2: inst.rs2,
+                },
+                (0b0100000, 0b111) => ANDN {
+                    rd: inst.rd,
+                    rs1: inst.rs1,
+                    rs2: inst.rs2,
+                },
+                (0b0100000, 0b110) => ORN {
+                    rd: inst.rd,
+                    rs1: inst.rs1,
+                    rs2: inst.rs2,
+                },
+                (0b0100000, 0b100) => XNOR {
+                    rd: inst.rd,
+                    rs1: inst.rs1,
+                    rs2: inst.rs2,
+                },
+                (0b0000101, 0b110) => MAX {
+                    rd: inst.rd,
+                    rs1: inst.rs1,
+                    rs2: inst.rs2,
+                },
+                (0b0000101, 0b111) => MAXU {
+                    rd: inst.rd,
+                    rs1: inst.rs1,
+                    rs2: inst.rs2,
+                },
+                (0b0000101, 0b100) => MIN {
+                    rd: inst.rd,
+                    rs1: inst.rs1,
+                    rs2: inst.rs2,
+                },
+                (0b0000101, 0b101) => MINU {
+                    rd: inst.rd,
+                    rs1: inst.rs1,
+                    rs2: inst.rs2,
+                },
+                (0b0110000, 0b001) => ROL {
+                    rd: inst.rd,
+                    rs1: inst.rs1,
+                    rs2: inst.rs2,
+                },
+                (0b0110000, 0b101) => ROR {
+                    rd: inst.rd,
+                    rs1: inst.rs1,
+                    rs2: inst.rs2,
+                },
+                (0b0000111, 0b101) => CZEROEQZ {
+                    rd: inst.rd,
+                    rs1: inst.rs1,
+                    rs2: inst.rs2,
+                },
+                (0b0000111, 0b111) => CZERONEZ {
+                    rd: inst.rd,
+                    rs1: inst.rs1,
+                    rs2: inst.rs2,
+                },
                 _ => {
                     return None;
                 }
@@ -830,12 +1173,30 @@ fn decode_32bit_instr(bits: u32) -> Option<Inst> {
                 0b001 => {
                     let mode = (inst.imm >> 5) & 0b1111111;
                     let shamt = (inst.imm & 0b11111) as u32;
-                    match mode {
-                        0b0000000 => SLLIW {
+                    match (mode, shamt) {
+                        (0b0000000, _) => SLLIW {
                             rd: inst.rd,
                             rs: inst.rs,
                             shamt,
                         },
+                        // slli.uw has a 6 bit shamt
+                        (0b0000100, _) | (0b0000101, _) => SLLIUW {
+                            rd: inst.rd,
+                            rs: inst.rs,
+                            shamt: (inst.imm & 0b111111) as u32,
+                        },
+                        (0b0110000, 0b00000) => CLZW {
+                            rd: inst.rd,
+                            rs: inst.rs,
+                        },
+                        (0b0110000, 0b00001) => CTZW {
+                            rd: inst.rd,
+                            rs: inst.rs,
+                        },
+                        (0b0110000, 0b00010) => CPOPW {
+                            rd: inst.rd,
+                            rs: inst.rs,
+                        },
                         _ => {
                             return None;
                         }
@@ -855,6 +1216,11 @@ fn decode_32bit_instr(bits: u32) -> Option<Inst> {
                             rs: inst.rs,
                             shamt,
                         },
+                        0b0110000 => RORIW {
+                            rd: inst.rd,
+                            rs: inst.rs,
+                            shamt,
+                        },
                         _ => {
                             return None;
                         }
@@ -918,19 +1284,111 @@ fn decode_32bit_instr(bits: u32) -> Option<Inst> {
                     rs1: inst.rs1,
                     rs2: inst.rs2,
                 },
+                (0b0000100, 0b000) => ADDUW {
+                    rd: inst.rd,
+                    rs1: inst.rs1,
+                    rs2: inst.rs2,
+                },
+                (0b0010000, 0b010) => SH1ADDUW {
+                    rd: inst.rd,
+                    rs1: inst.rs1,
+                    rs2: inst.rs2,
+                },
+                (0b0010000, 0b100) => SH2ADDUW {
+                    rd: inst.rd,
+                    rs1: inst.rs1,
+                    rs2: inst.rs2,
+                },
+                (0b0010000, 0b110) => SH3ADDUW {
+                    rd: inst.rd,
+                    rs1: inst.rs1,
+                    rs2: inst.rs2,
+                },
+                (0b0000100, 0b100) if inst.rs2 == Reg::Zero => ZEXTH {
+                    rd: inst.rd,
+                    rs: inst.rs1,
+                },
+                (0b0110000, 0b001) => ROLW {
+                    rd: inst.rd,
+                    rs1: inst.rs1,
+                    rs2: inst.rs2,
+                },
+                (0b0110000, 0b101) => RORW {
+                    rd: inst.rd,
+                    rs1: inst.rs1,
+                    rs2: inst.rs2,
+                },
                 _ => {
                     return None;
                 }
             }
         }
-        0b0001111 => FENCE {},
+        0b0001111 => {
+            let inst = IType::from(bits);
+            match (inst.func, inst.imm) {
+                (0b010, 0b000) if inst.rd == Reg::Zero => CBOINVAL { rs: inst.rs },
+                (0b010, 0b001) if inst.rd == Reg::Zero => CBOCLEAN { rs: inst.rs },
+                (0b010, 0b010) if inst.rd == Reg::Zero => CBOFLUSH { rs: inst.rs },
+                (0b010, _) => {
+                    return None;
+                }
+                _ => FENCE {},
+            }
+        }
         0b1110011 => {
-            if (bits >> 7) == 0 {
-                ECALL
-            } else if (bits >> 20) == 1 {
-                EBREAK
-            } else {
-                return None;
+            let inst = IType::from(bits);
+            let csr = bits >> 20;
+            let uimm = (bits >> 15) & 0b11111;
+            match inst.func {
+                0b000 => {
+                    if (bits >> 7) == 0 {
+                        ECALL
+                    } else if (bits >> 20) == 1 {
+                        EBREAK
+                    } else if bits == 0x10500073 {
+                        WFI
+                    } else if (bits >> 25) == 0b0001001 && inst.rd == Reg::Zero {
+                        SFENCEVMA {
+                            rs1: inst.rs,
+                            rs2: reg_at(bits, 20),
+                        }
+                    } else {
+                        return None;
+                    }
+                }
+                0b001 => CSRRW {
+                    rd: inst.rd,
+                    rs: inst.rs,
+                    csr,
+                },
+                0b010 => CSRRS {
+                    rd: inst.rd,
+                    rs: inst.rs,
+                    csr,
+                },
+                0b011 => CSRRC {
+                    rd: inst.rd,
+                    rs: inst.rs,
+                    csr,
+                },
+                0b101 => CSRRWI {
+                    rd: inst.rd,
+                    uimm,
+                    csr,
+                },
+                0b110 => CSRRSI {
+                    rd: inst.rd,
+                    uimm,
+                    csr,
+                },
+                0b111 => CSRRCI {
+                    rd: inst.rd,
+                    uimm,
+                    csr,
+                },
+                _ => {
+                    return None;
+                }
             }
         }
         0b0101111 => {
@@ -1406,3 +1864,54 @@ pub fn decode_instr(bits: u32) -> Option<(Inst, u64)> {
         Some((inst, 2))
     }
 }
+
+//-------------------------------
+
+#[cfg(test)]
+fn disasm(bits: u32) -> String {
+    let (inst, len) = decode_instr(bits).unwrap();
+    assert_eq!(len, 4);
+    format!("{}", inst)
+}
+
+#[test]
+fn test_decode_zba() {
+    assert_eq!(disasm(0x20c5a533), "sh1add\ta0,a1,a2");
+    assert_eq!(disasm(0x20c5e533), "sh3add\ta0,a1,a2");
+    assert_eq!(disasm(0x08c5853b), "add.uw\ta0,a1,a2");
+    assert_eq!(disasm(0x20c5c53b), "sh2add.uw\ta0,a1,a2");
+    assert_eq!(disasm(0x0a35951b), "slli.uw\ta0,a1,35");
+    assert_eq!(
+        decode_instr(0x20c5a533).unwrap().0.extension(),
+        Some(Extension::Zba)
+    );
+}
+
+#[test]
+fn test_decode_zbb() {
+    assert_eq!(disasm(0x40c5f533), "andn\ta0,a1,a2");
+    assert_eq!(disasm(0x40c5c533), "xnor\ta0,a1,a2");
+    assert_eq!(disasm(0x60059513), "clz\ta0,a1");
+    assert_eq!(disasm(0x6015951b), "ctzw\ta0,a1");
+    assert_eq!(disasm(0x60259513), "cpop\ta0,a1");
+    assert_eq!(disasm(0x0ac5d533), "minu\ta0,a1,a2");
+    assert_eq!(disasm(0x60459513), "sext.b\ta0,a1");
+    assert_eq!(disasm(0x0805c53b), "zext.h\ta0,a1");
+    assert_eq!(disasm(0x60c5953b), "rolw\ta0,a1,a2");
+    assert_eq!(disasm(0x6235d513), "rori\ta0,a1,35");
+    assert_eq!(disasm(0x6075d51b), "roriw\ta0,a1,7");
+    assert_eq!(disasm(0x2875d513), "orc.b\ta0,a1");
+    assert_eq!(disasm(0x6b85d513), "rev8\ta0,a1");
+}
+
+#[test]
+fn test_decode_zicond_zicbom() {
+    assert_eq!(disasm(0x0ec5d533), "czero.eqz\ta0,a1,a2");
+    assert_eq!(disasm(0x0ec5f533), "czero.nez\ta0,a1,a2");
+    assert_eq!(disasm(0x0015200f), "cbo.clean\t0(a0)");
+    assert_eq!(disasm(0x0025200f), "cbo.flush\t0(a0)");
+    assert_eq!(disasm(0x0005200f), "cbo.inval\t0(a0)");
+
+    // plain fence is unaffected
+    assert_eq!(decode_instr(0x0ff0000f).unwrap().0.extension(), None);
+}
@@ -0,0 +1,123 @@
+use anyhow::Result;
+use gimli::{EndianSlice, LittleEndian, SectionId};
+use std::collections::BTreeMap;
+
+//-------------------------------
+
+/// Maps instruction addresses to source lines, built from .debug_line.
+pub struct LineTable {
+    // Each row covers addresses up to the next row.  None marks the
+    // end of a sequence.
+    rows: BTreeMap<u64, Option<(usize, u32)>>,
+    files: Vec<String>,
+}
+
+impl LineTable {
+    pub fn new(sections: &BTreeMap<String, Vec<u8>>) -> Result<Self> {
+        let load = |id: SectionId| -> Result<EndianSlice<LittleEndian>> {
+            let data = sections.get(id.name()).map(|v| &v[..]).unwrap_or(&[]);
+            Ok(EndianSlice::new(data, LittleEndian))
+        };
+        let no_sup =
+            |_| -> Result<EndianSlice<LittleEndian>> { Ok(EndianSlice::new(&[], LittleEndian)) };
+        let dwarf = gimli::Dwarf::load(load, no_sup)?;
+
+        let mut rows = BTreeMap::new();
+        let mut files = Vec::new();
+        let mut file_indexes = BTreeMap::new();
+
+        let mut units = dwarf.units();
+        while let Some(header) = units.next()? {
+            let unit = dwarf.unit(header)?;
+            let program = match unit.line_program.clone() {
+                Some(program) => program,
+                None => continue,
+            };
+
+            let comp_dir = unit
+                .comp_dir
+                .map(|dir| String::from_utf8_lossy(dir.slice()).into_owned());
+
+            let mut program_rows = program.rows();
+            while let Some((header, row)) = program_rows.next_row()? {
+                if row.end_sequence() {
+                    // Another sequence may start here.
+                    rows.entry(row.address()).or_insert(None);
+                    continue;
+                }
+
+                let file = match row.file(header) {
+                    Some(file) => file,
+                    None => continue,
+                };
+
+                let mut path = String::new();
+                if let Some(dir) = file.directory(header) {
+                    path = String::from_utf8_lossy(dwarf.attr_string(&unit, dir)?.slice())
+                        .into_owned();
+                }
+                let name = dwarf.attr_string(&unit, file.path_name())?;
+                let name = String::from_utf8_lossy(name.slice());
+                if name.starts_with('/') || path.is_empty() {
+                    path = name.into_owned();
+                } else {
+                    path = format!("{}/{}", path, name);
+                }
+                if !path.starts_with('/') {
+                    if let Some(dir) = &comp_dir {
+                        path = format!("{}/{}", dir, path);
+                    }
+                }
+
+                let index = *file_indexes.entry(path.clone()).or_insert_with(|| {
+                    files.push(path);
+                    files.len() - 1
+                });
+                let line = row.line().unwrap_or(0) as u32;
+                rows.insert(row.address(), Some((index, line)));
+            }
+        }
+
+        Ok(LineTable { rows, files })
+    }
+
+    pub fn lookup(&self, addr: u64) -> Option<(usize, u32)> {
+        match self.rows.range(..=addr).next_back() {
+            Some((_, Some((file, line)))) if *line != 0 => Some((*file, *line)),
+            _ => None,
+        }
+    }
+
+    pub fn file(&self, index: usize) -> &str {
+        &self.files[index]
+    }
+
+    /// Formats the source location of an address as 'file:line'.
+    pub fn location(&self, addr: u64) -> Option<String> {
+        self.lookup(addr)
+            .map(|(file, line)| format!("{}:{}", self.files[file], line))
+    }
+}
+
+//-------------------------------
+
+#[test]
+fn test_line_lookup() {
+    let mut rows = BTreeMap::new();
+    rows.insert(0x100, Some((0, 10)));
+    rows.insert(0x108, Some((0, 11)));
+    rows.insert(0x110, None);
+    let table = LineTable {
+        rows,
+        files: vec!["dm-btree.c".to_string()],
+    };
+
+    assert_eq!(table.lookup(0xfc), None);
+    assert_eq!(table.lookup(0x100), Some((0, 10)));
+    assert_eq!(table.lookup(0x106), Some((0, 10)));
+    assert_eq!(table.lookup(0x10e), Some((0, 11)));
+    assert_eq!(table.lookup(0x110), None);
+    assert_eq!(table.location(0x108), Some("dm-btree.c:11".to_string()));
+}
+
+//-------------------------------
@@ -1,73 +1,360 @@
+use crate::core_dump::write_core;
+use crate::cost::Access;
+use crate::coverage::CoverageMap;
 use crate::decode::Reg;
+use crate::dwarf::LineTable;
+use crate::gdb::*;
 use crate::guest::*;
 use crate::loader::*;
 use crate::memory::*;
 use crate::memory::{Addr, PERM_EXEC};
+use crate::semihost::*;
+use crate::stubs::block_manager::{restore_bm, snapshot_bm};
 use crate::vm::*;
 
 use anyhow::{anyhow, Result};
-use elf::types::Symbol;
 use libc::{c_int, strerror_r};
-use log::{debug, warn};
-use std::collections::BTreeMap;
+use log::{debug, info, warn};
+use std::cell::Cell;
+use std::collections::{BTreeMap, BTreeSet};
 use std::ffi::CStr;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::net::TcpStream;
 use std::ops::{Deref, DerefMut};
 use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use std::sync::{Arc, Mutex};
 
 use Reg::*;
 
 //-------------------------------
 
+/// Context added to errors raised while the guest was running, giving
+/// the guest call stack at the time.
+#[derive(Debug)]
+pub struct GuestFault {
+    pub msg: String,
+    pub backtrace: Vec<String>,
+}
+
+impl fmt::Display for GuestFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}\nguest backtrace:", self.msg)?;
+        for frame in &self.backtrace {
+            write!(f, "\n  {}", frame)?;
+        }
+        Ok(())
+    }
+}
+
+// Returned by the callback at a call's exit address.  This is how
+// control gets back to the host, rather than a failure.
+#[derive(Debug, thiserror::Error)]
+#[error("call complete, exiting")]
+struct CallComplete;
+
+// Raised when the guest makes a SYS_EXIT semihosting call with a zero
+// status.  Like CallComplete, this ends the call successfully.
+#[derive(Debug, thiserror::Error)]
+#[error("guest exited")]
+struct GuestExit;
+
+//-------------------------------
+
 type FixCallback = Box<dyn Fn(&mut Fixture) -> Result<()>>;
 
+// Breakpoint callbacks are shared, so they can run whilst the guest
+// re-enters the same breakpoint.
+type SharedCallback = Rc<dyn Fn(&mut Fixture) -> Result<()>>;
+
+/// Decides whether a hook should fire, given the state of the guest.
+pub type HookPredicate = Box<dyn Fn(&mut Fixture) -> Result<bool>>;
+
+/// Identifies a hook added with Fixture::add_hook(), so it can be removed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct HookId(u64);
+
+/// A callback to run when the guest reaches an address.  It fires
+/// every time unless restricted with when(), nth_hit() or once().
+pub struct Hook {
+    callback: FixCallback,
+    predicate: Option<HookPredicate>,
+    nth: Option<u64>,
+    once: bool,
+
+    // Number of times the predicate has passed.
+    hits: Cell<u64>,
+}
+
+impl Hook {
+    pub fn new(callback: FixCallback) -> Self {
+        Hook {
+            callback,
+            predicate: None,
+            nth: None,
+            once: false,
+            hits: Cell::new(0),
+        }
+    }
+
+    /// Only fire when the predicate holds.
+    pub fn when(mut self, predicate: HookPredicate) -> Self {
+        self.predicate = Some(predicate);
+        self
+    }
+
+    /// Only fire on the nth hit, counting from 1.  Hits where the
+    /// predicate fails don't count.
+    pub fn nth_hit(mut self, n: u64) -> Self {
+        self.nth = Some(n);
+        self
+    }
+
+    /// Remove the hook after it has fired.
+    pub fn once(mut self) -> Self {
+        self.once = true;
+        self
+    }
+
+    fn should_fire(&self, fix: &mut Fixture) -> Result<bool> {
+        if let Some(predicate) = &self.predicate {
+            if !(*predicate)(fix)? {
+                return Ok(false);
+            }
+        }
+
+        let hits = self.hits.get() + 1;
+        self.hits.set(hits);
+        match self.nth {
+            Some(n) => Ok(hits == n),
+            None => Ok(true),
+        }
+    }
+}
+
+// Everything attached to a breakpoint address.  The hooks run in the
+// order they were added, followed by the callback from at_addr(), which
+// is usually a stub.  If a callback moves the pc, eg, by returning from
+// the function, the remaining ones are skipped.
+#[derive(Default)]
+struct Hooks {
+    hooks: Vec<(HookId, Rc<Hook>)>,
+    stub: Option<SharedCallback>,
+}
+
+/// Called with the pc of the instruction, or stub, that accessed a
+/// watched range.
+pub type WatchCallback = Box<dyn Fn(&mut Fixture, Addr, &WatchHit) -> Result<()>>;
+
+/// Handles a semihosting service, given the arguments from a0 to a5.
+/// The value returned is passed back to the guest in a0.
+pub type EcallHandler = Box<dyn Fn(&mut Fixture, &[u64; 6]) -> Result<u64>>;
+
+// Shared for the same reason as SharedCallback.
+type SharedEcallHandler = Rc<dyn Fn(&mut Fixture, &[u64; 6]) -> Result<u64>>;
+
+/// Heap blocks leaked from the same place, see Fixture::leaks().
+pub struct Leak {
+    pub trace: Vec<Addr>,
+    pub blocks: Vec<(Addr, usize)>,
+}
+
+impl Leak {
+    pub fn bytes(&self) -> usize {
+        self.blocks.iter().map(|(_, len)| len).sum()
+    }
+}
+
+/// Where the guest's heap and stack go, and how big they are, see
+/// Fixture::with_layout().
+#[derive(Clone, Debug)]
+pub struct MemLayout {
+    pub heap_base: Addr,
+
+    /// Must be a power of two.
+    pub heap_size: u64,
+
+    /// The heap doubles in size whenever it's full, up to this.  The
+    /// default is heap_size, so it never grows.
+    pub heap_limit: u64,
+
+    /// The stack grows down from here.
+    pub stack_top: Addr,
+    pub stack_size: u64,
+}
+
+impl Default for MemLayout {
+    /// A 16M heap at 3G, and an 8K stack just below 4G.
+    fn default() -> Self {
+        MemLayout {
+            heap_base: Addr(1024 * 1024 * 1024 * 3),
+            heap_size: 16 * 1024 * 1024,
+            heap_limit: 16 * 1024 * 1024,
+            stack_top: Addr(1 << 32),
+            stack_size: 8 * 1024,
+        }
+    }
+}
+
+impl MemLayout {
+    fn check(&self) -> Result<()> {
+        if !self.heap_size.is_power_of_two() {
+            return Err(anyhow!(
+                "heap size {:#x} isn't a power of two",
+                self.heap_size
+            ));
+        }
+        if self.heap_limit < self.heap_size {
+            return Err(anyhow!("heap limit is less than its size"));
+        }
+        if self.stack_size > self.stack_top.0 {
+            return Err(anyhow!("stack extends below zero"));
+        }
+
+        let heap_end = self
+            .heap_base
+            .0
+            .checked_add(self.heap_limit)
+            .ok_or_else(|| anyhow!("heap extends past the end of memory"))?;
+        let stack_base = self.stack_top.0 - self.stack_size;
+        if self.heap_base.0 < self.stack_top.0 && stack_base < heap_end {
+            return Err(anyhow!("heap and stack overlap"));
+        }
+        Ok(())
+    }
+}
+
+/// Saved state of the guest, see Fixture::snapshot().
+pub struct Snapshot {
+    vm: VmSnapshot,
+    bm: Option<(Addr, crate::block_manager::BlockManager)>,
+}
+
 #[allow(dead_code)]
 pub struct Fixture {
     pub vm: VM,
 
-    // Entry points for symbols
-    symbols: BTreeMap<String, Symbol>,
+    // The module under test, symbols and section addresses reflect
+    // where it was loaded.
+    module: Module,
 
     // Associates breakpoint addresses with callback functions.
-    breakpoints: BTreeMap<u64, FixCallback>,
+    breakpoints: BTreeMap<u64, Hooks>,
+
+    // The address of every live hook.
+    hook_locs: BTreeMap<HookId, u64>,
+    next_hook: u64,
+
+    // Watchpoint callbacks, indexed by the id Memory gave the watchpoint.
+    watchpoints: BTreeMap<u64, WatchCallback>,
+
+    // Semihosting handlers registered by the test, indexed by service
+    // number.  These take precedence over the built in services.
+    ecall_handlers: BTreeMap<u64, SharedEcallHandler>,
+
+    // Values the guest can fetch with SYS_GET_PARAM.
+    params: BTreeMap<String, u64>,
+
+    // Whether the guest may read uninitialised memory, and the functions
+    // excused from the check, either because they make the read or
+    // because they allocated the memory.
+    uninit_checks: bool,
+    uninit_readers: BTreeSet<String>,
+    uninit_allocators: BTreeSet<String>,
 
     // Current indentation for function tracing.
     trace_indent: usize,
+
+    // Set while a gdb session is attached.
+    debugger: Option<Debugger>,
+
+    // Maximum number of instructions a single call may execute.
+    call_budget: Option<u64>,
+
+    // Source lines for backtraces, read from the debug info on first use.
+    line_table: Option<LineTable>,
+
+    // WARN()s that have fired, oldest first.
+    warnings: Vec<BugEntry>,
 }
 
 impl Fixture {
     pub fn new<P: AsRef<Path>>(kernel_dir: P) -> Result<Self> {
-        let mut module = PathBuf::new();
-        module.push(kernel_dir);
-        module.push("drivers/md/persistent-data/dm-persistent-data.ko");
+        Self::with_layout(kernel_dir, &MemLayout::default())
+    }
+
+    /// Like new(), but with the heap and stack placed and sized by
+    /// 'layout', eg, for tests that build large btrees.
+    pub fn with_layout<P: AsRef<Path>>(kernel_dir: P, layout: &MemLayout) -> Result<Self> {
+        layout.check()?;
+
+        let mut path = PathBuf::new();
+        path.push(kernel_dir);
+        path.push("drivers/md/persistent-data/dm-persistent-data.ko");
 
-        let heap_begin = Addr(1024 * 1024 * 1024 * 3);
-        let heap_end = Addr(heap_begin.0 + (16 * 1024 * 1024));
-        let mem = Memory::new(heap_begin, heap_end);
+        let heap_begin = layout.heap_base;
+        let heap_end = Addr(heap_begin.0 + layout.heap_size);
+        let mut mem = Memory::new(heap_begin, heap_end);
+        mem.set_heap_limit(layout.heap_limit);
         let mut vm = VM::new(mem);
-        let symbols = load_elf(&mut vm.mem, module)?;
+        let module = load_elf(&mut vm.mem, path)?;
+
+        // Nothing else is mapped in the heap, so check the module hasn't
+        // been loaded there.
+        let heap_limit = heap_begin.0 + layout.heap_limit;
+        for (name, s) in &module.sections {
+            if s.base.0 < heap_limit && heap_begin.0 < s.base.0 + s.len {
+                return Err(anyhow!("module section {} overlaps the heap", name));
+            }
+        }
 
+        Self::from_parts(vm, module, layout)
+    }
+
+    // Sets up the stack for a vm that already has the module loaded.
+    fn from_parts(mut vm: VM, module: Module, layout: &MemLayout) -> Result<Self> {
         // Setup the stack and heap
-        vm.setup_stack(8 * 1024)?;
+        vm.setup_stack_at(layout.stack_top, layout.stack_size)?;
+
+        // Accesses only trigger watchpoints while the guest is running.
+        vm.mem.record_watch_hits(false);
 
         Ok(Fixture {
             vm,
-            symbols,
+            module,
             breakpoints: BTreeMap::new(),
+            hook_locs: BTreeMap::new(),
+            next_hook: 0,
+            watchpoints: BTreeMap::new(),
+            ecall_handlers: BTreeMap::new(),
+            params: BTreeMap::new(),
+            uninit_checks: true,
+            uninit_readers: BTreeSet::new(),
+            uninit_allocators: BTreeSet::new(),
             trace_indent: 0,
+            debugger: None,
+            call_budget: None,
+            line_table: None,
+            warnings: Vec::new(),
         })
     }
 
     fn lookup_fn(&self, func: &str) -> Result<Addr> {
-        if let Some(addr) = self.symbols.get(func) {
+        if let Some(addr) = self.module.symbols.get(func) {
             Ok(Addr(addr.value))
         } else {
             Err(anyhow!("couldn't lookup symbol '{}'", func))
         }
     }
 
+    pub fn has_symbol(&self, name: &str) -> bool {
+        self.module.symbols.contains_key(name)
+    }
+
     fn symbol_rmap(&self, loc: u64) -> Option<String> {
-        for (name, sym) in &self.symbols {
+        for (name, sym) in &self.module.symbols {
             if sym.value == loc {
                 return Some(name.clone());
             }
@@ -76,42 +363,818 @@ impl Fixture {
         None
     }
 
+    /// Captures the vm registers and memory, and the block manager
+    /// with its engine.  Data is shared copy-on-write, so this is cheap,
+    /// and a single expensive setup can be restored many times.  Hooks
+    /// are not part of the snapshot; the ones in place when restore()
+    /// is called remain.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            vm: self.vm.snapshot(),
+            bm: snapshot_bm(),
+        }
+    }
+
+    pub fn restore(&mut self, snap: &Snapshot) {
+        self.vm.restore(&snap.vm);
+        restore_bm(&snap.bm);
+    }
+
+    /// Formats a guest address as 'func+0x1c'.
+    pub fn symbolize(&self, addr: Addr) -> String {
+        self.module.symbolize(addr.0)
+    }
+
+    fn line_table(&mut self) -> &LineTable {
+        if self.line_table.is_none() {
+            let table = self
+                .module
+                .debug_sections()
+                .and_then(|sections| LineTable::new(&sections))
+                .unwrap_or_else(|e| {
+                    debug!("couldn't read line table: {}", e);
+                    LineTable::new(&BTreeMap::new()).unwrap()
+                });
+            self.line_table = Some(table);
+        }
+        self.line_table.as_ref().unwrap()
+    }
+
+    /// The guest call stack, innermost first, one line per frame, eg,
+    /// "#1 0x100a3c dm_btree_insert+0x24 (drivers/md/persistent-data/dm-btree.c:512)".
+    /// Source locations are only given if the module has debug info.
+    pub fn backtrace(&mut self) -> Vec<String> {
+        let addrs = self.vm.backtrace();
+        self.format_frames(&addrs)
+    }
+
+    // Formats a backtrace, see backtrace().
+    fn format_frames(&mut self, addrs: &[Addr]) -> Vec<String> {
+        let mut frames = Vec::new();
+        for (i, addr) in addrs.iter().enumerate() {
+            let mut frame = format!("#{} {:#x} {}", i, addr.0, self.symbolize(*addr));
+            if let Some(loc) = self.line_table().location(addr.0) {
+                frame.push_str(&format!(" ({})", loc));
+            }
+            frames.push(frame);
+        }
+        frames
+    }
+
+    /// Heap blocks allocated by the guest that haven't been freed,
+    /// grouped by the backtrace they were allocated from, most bytes
+    /// first.  Blocks the test allocated itself aren't included.
+    pub fn leaks(&self) -> Vec<Leak> {
+        let mut by_trace: BTreeMap<&[Addr], Vec<(Addr, usize)>> = BTreeMap::new();
+        for (ptr, len) in self.vm.mem.allocations() {
+            if let Some(trace) = self.vm.mem.alloc_trace(ptr) {
+                by_trace.entry(trace).or_default().push((ptr, len));
+            }
+        }
+
+        let mut leaks: Vec<Leak> = by_trace
+            .into_iter()
+            .map(|(trace, blocks)| Leak {
+                trace: trace.to_vec(),
+                blocks,
+            })
+            .collect();
+        leaks.sort_by_key(|l| std::cmp::Reverse(l.bytes()));
+        leaks
+    }
+
+    /// Describes leaks, giving the total, then the size and allocation
+    /// backtrace of each group.
+    pub fn leak_report(&mut self, leaks: &[Leak]) -> String {
+        let blocks: usize = leaks.iter().map(|l| l.blocks.len()).sum();
+        let bytes: usize = leaks.iter().map(|l| l.bytes()).sum();
+        let mut report = format!("{} blocks leaked, {} bytes in total\n", blocks, bytes);
+        for leak in leaks {
+            report.push_str(&format!(
+                "\n{} bytes in {} block{}, allocated from:\n",
+                leak.bytes(),
+                leak.blocks.len(),
+                if leak.blocks.len() == 1 { "" } else { "s" }
+            ));
+            for frame in self.format_frames(&leak.trace) {
+                report.push_str(&format!("  {}\n", frame));
+            }
+        }
+        report
+    }
+
+    /// Says what an address belongs to, eg, "heap block 0x2000004+0x10, 64 bytes",
+    /// "4 bytes past the end of heap block 0x2000004, 64 bytes", or
+    /// ".data dm_btree_info+0x8".
+    pub fn describe_addr(&self, addr: Addr) -> Option<String> {
+        if let Some((ptr, len)) = self.vm.mem.allocation(addr) {
+            return Some(format!(
+                "heap block {:#x}+{:#x}, {} bytes",
+                ptr.0,
+                addr.0 - ptr.0,
+                len
+            ));
+        }
+
+        if let Some((ptr, len)) = self.vm.mem.freed_block(addr) {
+            return Some(format!(
+                "freed heap block {:#x}+{:#x}, {} bytes",
+                ptr.0,
+                addr.0 - ptr.0,
+                len
+            ));
+        }
+
+        if let Some(near) = self.vm.mem.nearest_block(addr) {
+            return Some(format!(
+                "{} {}heap block {:#x}, {} bytes",
+                near.position(),
+                if near.freed { "freed " } else { "" },
+                near.ptr.0,
+                near.len
+            ));
+        }
+
+        for (name, s) in &self.module.sections {
+            if addr.0 >= s.base.0 && addr.0 < s.base.0 + s.len {
+                return Some(format!("{} {}", name, self.symbolize(addr)));
+            }
+        }
+
+        self.vm
+            .mem
+            .mapping(addr)
+            .map(|(begin, end)| format!("mapping {:#x}-{:#x}", begin.0, end.0))
+    }
+
+    /// A post-mortem for a failed test: the error, the last few
+    /// instructions executed, the registers, and the memory around the
+    /// faulting address if there is one.
+    pub fn failure_report(&mut self, e: &anyhow::Error) -> String {
+        let mut report = format!("{}\n", e);
+
+        let recent: Vec<Executed> = self.vm.recent().cloned().collect();
+        if !recent.is_empty() {
+            report.push_str(&format!("\nlast {} instructions:\n", recent.len()));
+            let mut func = None;
+            for e in &recent {
+                let (name, offset) = match self.module.lookup_addr(e.pc.0) {
+                    Some((name, offset)) => (Some(name.to_string()), offset),
+                    None => (None, e.pc.0),
+                };
+                if name != func {
+                    report.push_str(&format!("  {}:\n", name.as_deref().unwrap_or("?")));
+                    func = name;
+                }
+
+                let inst = e
+                    .inst()
+                    .map(|i| i.to_string().replace('\t', " "))
+                    .unwrap_or_else(|| "?".to_string());
+                // Compressed instructions are 16 bits.
+                let bits = if e.bits & 3 != 3 {
+                    format!("{:04x}    ", e.bits)
+                } else {
+                    format!("{:08x}", e.bits)
+                };
+                let mut line = format!("    {:#x} +{:<#6x} {}  {:<24}", e.pc.0, offset, bits, inst);
+                if let Some((rd, v)) = e.rd {
+                    line.push_str(&format!(" {} = {:#x}", rd, v));
+                }
+                report.push_str(line.trim_end());
+                report.push('\n');
+            }
+        }
+
+        report.push_str(&format!("\nregisters:\n{}\n", self.vm));
+        for r in 1..33u32 {
+            let r = Reg::from(r);
+            let v = self.vm.reg(r);
+            if let Some(owner) = self.describe_addr(Addr(v)) {
+                report.push_str(&format!("  {:<4} {:#x}: {}\n", r.to_string(), v, owner));
+            }
+        }
+
+        if let Some(addr) = fault_addr(e) {
+            report.push_str(&format!("\nmemory around {:#x}:\n", addr.0));
+            report.push_str(&hexdump(&self.vm.mem, addr));
+            if let Some(owner) = self.describe_addr(addr) {
+                report.push_str(&format!("  ({})\n", owner));
+            }
+        }
+
+        report
+    }
+
+    /// Writes an ELF core file of the guest, for examining a failure with
+    /// gdb.  'e' decides which signal gdb reports.
+    pub fn write_core<P: AsRef<Path>>(&self, path: P, e: &anyhow::Error) -> Result<()> {
+        let signal = e
+            .chain()
+            .find_map(|c| {
+                if let Some(e) = c.downcast_ref::<VmErr>() {
+                    fault_signal(e)
+                } else if c.is::<MemErr>() {
+                    Some(SIGSEGV)
+                } else {
+                    None
+                }
+            })
+            .unwrap_or(SIGABRT);
+
+        let mut file = BufWriter::new(File::create(path)?);
+        write_core(&self.vm, signal, &mut file)?;
+        file.flush()?;
+        Ok(())
+    }
+
+    // Adds the guest backtrace to an error, unless a nested call has
+    // already done so.
+    fn with_backtrace(&mut self, e: anyhow::Error) -> anyhow::Error {
+        if e.is::<GuestFault>() {
+            return e;
+        }
+
+        let fault = GuestFault {
+            msg: e.to_string(),
+            backtrace: self.backtrace(),
+        };
+        e.context(fault)
+    }
+
+    /// Builds the map needed to turn the vm's coverage hits into
+    /// reports.  This reads the module's debug info, so is slow.
+    pub fn coverage_map(&self) -> Result<CoverageMap> {
+        CoverageMap::new(&self.module, &self.vm.mem)
+    }
+
+    /// A gdb command that loads the module's debug info at the addresses
+    /// it's been loaded to, eg, "add-symbol-file dm-persistent-data.ko -s .text 0x100000 ..."
+    pub fn add_symbol_file_cmd(&self) -> String {
+        self.module.add_symbol_file_cmd()
+    }
+
+    /// Hands control of the guest to gdb.  The guest is stopped
+    /// immediately, so breakpoints can be set before the test gets going.
+    pub fn attach_debugger(&mut self, stream: TcpStream) {
+        let mut dbg = Debugger::new(stream);
+        if dbg.attach(self) {
+            self.debugger = Some(dbg);
+        }
+    }
+
+    /// Tells gdb the guest has finished, and waits for the stub to shut down.
+    pub fn detach_debugger(&mut self) {
+        if let Some(mut dbg) = self.debugger.take() {
+            dbg.finish();
+        }
+    }
+
+    // Like VM::run(), but single steps under the control of gdb if
+    // it's attached.
+    fn exec_vm(&mut self) -> crate::vm::Result<()> {
+        loop {
+            match self.debugger.take() {
+                None => return self.vm.run(),
+                Some(mut dbg) => {
+                    if !dbg.check(self) {
+                        // gdb has gone away, so carry on without it.
+                        continue;
+                    }
+
+                    let r = self.vm.step();
+                    dbg.stepped(&r);
+                    self.debugger = Some(dbg);
+                    r?;
+                }
+            }
+        }
+    }
+
+    // Lets gdb examine the guest before an error is returned.
+    fn debug_fault(&mut self, e: &VmErr) {
+        if let Some(mut dbg) = self.debugger.take() {
+            if dbg.fault(self, e) {
+                self.debugger = Some(dbg);
+            }
+        }
+    }
+
     // Runs the vm, handling any breakpoints.
     fn run_vm(&mut self) -> Result<()> {
+        let old = self.vm.mem.record_watch_hits(true);
+        let old_uninit = self.vm.mem.check_uninit(self.uninit_checks);
+        let r = self.run_vm_();
+        self.vm.mem.check_uninit(old_uninit);
+        self.vm.mem.record_watch_hits(old);
+        r
+    }
+
+    // Runs the instruction at the pc again without checking for
+    // uninitialised reads.  gdb has already had its chance to stop
+    // here, but this is the step it's waiting for.
+    fn step_unchecked(&mut self) -> crate::vm::Result<()> {
+        let old = self.vm.mem.check_uninit(false);
+        let r = match self.debugger.take() {
+            None => self.vm.step(),
+            Some(mut dbg) => {
+                let r = self.vm.step();
+                dbg.stepped(&r);
+                self.debugger = Some(dbg);
+                r
+            }
+        };
+        self.vm.mem.check_uninit(old);
+        r
+    }
+
+    fn run_vm_(&mut self) -> Result<()> {
+        // Set when an instruction made a suppressed uninitialised read,
+        // and needs running again without the check.
+        let mut unchecked = false;
+
         loop {
-            match self.vm.run() {
+            let r = if unchecked {
+                unchecked = false;
+                match self.step_unchecked() {
+                    Ok(()) => continue,
+                    r => r,
+                }
+            } else {
+                self.exec_vm()
+            };
+
+            match r {
                 Ok(()) => return Ok(()),
                 Err(VmErr::Breakpoint) => {
                     let loc = self.vm.reg(Reg::PC);
+                    if self.breakpoints.contains_key(&loc) {
+                        // Stubs for suppressed functions may read
+                        // anything.
+                        let check = self.uninit_checks && !self.uninit_reader(Addr(loc));
+                        let old = self.vm.mem.check_uninit(check);
+                        let r = self.run_hooks(loc);
+                        self.vm.mem.check_uninit(old);
 
-                    // Temporarily remove the breakpoint before executing, this
-                    // gets around some issues with the fixture being held multiple
-                    // times, and allows the breakpoints to recurse back into here.  The
-                    // downside is you cannot recurse a particular breakpoint.
-                    if let Some(callback) = self.breakpoints.remove(&loc) {
-                        let r = (*callback)(self);
-                        self.breakpoints.insert(loc, callback);
-                        r?;
+                        if let Err(e) = r {
+                            if e.is::<CallComplete>() {
+                                return Err(e);
+                            }
+                            let e = self.explain_stub_error(Addr(loc), e);
+                            return Err(self.with_backtrace(e));
+                        }
+
+                        // Stubs can hit watchpoints too.
+                        if let Err(e) = self.handle_watch_hits(Addr(loc)) {
+                            return Err(self.with_backtrace(e));
+                        }
                     } else {
-                        return Err(anyhow!(
-                            "Breakpoint at {:x?} without callback",
-                            self.vm.reg(PC)
-                        ));
+                        let e = anyhow!("Breakpoint at {:x?} without callback", self.vm.reg(PC));
+                        return Err(self.with_backtrace(e));
+                    }
+                }
+                Err(VmErr::Watchpoint(pc)) => {
+                    if let Err(e) = self.handle_watch_hits(pc) {
+                        return Err(self.with_backtrace(e));
+                    }
+                }
+                Err(VmErr::ECall) => {
+                    if let Err(e) = self.handle_ecall() {
+                        if e.is::<GuestExit>() {
+                            return Err(e);
+                        }
+                        self.debug_fault(&VmErr::ECall);
+                        return Err(self.with_backtrace(e));
                     }
                 }
                 Err(VmErr::EBreak) => {
-                    if let Some(global) = self.symbol_rmap(self.vm.reg(Reg::PC)) {
+                    if let Some(bug) = self.module.bug_at(self.vm.pc()).cloned() {
+                        if bug.is_warning() {
+                            self.handle_warning(&bug)?;
+                            continue;
+                        }
+
+                        self.debug_fault(&VmErr::EBreak);
+                        let e = anyhow!("BUG at {}", self.bug_location(&bug));
+                        return Err(self.with_backtrace(e));
+                    }
+
+                    self.debug_fault(&VmErr::EBreak);
+                    let e = if let Some(global) = self.symbol_rmap(self.vm.reg(Reg::PC)) {
                         warn!("unstubbed global called: {}", global);
-                        return Err(anyhow!("unstubbed global access '{}'", global));
+                        anyhow!("unstubbed global access '{}'", global)
                     } else {
-                        return Err(VmErr::EBreak.into());
-                    }
+                        VmErr::EBreak.into()
+                    };
+                    return Err(self.with_backtrace(e));
+                }
+                Err(VmErr::BadAccess(MemErr::UninitRead(addr)))
+                    if self.uninit_reader(self.vm.pc()) || self.uninit_allocator(addr) =>
+                {
+                    self.vm.unwind_fault();
+                    unchecked = true;
+                }
+                Err(e @ VmErr::BadAccess(_)) => {
+                    self.debug_fault(&e);
+                    let e = match self.explain_fault(&e) {
+                        Some(msg) => anyhow::Error::new(e).context(msg),
+                        None => e.into(),
+                    };
+                    return Err(self.with_backtrace(e));
+                }
+                Err(e @ VmErr::BudgetExhausted { .. }) => {
+                    self.debug_fault(&e);
+                    let msg = self.budget_report(&e);
+                    let e = anyhow::Error::new(e).context(msg);
+                    return Err(self.with_backtrace(e));
+                }
+                Err(e) => {
+                    self.debug_fault(&e);
+                    return Err(self.with_backtrace(e.into()));
                 }
-                err => err?,
             }
         }
     }
 
+    /// Calls 'callback' whenever the guest, or a stub, accesses memory in
+    /// [begin, end).  Accesses made by the test itself, or by callbacks,
+    /// don't count.  Returns an id for unwatch().
+    pub fn watch(
+        &mut self,
+        begin: Addr,
+        end: Addr,
+        kind: WatchKind,
+        callback: WatchCallback,
+    ) -> u64 {
+        let id = self.vm.mem.add_watchpoint(begin, end, kind);
+        self.watchpoints.insert(id, callback);
+        id
+    }
+
+    pub fn unwatch(&mut self, id: u64) -> bool {
+        self.watchpoints.remove(&id);
+        self.vm.mem.rm_watchpoint(id)
+    }
+
+    // Runs the callbacks for the watchpoints hit by the instruction, or
+    // stub, at pc.
+    fn handle_watch_hits(&mut self, pc: Addr) -> Result<()> {
+        let hits = self.vm.mem.take_watch_hits();
+        if let Some(dbg) = &mut self.debugger {
+            dbg.watch_hits(&hits);
+        }
+
+        for hit in &hits {
+            // The callback is removed while it runs, so a watched access
+            // made from a nested call into the guest doesn't recurse.
+            if let Some(callback) = self.watchpoints.remove(&hit.id) {
+                let r = (*callback)(self, pc, hit);
+                if self.vm.mem.has_watchpoint(hit.id) {
+                    self.watchpoints.insert(hit.id, callback);
+                }
+                r?;
+            }
+        }
+
+        // Ignore any accesses made by the callbacks.
+        self.vm.mem.take_watch_hits();
+        Ok(())
+    }
+
+    /// Registers a handler for a semihosting service, see the semihost
+    /// module for the ABI.  Custom services should use numbers from
+    /// SYS_USER upwards, registering a built in number replaces it.
+    pub fn on_ecall(&mut self, service: u64, handler: EcallHandler) {
+        self.ecall_handlers.insert(service, Rc::from(handler));
+    }
+
+    /// Sets a parameter the guest can read with SYS_GET_PARAM.
+    pub fn set_param(&mut self, name: &str, v: u64) {
+        self.params.insert(name.to_string(), v);
+    }
+
+    // Services the ECALL the guest has stopped at.
+    fn handle_ecall(&mut self) -> Result<()> {
+        let Ecall { service, args } = Ecall::from_vm(&self.vm);
+        if let Some(handler) = self.ecall_handlers.get(&service).cloned() {
+            let rv = (*handler)(self, &args)?;
+            Ecall::complete(&mut self.vm, rv);
+            return Ok(());
+        }
+
+        let rv = match service {
+            SYS_LOG => {
+                let msg = self.vm.mem.read_string(Addr(args[0]))?;
+                info!("guest: {}", msg);
+                0
+            }
+            SYS_ASSERT => {
+                if args[0] == 0 {
+                    let msg = if args[1] == 0 {
+                        "guest assertion failed".to_string()
+                    } else {
+                        let msg = self.vm.mem.read_string(Addr(args[1]))?;
+                        format!("guest assertion failed: {}", msg)
+                    };
+                    return Err(anyhow!(msg));
+                }
+                0
+            }
+            SYS_EXIT => {
+                if args[0] == 0 {
+                    return Err(GuestExit.into());
+                }
+                return Err(anyhow!("guest exited with status {}", args[0] as i64));
+            }
+            SYS_GET_PARAM => {
+                let name = self.vm.mem.read_string(Addr(args[0]))?;
+                *self.params.get(&name).unwrap_or(&args[1])
+            }
+            _ => {
+                return Err(anyhow!("unknown semihosting service {:#x}", service));
+            }
+        };
+        Ecall::complete(&mut self.vm, rv);
+        Ok(())
+    }
+
+    /// Turns checking for reads of uninitialised memory by the guest on
+    /// or off.  It's on by default.
+    pub fn set_uninit_checks(&mut self, enable: bool) {
+        self.uninit_checks = enable;
+    }
+
+    /// Lets a function, or its stub, read uninitialised memory, eg,
+    /// guest code copying the padding in a struct.  The memcpy stub
+    /// doesn't need this, it copies uninitialised bytes as they are.
+    pub fn suppress_uninit_reads_by(&mut self, func: &str) -> Result<()> {
+        self.lookup_fn(func)?;
+        self.uninit_readers.insert(func.to_string());
+        Ok(())
+    }
+
+    /// Lets the guest read uninitialised memory in blocks allocated by
+    /// a function.
+    pub fn suppress_uninit_allocs_by(&mut self, func: &str) -> Result<()> {
+        self.lookup_fn(func)?;
+        self.uninit_allocators.insert(func.to_string());
+        Ok(())
+    }
+
+    fn function_at(&self, addr: Addr) -> Option<&str> {
+        self.module.lookup_addr(addr.0).map(|(name, _)| name)
+    }
+
+    fn uninit_reader(&self, pc: Addr) -> bool {
+        match self.function_at(pc) {
+            Some(func) => self.uninit_readers.contains(func),
+            None => false,
+        }
+    }
+
+    fn uninit_allocator(&self, addr: Addr) -> bool {
+        match self.vm.mem.alloc_site(addr) {
+            Some(site) => match self.function_at(site) {
+                Some(func) => self.uninit_allocators.contains(func),
+                None => false,
+            },
+            None => false,
+        }
+    }
+
+    // Says who read uninitialised memory, and where it came from, eg,
+    // "dm_btree_insert+0x24 read uninitialised memory at 0x2000014, heap
+    // block 0x2000004+0x10, 64 bytes, allocated by dm_btree_empty+0x1c".
+    fn uninit_report(&self, pc: Addr, addr: Addr) -> String {
+        let mut msg = format!(
+            "{} ({:#x}) read uninitialised memory at {:#x}",
+            self.symbolize(pc),
+            pc.0,
+            addr.0
+        );
+        if let Some(what) = self.describe_addr(addr) {
+            msg.push_str(&format!(", {}", what));
+        }
+        if let Some(site) = self.vm.mem.alloc_site(addr) {
+            msg.push_str(&format!(", allocated by {}", self.symbolize(site)));
+        }
+        msg
+    }
+
+    // Says who touched a freed block, and where it was allocated and
+    // freed.
+    fn use_after_free_report(&mut self, pc: Addr, addr: Addr) -> String {
+        let mut msg = format!(
+            "{} ({:#x}) accessed freed memory at {:#x}",
+            self.symbolize(pc),
+            pc.0,
+            addr.0
+        );
+        if let Some(what) = self.describe_addr(addr) {
+            msg.push_str(&format!(", {}", what));
+        }
+
+        let alloc = self.vm.mem.alloc_trace(addr).map(|t| t.to_vec());
+        let free = self.vm.mem.free_trace(addr).map(|t| t.to_vec());
+        for (what, trace) in [("allocated", alloc), ("freed", free)].iter() {
+            if let Some(trace) = trace {
+                msg.push_str(&format!("\n{} from:", what));
+                for frame in self.format_frames(trace) {
+                    msg.push_str(&format!("\n  {}", frame));
+                }
+            }
+        }
+        msg
+    }
+
+    // Says which heap block an access strayed outside of, eg,
+    // "dm_btree_insert+0x4c (0x100a3c) 8-byte write 4 bytes past the end
+    // of 64-byte block 0x2000004, allocated by alloc_node+0x20".
+    fn overflow_report(&self, pc: Addr, addr: Addr, access: Option<Access>) -> Option<String> {
+        let near = self.vm.mem.nearest_block(addr)?;
+        if near.inside() {
+            return None;
+        }
+
+        let what = match access {
+            Some(a) => format!("{}-byte {}", a.len, if a.write { "write" } else { "read" }),
+            None => "access".to_string(),
+        };
+        let mut msg = format!(
+            "{} ({:#x}) {} {} {}{}-byte block {:#x}",
+            self.symbolize(pc),
+            pc.0,
+            what,
+            near.position(),
+            if near.freed { "freed " } else { "" },
+            near.len,
+            near.ptr.0
+        );
+        if let Some(site) = self.vm.mem.alloc_site(near.ptr) {
+            msg.push_str(&format!(", allocated by {}", self.symbolize(site)));
+        }
+        Some(msg)
+    }
+
+    // Explains a bad access by the instruction, or stub, at pc.
+    // 'access' is what it was trying to do, if known.
+    fn access_report(&mut self, pc: Addr, e: &MemErr, access: Option<Access>) -> Option<String> {
+        match e {
+            MemErr::UninitRead(addr) => Some(self.uninit_report(pc, *addr)),
+            MemErr::UseAfterFree(addr) => Some(self.use_after_free_report(pc, *addr)),
+            MemErr::UnmappedRegion(addr, _) | MemErr::BadPerms(addr, _) => {
+                self.overflow_report(pc, *addr, access)
+            }
+            _ => None,
+        }
+    }
+
+    /// Explains a fault the guest has stopped at in terms of the heap,
+    /// eg, which block a load overran, or where a block that was read
+    /// too early came from.  None if there's nothing to add.
+    pub fn explain_fault(&mut self, e: &VmErr) -> Option<String> {
+        match e {
+            VmErr::BadAccess(e) => {
+                let access = self.vm.access_at_pc();
+                self.access_report(self.vm.pc(), e, access)
+            }
+            _ => None,
+        }
+    }
+
+    // Explains bad accesses made by stubs, eg, memcpy.
+    fn explain_stub_error(&mut self, pc: Addr, e: anyhow::Error) -> anyhow::Error {
+        let msg = match e.downcast_ref::<MemErr>() {
+            Some(m) => self.access_report(pc, m, None),
+            None => None,
+        };
+        match msg {
+            Some(msg) => e.context(msg),
+            None => e,
+        }
+    }
+
+    fn bug_location(&self, bug: &BugEntry) -> String {
+        bug.location().unwrap_or_else(|| self.symbolize(bug.addr))
+    }
+
+    // Logs a WARN() and steps over its ebreak, as the kernel would.
+    // WARN_ONCE()s mark their entry as done, so only fire once.
+    fn handle_warning(&mut self, bug: &BugEntry) -> Result<()> {
+        let flags_addr = Addr(bug.entry.0 + BUG_FLAGS_OFFSET);
+        let flags = self.vm.mem.read_into::<u16>(flags_addr, PERM_READ)?;
+        let once = (flags & BUGFLAG_ONCE) != 0;
+        if !once || (flags & BUGFLAG_DONE) == 0 {
+            if once {
+                let flags = flags | BUGFLAG_DONE;
+                self.vm
+                    .mem
+                    .write(flags_addr, &flags.to_le_bytes(), PERM_WRITE)?;
+            }
+
+            warn!(
+                "WARNING at {}\n  {}",
+                self.bug_location(bug),
+                self.backtrace().join("\n  ")
+            );
+            self.warnings.push(bug.clone());
+        }
+
+        // The ebreak may be compressed.
+        let bits = self.vm.mem.read_into::<u16>(bug.addr, PERM_EXEC)?;
+        self.vm.inc_pc(if (bits & 3) == 3 { 4 } else { 2 });
+        Ok(())
+    }
+
+    /// WARN()s that have fired so far, oldest first.
+    pub fn warnings(&self) -> &[BugEntry] {
+        &self.warnings
+    }
+
+    pub fn clear_warnings(&mut self) {
+        self.warnings.clear();
+    }
+
+    /// Runs 'f', failing if it doesn't trigger a WARN().
+    pub fn expect_warning<T, F>(&mut self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Fixture) -> Result<T>,
+    {
+        let count = self.warnings.len();
+        let r = f(self)?;
+        if self.warnings.len() == count {
+            return Err(anyhow!("expected a WARN, but none fired"));
+        }
+        Ok(r)
+    }
+
+    /// Runs 'f', failing if it triggers a WARN().
+    pub fn expect_no_warnings<T, F>(&mut self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Fixture) -> Result<T>,
+    {
+        let count = self.warnings.len();
+        let r = f(self)?;
+        if let Some(bug) = self.warnings.get(count) {
+            return Err(anyhow!("unexpected WARN at {}", self.bug_location(bug)));
+        }
+        Ok(r)
+    }
+
+    fn budget_report(&self, e: &VmErr) -> String {
+        let mut msg = String::new();
+        if let VmErr::BudgetExhausted { pc, hot_loops } = e {
+            msg.push_str(&format!(
+                "instruction budget exhausted in {}",
+                self.symbolize(*pc)
+            ));
+            if !hot_loops.is_empty() {
+                let loops: Vec<String> = hot_loops
+                    .iter()
+                    .map(|(addr, count)| format!("{} ({})", self.symbolize(*addr), count))
+                    .collect();
+                msg.push_str(&format!(", hottest loops: {}", loops.join(", ")));
+            }
+        }
+        msg
+    }
+
+    // Tightens the vm's instruction limit, returning the old one so it
+    // can be restored.  An outer limit is never relaxed.
+    fn push_budget(&mut self, budget: u64) -> Option<u64> {
+        let old = self.vm.instr_limit();
+        let limit = self.vm.stats.instrs + budget;
+        self.vm
+            .set_instr_limit(Some(old.map_or(limit, |old| u64::min(old, limit))));
+        old
+    }
+
+    /// Limits the number of instructions each call may execute, None
+    /// removes the limit.  Calls that run out fail with
+    /// VmErr::BudgetExhausted.
+    pub fn set_call_budget(&mut self, budget: Option<u64>) {
+        self.call_budget = budget;
+    }
+
+    /// Limits the number of instructions executed from now on, across
+    /// all calls.  The test runner uses this for a per test budget.
+    pub fn set_test_budget(&mut self, budget: Option<u64>) {
+        let limit = budget.map(|b| self.vm.stats.instrs + b);
+        self.vm.set_instr_limit(limit);
+    }
+
+    /// Runs 'f' with a tighter instruction budget.  Useful for asserting
+    /// the worst case cost of an operation.
+    pub fn with_budget<T, F>(&mut self, budget: u64, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Fixture) -> Result<T>,
+    {
+        let old = self.push_budget(budget);
+        let r = f(self);
+        self.vm.set_instr_limit(old);
+        r
+    }
+
     // Call a named function in the vm.  Returns the contents of Ra.
     pub fn call_at(&mut self, code: Addr) -> Result<()> {
         use Reg::*;
@@ -119,9 +1182,17 @@ impl Fixture {
         // We need a unique address return control to us.
         let exit_addr = self.vm.mem.alloc_perms(4, PERM_EXEC)?;
 
+        // If a stub is calling back into the guest, any watchpoints it
+        // hit should be reported against it.
+        self.handle_watch_hits(self.vm.pc())?;
+
+        // Restored if the guest exits part way through.
+        let (old_sp, old_ra) = (self.vm.reg(Sp), self.vm.reg(Ra));
+
         self.vm.push_reg(Ra)?;
         self.vm.set_reg(Ra, exit_addr.0);
         self.vm.set_pc(code);
+        self.vm.note_host_call(code);
 
         // FIXME: use AtommicBool
         let completed = Arc::new(Mutex::new(false));
@@ -132,14 +1203,23 @@ impl Fixture {
                 let mut completed = completed.lock().unwrap();
                 *completed = true;
                 fix.vm.pop_reg(Ra)?;
-                Err(anyhow!("call complete, exiting"))
+                Err(CallComplete.into())
             };
 
             self.at_addr(exit_addr, Box::new(callback));
         }
 
+        let old_limit = self.call_budget.map(|b| self.push_budget(b));
+        let depth = self.vm.call_depth();
         let result = self.run_vm();
-        self.vm.mem.unmap(exit_addr)?;
+        self.vm.unwind_to(depth);
+        self.vm.note_host_return();
+        if let Some(old) = old_limit {
+            self.vm.set_instr_limit(old);
+        }
+        self.vm.mem.free(exit_addr)?;
+        self.breakpoints.remove(&exit_addr.0);
+        self.vm.rm_breakpoint(exit_addr);
         match result {
             Ok(_) => {
                 // Not sure how we can get here
@@ -150,6 +1230,10 @@ impl Fixture {
                 if *completed {
                     // FIXME: pop Ra from the stack
                     Ok(())
+                } else if e.is::<GuestExit>() {
+                    self.vm.set_reg(Sp, old_sp);
+                    self.vm.set_reg(Ra, old_ra);
+                    Ok(())
                 } else {
                     Err(e)
                 }
@@ -161,6 +1245,12 @@ impl Fixture {
         self.call_at(self.lookup_fn(func)?)
     }
 
+    /// Calls a function, failing if it executes more than 'budget'
+    /// instructions.
+    pub fn call_with_budget(&mut self, func: &str, budget: u64) -> Result<()> {
+        self.with_budget(budget, |fix| fix.call(func))
+    }
+
     // Use this to call functions that return an int errno.
     pub fn call_with_errno(&mut self, tm_func: &str) -> Result<()> {
         self.call(tm_func)?;
@@ -187,9 +1277,86 @@ impl Fixture {
         }
         Ok(())
     }
+
+    // Runs the callbacks at a breakpoint.  The callbacks stay in place
+    // whilst they run, so the guest may come back through the same
+    // breakpoint, eg, a recursive function, or a stub that calls back into
+    // the guest.
+    fn run_hooks(&mut self, loc: u64) -> Result<()> {
+        let (hooks, stub) = match self.breakpoints.get(&loc) {
+            Some(entry) => (entry.hooks.clone(), entry.stub.clone()),
+            None => return Ok(()),
+        };
+        let pc = self.vm.pc();
+
+        for (id, hook) in hooks {
+            // An earlier hook may have removed this one.
+            if !self.hook_locs.contains_key(&id) || !hook.should_fire(self)? {
+                continue;
+            }
+
+            if hook.once {
+                self.remove_hook(id);
+            }
+            (*hook.callback)(self)?;
+
+            if self.vm.pc() != pc {
+                return Ok(());
+            }
+        }
+
+        if let Some(stub) = stub {
+            (*stub)(self)?;
+        }
+        Ok(())
+    }
+
+    /// Sets the callback that runs when the guest reaches 'loc', usually
+    /// a stub for a function.  This replaces any previous callback set
+    /// with at_addr(), but runs after any hooks added with add_hook().
     pub fn at_addr(&mut self, loc: Addr, callback: FixCallback) {
         self.vm.add_breakpoint(loc);
-        self.breakpoints.insert(loc.0, callback);
+        self.breakpoints.entry(loc.0).or_default().stub = Some(Rc::from(callback));
+    }
+
+    /// Adds a hook at 'loc'.  Any number of hooks may share an address,
+    /// they run in the order they were added.
+    pub fn add_hook(&mut self, loc: Addr, hook: Hook) -> HookId {
+        let id = HookId(self.next_hook);
+        self.next_hook += 1;
+
+        self.vm.add_breakpoint(loc);
+        self.breakpoints
+            .entry(loc.0)
+            .or_default()
+            .hooks
+            .push((id, Rc::new(hook)));
+        self.hook_locs.insert(id, loc.0);
+        id
+    }
+
+    pub fn hook_func(&mut self, name: &str, hook: Hook) -> Result<HookId> {
+        let func_addr = self.lookup_fn(name)?;
+        Ok(self.add_hook(func_addr, hook))
+    }
+
+    /// Removes a hook, leaving any others at the same address in place.
+    /// Returns false if it had already gone, eg, a one shot hook that has
+    /// fired.
+    pub fn remove_hook(&mut self, id: HookId) -> bool {
+        let loc = match self.hook_locs.remove(&id) {
+            Some(loc) => loc,
+            None => return false,
+        };
+
+        if let Some(entry) = self.breakpoints.get_mut(&loc) {
+            entry.hooks.retain(|(hid, _)| *hid != id);
+            if entry.hooks.is_empty() && entry.stub.is_none() {
+                self.breakpoints.remove(&loc);
+                self.vm.rm_breakpoint(Addr(loc));
+            }
+        }
+        true
     }
 
     pub fn at_func(&mut self, name: &str, callback: FixCallback) -> Result<()> {
@@ -263,7 +1430,8 @@ impl Fixture {
             }
         };
 
-        self.at_func(func, Box::new(entry_callback))?;
+        // A hook, so it runs before any stub for the function.
+        self.hook_func(func, Hook::new(Box::new(entry_callback)))?;
         self.at_addr(trampoline, Box::new(exit_callback));
 
         Ok(())
@@ -272,6 +1440,46 @@ impl Fixture {
 
 //-------------------------------
 
+// The address behind a bad memory access.
+fn fault_addr(e: &anyhow::Error) -> Option<Addr> {
+    let mem_err = e.chain().find_map(|c| {
+        if let Some(VmErr::BadAccess(m)) = c.downcast_ref::<VmErr>() {
+            Some(m)
+        } else {
+            c.downcast_ref::<MemErr>()
+        }
+    })?;
+
+    match mem_err {
+        MemErr::UnmappedRegion(addr, _)
+        | MemErr::BadPerms(addr, _)
+        | MemErr::BadFree(addr)
+        | MemErr::UninitRead(addr)
+        | MemErr::UseAfterFree(addr) => Some(*addr),
+        _ => None,
+    }
+}
+
+// Dumps the 64 bytes around 'addr', with '??' for bytes that can't be
+// read.
+fn hexdump(mem: &Memory, addr: Addr) -> String {
+    let begin = (addr.0 & !0xf).saturating_sub(32);
+    let mut dump = String::new();
+    for row in 0..4 {
+        let row = begin + row * 16;
+        dump.push_str(&format!("  {:#x}:", row));
+        for a in row..(row + 16) {
+            let mut b = [0u8; 1];
+            match mem.read(Addr(a), &mut b, 0) {
+                Ok(()) => dump.push_str(&format!(" {:02x}", b[0])),
+                Err(_) => dump.push_str(" ??"),
+            }
+        }
+        dump.push('\n');
+    }
+    dump
+}
+
 // FIXME: move somewhere else
 pub fn error_string(errno: i32) -> String {
     let mut buf = [0_i8; 512];
@@ -342,3 +1550,296 @@ pub fn auto_guest<'a, G: Guest>(
 }
 
 //-------------------------------
+
+// A fixture for a made up module.  Each function's code is mapped at
+// its own page, from 0x1000 upwards.
+#[cfg(test)]
+fn test_fixture(funcs: &[(&str, &[u32])]) -> Fixture {
+    let layout = MemLayout::default();
+    let heap_end = Addr(layout.heap_base.0 + layout.heap_size);
+    let mut mem = Memory::new(layout.heap_base, heap_end);
+
+    let mut syms = Vec::new();
+    for (i, (name, code)) in funcs.iter().enumerate() {
+        let addr = Addr(0x1000 * (i as u64 + 1));
+        let bytes: Vec<u8> = code.iter().flat_map(|i| i.to_le_bytes().to_vec()).collect();
+        mem.mmap_bytes(addr, &bytes, PERM_EXEC).unwrap();
+        syms.push((*name, addr, bytes.len() as u64));
+    }
+
+    let vm = VM::new(mem);
+    Fixture::from_parts(vm, Module::from_functions(&syms), &layout).unwrap()
+}
+
+// Adds a hook to 'f' that records a0 each time it fires.
+#[cfg(test)]
+fn record_a0(
+    fix: &mut Fixture,
+    hook: fn(Hook) -> Hook,
+) -> (HookId, Rc<std::cell::RefCell<Vec<u64>>>) {
+    let seen = Rc::new(std::cell::RefCell::new(Vec::new()));
+    let cb = {
+        let seen = seen.clone();
+        move |fix: &mut Fixture| {
+            seen.borrow_mut().push(fix.vm.reg(A0));
+            Ok(())
+        }
+    };
+    let id = fix.hook_func("f", hook(Hook::new(Box::new(cb)))).unwrap();
+    (id, seen)
+}
+
+#[cfg(test)]
+fn call_f(fix: &mut Fixture, args: &[u64]) {
+    for a in args {
+        fix.vm.set_reg(A0, *a);
+        fix.call("f").unwrap();
+    }
+}
+
+// f: ret
+#[cfg(test)]
+const RET: &[u32] = &[0x00008067];
+
+#[test]
+fn test_mem_layout_check() {
+    let layout = MemLayout::default();
+    assert!(layout.check().is_ok());
+
+    let huge = MemLayout {
+        heap_limit: u64::MAX,
+        ..layout.clone()
+    };
+    assert_eq!(
+        huge.check().unwrap_err().to_string(),
+        "heap extends past the end of memory"
+    );
+
+    let overlap = MemLayout {
+        stack_top: Addr(layout.heap_base.0 + 4096),
+        ..layout
+    };
+    assert_eq!(
+        overlap.check().unwrap_err().to_string(),
+        "heap and stack overlap"
+    );
+}
+
+#[test]
+fn test_hook_when() {
+    let mut fix = test_fixture(&[("f", RET)]);
+    let (_, seen) = record_a0(&mut fix, |h| {
+        h.when(Box::new(|fix| Ok(fix.vm.reg(A0) % 2 == 0)))
+    });
+    call_f(&mut fix, &[1, 2, 3, 4]);
+    assert_eq!(*seen.borrow(), vec![2, 4]);
+}
+
+#[test]
+fn test_hook_nth_hit() {
+    let mut fix = test_fixture(&[("f", RET)]);
+    let (_, all) = record_a0(&mut fix, |h| h);
+    let (_, seen) = record_a0(&mut fix, |h| h.nth_hit(3));
+
+    // Misses of the predicate don't count as hits.
+    let (_, even) = record_a0(&mut fix, |h| {
+        h.when(Box::new(|fix| Ok(fix.vm.reg(A0) % 2 == 0)))
+            .nth_hit(2)
+    });
+    call_f(&mut fix, &[1, 2, 3, 4, 5, 6]);
+    assert_eq!(all.borrow().len(), 6);
+    assert_eq!(*seen.borrow(), vec![3]);
+    assert_eq!(*even.borrow(), vec![4]);
+}
+
+#[test]
+fn test_hook_once() {
+    let mut fix = test_fixture(&[("f", RET)]);
+    let (id, seen) = record_a0(&mut fix, |h| h.once());
+    call_f(&mut fix, &[1, 2, 3]);
+    assert_eq!(*seen.borrow(), vec![1]);
+
+    // It's already gone.
+    assert!(!fix.remove_hook(id));
+    assert!(!fix.breakpoints.contains_key(&0x1000));
+}
+
+#[test]
+fn test_remove_hook() {
+    let mut fix = test_fixture(&[("f", RET)]);
+    let (id1, seen1) = record_a0(&mut fix, |h| h);
+    let (id2, seen2) = record_a0(&mut fix, |h| h);
+    let (id3, seen3) = record_a0(&mut fix, |h| h);
+    call_f(&mut fix, &[1]);
+
+    assert!(fix.remove_hook(id2));
+    assert!(!fix.remove_hook(id2));
+    call_f(&mut fix, &[2]);
+    assert_eq!(*seen1.borrow(), vec![1, 2]);
+    assert_eq!(*seen2.borrow(), vec![1]);
+    assert_eq!(*seen3.borrow(), vec![1, 2]);
+
+    // The breakpoint goes with the last hook.
+    assert!(fix.remove_hook(id1));
+    assert!(fix.remove_hook(id3));
+    assert!(!fix.breakpoints.contains_key(&0x1000));
+    call_f(&mut fix, &[3]);
+    assert_eq!(seen1.borrow().len(), 2);
+}
+
+// sys: ecall; ret
+#[cfg(test)]
+const SYS: &[u32] = &[0x00000073, 0x00008067];
+
+// exits: addi sp,sp,-32; li ra,0; ecall; ebreak
+#[cfg(test)]
+const EXITS: &[u32] = &[0xfe010113, 0x00000093, 0x00000073, 0x00100073];
+
+#[cfg(test)]
+fn ecall(fix: &mut Fixture, func: &str, service: u64, args: &[u64]) -> Result<u64> {
+    fix.vm.set_reg(A7, service);
+    for (r, v) in [A0, A1].iter().zip(args) {
+        fix.vm.set_reg(*r, *v);
+    }
+    fix.call(func)?;
+    Ok(fix.vm.reg(A0))
+}
+
+#[test]
+fn test_on_ecall() {
+    let mut fix = test_fixture(&[("sys", SYS)]);
+    fix.on_ecall(SYS_USER, Box::new(|_, args| Ok(args[0] + args[1])));
+    assert_eq!(ecall(&mut fix, "sys", SYS_USER, &[3, 4]).unwrap(), 7);
+
+    // Handlers take precedence over the built in services.  The built
+    // in SYS_GET_PARAM would fail to read a name from address 0.
+    fix.on_ecall(SYS_GET_PARAM, Box::new(|_, args| Ok(args[1] * 2)));
+    assert_eq!(ecall(&mut fix, "sys", SYS_GET_PARAM, &[0, 21]).unwrap(), 42);
+}
+
+#[test]
+fn test_ecall_exit() {
+    let mut fix = test_fixture(&[("exits", EXITS)]);
+    let (sp, ra) = (fix.vm.reg(Sp), fix.vm.reg(Ra));
+    ecall(&mut fix, "exits", SYS_EXIT, &[0]).unwrap();
+    assert_eq!(fix.vm.reg(Sp), sp);
+    assert_eq!(fix.vm.reg(Ra), ra);
+
+    let e = ecall(&mut fix, "exits", SYS_EXIT, &[3]).unwrap_err();
+    assert_eq!(e.root_cause().to_string(), "guest exited with status 3");
+}
+
+#[test]
+fn test_ecall_assert() {
+    let mut fix = test_fixture(&[("sys", SYS)]);
+    assert_eq!(ecall(&mut fix, "sys", SYS_ASSERT, &[1, 0]).unwrap(), 0);
+
+    let e = ecall(&mut fix, "sys", SYS_ASSERT, &[0, 0]).unwrap_err();
+    assert_eq!(e.root_cause().to_string(), "guest assertion failed");
+}
+
+#[test]
+fn test_unknown_ecall() {
+    let mut fix = test_fixture(&[("sys", SYS)]);
+    let e = ecall(&mut fix, "sys", SYS_USER, &[]).unwrap_err();
+    assert_eq!(
+        e.root_cause().to_string(),
+        "unknown semihosting service 0x1000"
+    );
+}
+
+#[test]
+fn test_suppressed_uninit_read() {
+    // f: ld a0,0(a0); ret
+    let mut fix = test_fixture(&[("f", &[0x00053503, 0x00008067])]);
+    fix.vm.enable_coverage();
+    let ptr = fix.vm.mem.alloc(8).unwrap();
+    fix.vm.set_reg(A0, ptr.0);
+    assert!(fix.call("f").is_err());
+
+    fix.suppress_uninit_reads_by("f").unwrap();
+    fix.vm.set_reg(A0, ptr.0);
+    fix.vm.stats = Stats::default();
+    fix.vm.take_coverage();
+    fix.call("f").unwrap();
+
+    // The load is run again unchecked, but only counted once.
+    assert_eq!(fix.vm.stats.instrs, 2);
+    assert_eq!(fix.vm.take_coverage().get(&0x1000), Some(&1));
+    let loads = fix.vm.recent().filter(|e| e.pc == Addr(0x1000)).count();
+    assert_eq!(loads, 2, "one for each call");
+}
+
+#[test]
+fn test_leaks() {
+    // alloc_a, alloc_b: addi sp,sp,-16; sd ra,8(sp); call __kmalloc;
+    //                   ld ra,8(sp); addi sp,sp,16; ret
+    let alloc = |call| {
+        [
+            0xff010113, 0x00113423, call, 0x00813083, 0x01010113, 0x00008067,
+        ]
+    };
+    let mut fix = test_fixture(&[
+        ("__kmalloc", &[0x00100073]),
+        ("alloc_a", &alloc(0xff9fe0ef)),
+        ("alloc_b", &alloc(0xff9fd0ef)),
+    ]);
+    fix.at_func("__kmalloc", Box::new(crate::stubs::kmalloc))
+        .unwrap();
+
+    // Blocks the test allocates don't count.
+    fix.vm.mem.alloc(1024).unwrap();
+
+    let mut ptrs = Vec::new();
+    for (func, len) in &[("alloc_a", 32), ("alloc_b", 100), ("alloc_a", 32)] {
+        fix.vm.set_reg(A0, *len);
+        fix.vm.set_reg(A1, 0);
+        fix.call(func).unwrap();
+        ptrs.push(Addr(fix.vm.reg(A0)));
+    }
+
+    // Grouped by where they were allocated from, most bytes first.
+    let leaks = fix.leaks();
+    assert_eq!(leaks.len(), 2);
+    assert_eq!(leaks[0].blocks, vec![(ptrs[1], 100)]);
+    assert_eq!(leaks[0].trace[0], Addr(0x3008));
+    assert_eq!(leaks[1].blocks, vec![(ptrs[0], 32), (ptrs[2], 32)]);
+    assert_eq!(leaks[1].trace[0], Addr(0x2008));
+    assert_eq!(leaks[1].bytes(), 64);
+
+    fix.vm.mem.free(ptrs[1]).unwrap();
+    assert_eq!(fix.leaks().len(), 1);
+}
+
+// Calls a guest function of 300 blocks from the host 20,000 times.
+// Every call maps a fresh exit address, which mustn't throw away the
+// blocks already decoded.  Only meaningful in a release build, and
+// without RUST_BACKTRACE, since every call returns through an error:
+//
+//   cargo test --release bench_host_calls -- --ignored --nocapture
+#[test]
+#[ignore]
+fn bench_host_calls() {
+    use std::time::Instant;
+
+    // 300 x (addi a0,a0,1; xor a1,a1,a0; bnez a0,1f; 1:), ret
+    let mut work = Vec::new();
+    for _ in 0..300 {
+        work.extend_from_slice(&[0x00150513, 0x00a5c5b3, 0x00051263]);
+    }
+    work.push(0x00008067);
+    let mut fix = test_fixture(&[("work", &work)]);
+
+    let start = Instant::now();
+    for _ in 0..20_000 {
+        fix.call("work").unwrap();
+    }
+    let secs = start.elapsed().as_secs_f64();
+    let instrs = fix.vm.stats.instrs;
+    println!(
+        "{} instrs in {:.2}s, {:.1} Minstr/s",
+        instrs,
+        secs,
+        instrs as f64 / secs / 1_000_000.0
+    );
+}
@@ -0,0 +1,701 @@
+use crate::fixture::*;
+use crate::memory::*;
+use crate::vm::*;
+
+use anyhow::{anyhow, Result};
+use gdbstub::arch::Arch;
+use gdbstub::target::ext::base::singlethread::{
+    SingleThreadOps, SingleThreadReverseCont, SingleThreadReverseContOps, SingleThreadReverseStep,
+    SingleThreadReverseStepOps, StopReason,
+};
+use gdbstub::target::ext::base::{self, GdbInterrupt, ReplayLogPosition, ResumeAction};
+use gdbstub::target::ext::breakpoints::{
+    Breakpoints, BreakpointsOps, HwWatchpoint, HwWatchpointOps, SwBreakpoint, SwBreakpointOps,
+    WatchKind as GdbWatchKind,
+};
+use gdbstub::target::ext::monitor_cmd::{outputln, ConsoleOutput, MonitorCmd, MonitorCmdOps};
+use gdbstub::target::{Target, TargetError, TargetResult};
+use gdbstub::GdbStub;
+use gdbstub_arch::riscv::Riscv64;
+use log::{debug, warn};
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+//-------------------------------
+
+// The Fixture isn't Send (it's full of boxed closures), so it stays on the
+// test thread.  The gdbstub state machine runs on a thread of its own, and
+// forwards every request that needs to look at the guest over a channel.
+// The test thread services these requests whenever the guest is stopped.
+
+const NR_REGS: usize = 33;
+
+// SIGTRAP etc. as gdb understands them.  These match Linux, so are
+// used in core files too.
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+pub(crate) const SIGABRT: u8 = 6;
+pub(crate) const SIGSEGV: u8 = 11;
+const SIGXCPU: u8 = 24;
+
+// The signal a fault is reported as.  None if it isn't a fault.
+pub(crate) fn fault_signal(e: &VmErr) -> Option<u8> {
+    match e {
+        VmErr::BadAccess(_) => Some(SIGSEGV),
+        VmErr::DecodeError(_) | VmErr::UnimplementedInstruction(_) | VmErr::IllegalCsr(_) => {
+            Some(SIGILL)
+        }
+        VmErr::EBreak | VmErr::ECall => Some(SIGTRAP),
+        VmErr::BudgetExhausted { .. } => Some(SIGXCPU),
+        VmErr::Breakpoint | VmErr::Watchpoint(_) => None,
+    }
+}
+
+enum Request {
+    ReadRegs,
+    WriteRegs(Vec<u64>),
+    ReadMem(u64, usize),
+    WriteMem(u64, Vec<u8>),
+    AddBreakpoint(u64),
+    RemoveBreakpoint(u64),
+    AddWatchpoint(u64, WatchKind),
+    RemoveWatchpoint(u64, WatchKind),
+    Monitor(String),
+    Resume(ResumeAction),
+    ReverseStep,
+    ReverseCont,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Stop {
+    DoneStep,
+    Interrupted,
+    Breakpoint,
+    Watchpoint(u64, WatchKind),
+    Halted,
+    Signal(u8),
+
+    // Reversing has reached the start of the recording.
+    ReplayStart,
+}
+
+enum Reply {
+    Regs(Vec<u64>),
+    Mem(Option<Vec<u8>>),
+    Done(bool),
+    Text(String),
+    Stopped(Stop),
+}
+
+fn to_watch_kind(kind: GdbWatchKind) -> WatchKind {
+    match kind {
+        GdbWatchKind::Write => WatchKind::Write,
+        GdbWatchKind::Read => WatchKind::Read,
+        GdbWatchKind::ReadWrite => WatchKind::Access,
+    }
+}
+
+fn from_watch_kind(kind: WatchKind) -> GdbWatchKind {
+    match kind {
+        WatchKind::Write => GdbWatchKind::Write,
+        WatchKind::Read => GdbWatchKind::Read,
+        WatchKind::Access => GdbWatchKind::ReadWrite,
+    }
+}
+
+// Addresses typed by the user, in hex with a 0x prefix, or decimal.
+fn parse_addr(s: &str) -> Option<Addr> {
+    let v = if let Some(hex) = s.strip_prefix("0x") {
+        u64::from_str_radix(hex, 16).ok()?
+    } else {
+        s.parse().ok()?
+    };
+    Some(Addr(v))
+}
+
+//-------------------------------
+
+// Lives on the gdbstub thread.
+struct GdbTarget {
+    requests: Sender<Request>,
+    replies: Receiver<Reply>,
+    interrupt: Arc<AtomicBool>,
+}
+
+impl GdbTarget {
+    fn request(&mut self, req: Request) -> Result<Reply> {
+        self.requests
+            .send(req)
+            .map_err(|_| anyhow!("test thread has gone away"))?;
+        self.replies
+            .recv()
+            .map_err(|_| anyhow!("test thread has gone away"))
+    }
+
+    // Sends a request that runs the guest, forwards or backwards, and
+    // waits for it to stop.
+    fn run(&mut self, req: Request, gdb_interrupt: GdbInterrupt<'_>) -> Result<StopReason<u64>> {
+        self.requests
+            .send(req)
+            .map_err(|_| anyhow!("test thread has gone away"))?;
+
+        let mut gdb_interrupt = gdb_interrupt.no_async();
+        loop {
+            match self.replies.recv_timeout(Duration::from_millis(10)) {
+                Ok(Reply::Stopped(stop)) => {
+                    let reason = match stop {
+                        Stop::DoneStep => StopReason::DoneStep,
+                        Stop::Interrupted => StopReason::GdbInterrupt,
+                        Stop::Breakpoint => StopReason::SwBreak,
+                        Stop::Watchpoint(addr, kind) => StopReason::Watch {
+                            kind: from_watch_kind(kind),
+                            addr,
+                        },
+                        Stop::Halted => StopReason::Exited(0),
+                        Stop::Signal(sig) => StopReason::Signal(sig),
+                        Stop::ReplayStart => StopReason::ReplayLog(ReplayLogPosition::Begin),
+                    };
+                    return Ok(reason);
+                }
+                Ok(_) => return Err(anyhow!("unexpected reply to resume")),
+                Err(RecvTimeoutError::Timeout) => {
+                    if gdb_interrupt.pending() {
+                        self.interrupt.store(true, Ordering::SeqCst);
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => return Ok(StopReason::Exited(0)),
+            }
+        }
+    }
+}
+
+impl SingleThreadOps for GdbTarget {
+    fn resume(
+        &mut self,
+        action: ResumeAction,
+        gdb_interrupt: GdbInterrupt<'_>,
+    ) -> Result<StopReason<<Self::Arch as Arch>::Usize>, Self::Error> {
+        self.run(Request::Resume(action), gdb_interrupt)
+    }
+
+    fn support_reverse_step(&mut self) -> Option<SingleThreadReverseStepOps<Self>> {
+        Some(self)
+    }
+
+    fn support_reverse_cont(&mut self) -> Option<SingleThreadReverseContOps<Self>> {
+        Some(self)
+    }
+
+    fn read_registers(
+        &mut self,
+        regs: &mut <Self::Arch as Arch>::Registers,
+    ) -> TargetResult<(), Self> {
+        match self
+            .request(Request::ReadRegs)
+            .map_err(TargetError::Fatal)?
+        {
+            Reply::Regs(values) => {
+                regs.x.copy_from_slice(&values[0..32]);
+                regs.pc = values[32];
+                Ok(())
+            }
+            _ => Err(TargetError::Fatal(anyhow!("unexpected reply to read regs"))),
+        }
+    }
+
+    fn write_registers(
+        &mut self,
+        regs: &<Self::Arch as Arch>::Registers,
+    ) -> TargetResult<(), Self> {
+        let mut values = regs.x.to_vec();
+        values.push(regs.pc);
+        match self
+            .request(Request::WriteRegs(values))
+            .map_err(TargetError::Fatal)?
+        {
+            Reply::Done(_) => Ok(()),
+            _ => Err(TargetError::Fatal(anyhow!(
+                "unexpected reply to write regs"
+            ))),
+        }
+    }
+
+    fn read_addrs(
+        &mut self,
+        start_addr: <Self::Arch as Arch>::Usize,
+        data: &mut [u8],
+    ) -> TargetResult<(), Self> {
+        match self
+            .request(Request::ReadMem(start_addr, data.len()))
+            .map_err(TargetError::Fatal)?
+        {
+            Reply::Mem(Some(bytes)) => {
+                data.copy_from_slice(&bytes);
+                Ok(())
+            }
+            Reply::Mem(None) => Err(TargetError::NonFatal),
+            _ => Err(TargetError::Fatal(anyhow!("unexpected reply to read mem"))),
+        }
+    }
+
+    fn write_addrs(
+        &mut self,
+        start_addr: <Self::Arch as Arch>::Usize,
+        data: &[u8],
+    ) -> TargetResult<(), Self> {
+        match self
+            .request(Request::WriteMem(start_addr, data.to_vec()))
+            .map_err(TargetError::Fatal)?
+        {
+            Reply::Done(true) => Ok(()),
+            Reply::Done(false) => Err(TargetError::NonFatal),
+            _ => Err(TargetError::Fatal(anyhow!("unexpected reply to write mem"))),
+        }
+    }
+}
+
+impl SingleThreadReverseStep for GdbTarget {
+    fn reverse_step(
+        &mut self,
+        gdb_interrupt: GdbInterrupt<'_>,
+    ) -> Result<StopReason<<Self::Arch as Arch>::Usize>, Self::Error> {
+        self.run(Request::ReverseStep, gdb_interrupt)
+    }
+}
+
+impl SingleThreadReverseCont for GdbTarget {
+    fn reverse_cont(
+        &mut self,
+        gdb_interrupt: GdbInterrupt<'_>,
+    ) -> Result<StopReason<<Self::Arch as Arch>::Usize>, Self::Error> {
+        self.run(Request::ReverseCont, gdb_interrupt)
+    }
+}
+
+impl Breakpoints for GdbTarget {
+    fn sw_breakpoint(&mut self) -> Option<SwBreakpointOps<Self>> {
+        Some(self)
+    }
+
+    fn hw_watchpoint(&mut self) -> Option<HwWatchpointOps<Self>> {
+        Some(self)
+    }
+}
+
+impl SwBreakpoint for GdbTarget {
+    fn add_sw_breakpoint(
+        &mut self,
+        addr: <Self::Arch as Arch>::Usize,
+        _kind: <Self::Arch as Arch>::BreakpointKind,
+    ) -> TargetResult<bool, Self> {
+        match self
+            .request(Request::AddBreakpoint(addr))
+            .map_err(TargetError::Fatal)?
+        {
+            Reply::Done(r) => Ok(r),
+            _ => Err(TargetError::Fatal(anyhow!("unexpected reply to add bp"))),
+        }
+    }
+
+    fn remove_sw_breakpoint(
+        &mut self,
+        addr: <Self::Arch as Arch>::Usize,
+        _kind: <Self::Arch as Arch>::BreakpointKind,
+    ) -> TargetResult<bool, Self> {
+        match self
+            .request(Request::RemoveBreakpoint(addr))
+            .map_err(TargetError::Fatal)?
+        {
+            Reply::Done(r) => Ok(r),
+            _ => Err(TargetError::Fatal(anyhow!("unexpected reply to remove bp"))),
+        }
+    }
+}
+
+impl HwWatchpoint for GdbTarget {
+    fn add_hw_watchpoint(
+        &mut self,
+        addr: <Self::Arch as Arch>::Usize,
+        kind: GdbWatchKind,
+    ) -> TargetResult<bool, Self> {
+        match self
+            .request(Request::AddWatchpoint(addr, to_watch_kind(kind)))
+            .map_err(TargetError::Fatal)?
+        {
+            Reply::Done(r) => Ok(r),
+            _ => Err(TargetError::Fatal(anyhow!("unexpected reply to add wp"))),
+        }
+    }
+
+    fn remove_hw_watchpoint(
+        &mut self,
+        addr: <Self::Arch as Arch>::Usize,
+        kind: GdbWatchKind,
+    ) -> TargetResult<bool, Self> {
+        match self
+            .request(Request::RemoveWatchpoint(addr, to_watch_kind(kind)))
+            .map_err(TargetError::Fatal)?
+        {
+            Reply::Done(r) => Ok(r),
+            _ => Err(TargetError::Fatal(anyhow!("unexpected reply to remove wp"))),
+        }
+    }
+}
+
+impl MonitorCmd for GdbTarget {
+    fn handle_monitor_cmd(
+        &mut self,
+        cmd: &[u8],
+        mut out: ConsoleOutput<'_>,
+    ) -> Result<(), Self::Error> {
+        let cmd = String::from_utf8_lossy(cmd).to_string();
+        match self.request(Request::Monitor(cmd))? {
+            Reply::Text(text) => {
+                outputln!(out, "{}", text);
+                Ok(())
+            }
+            _ => Err(anyhow!("unexpected reply to monitor")),
+        }
+    }
+}
+
+impl Target for GdbTarget {
+    type Arch = Riscv64;
+    type Error = anyhow::Error;
+
+    fn base_ops(&mut self) -> base::BaseOps<Self::Arch, Self::Error> {
+        base::BaseOps::SingleThread(self)
+    }
+
+    fn breakpoints(&mut self) -> Option<BreakpointsOps<Self>> {
+        Some(self)
+    }
+
+    fn monitor_cmd(&mut self) -> Option<MonitorCmdOps<Self>> {
+        Some(self)
+    }
+}
+
+//-------------------------------
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Stopped,
+    Continue,
+    Step,
+    StepDone,
+}
+
+/// The test thread's end of a gdb connection.  Owned by the Fixture,
+/// which consults it before every instruction while one is attached.
+pub struct Debugger {
+    requests: Receiver<Request>,
+    replies: Sender<Reply>,
+    interrupt: Arc<AtomicBool>,
+    thread: Option<JoinHandle<Result<()>>>,
+
+    breakpoints: BTreeSet<u64>,
+    mode: Mode,
+
+    // gdb's watchpoints, mapped to the ids Memory gave them.
+    watchpoints: BTreeMap<(u64, WatchKind), u64>,
+
+    // Set when one of gdb's watchpoints has been hit.
+    watch_stop: Option<(u64, WatchKind)>,
+
+    // The pc we resumed from, so we don't immediately stop on the
+    // breakpoint we're sat on.
+    resumed_from: Option<u64>,
+
+    // The fault gdb is looking at, explained, see 'monitor fault'.
+    fault: Option<String>,
+}
+
+impl Debugger {
+    /// Starts a gdbstub session on the given connection.  The guest is
+    /// considered stopped until gdb asks for it to be resumed.
+    pub fn new(stream: TcpStream) -> Self {
+        let (req_tx, req_rx) = channel();
+        let (rep_tx, rep_rx) = channel();
+        let interrupt = Arc::new(AtomicBool::new(false));
+
+        let thread = {
+            let interrupt = interrupt.clone();
+            thread::spawn(move || -> Result<()> {
+                let mut target = GdbTarget {
+                    requests: req_tx,
+                    replies: rep_rx,
+                    interrupt,
+                };
+                let mut stub = GdbStub::<GdbTarget, TcpStream>::new(stream);
+                stub.run(&mut target)
+                    .map_err(|e| anyhow!("gdbstub error: {:?}", e))?;
+                Ok(())
+            })
+        };
+
+        Debugger {
+            requests: req_rx,
+            replies: rep_tx,
+            interrupt,
+            thread: Some(thread),
+            breakpoints: BTreeSet::new(),
+            mode: Mode::Stopped,
+            watchpoints: BTreeMap::new(),
+            watch_stop: None,
+            resumed_from: None,
+            fault: None,
+        }
+    }
+
+    /// Returns the reason we should stop before executing the instruction
+    /// at pc, if any.
+    fn should_stop(&mut self, pc: u64) -> Option<Stop> {
+        if let Some(from) = self.resumed_from {
+            if from != pc {
+                self.resumed_from = None;
+            }
+        }
+
+        if self.interrupt.swap(false, Ordering::SeqCst) {
+            Some(Stop::Interrupted)
+        } else if let Some((addr, kind)) = self.watch_stop.take() {
+            Some(Stop::Watchpoint(addr, kind))
+        } else if self.mode == Mode::StepDone {
+            Some(Stop::DoneStep)
+        } else if self.breakpoints.contains(&pc) && self.resumed_from.is_none() {
+            Some(Stop::Breakpoint)
+        } else {
+            None
+        }
+    }
+
+    fn read_regs(fix: &Fixture) -> Vec<u64> {
+        use crate::decode::Reg;
+
+        let mut values = Vec::with_capacity(NR_REGS);
+        for r in 0..NR_REGS {
+            values.push(fix.vm.reg(Reg::from(r as u32)));
+        }
+        values
+    }
+
+    fn write_regs(fix: &mut Fixture, values: &[u64]) {
+        use crate::decode::Reg;
+
+        for (r, v) in values.iter().enumerate().take(NR_REGS) {
+            fix.vm.set_reg(Reg::from(r as u32), *v);
+        }
+    }
+
+    // Services requests from gdb until it resumes the guest.  Returns
+    // false if gdb has disconnected.
+    fn service(&mut self, fix: &mut Fixture) -> bool {
+        loop {
+            let req = match self.requests.recv() {
+                Ok(req) => req,
+                Err(_) => return false,
+            };
+
+            let reply = match req {
+                Request::ReadRegs => Reply::Regs(Self::read_regs(fix)),
+                Request::WriteRegs(values) => {
+                    Self::write_regs(fix, &values);
+                    Reply::Done(true)
+                }
+                Request::ReadMem(addr, len) => {
+                    let mut bytes = vec![0; len];
+                    match fix.vm.mem.read(Addr(addr), &mut bytes, 0) {
+                        Ok(()) => Reply::Mem(Some(bytes)),
+                        Err(_) => Reply::Mem(None),
+                    }
+                }
+                Request::WriteMem(addr, bytes) => {
+                    Reply::Done(fix.vm.mem.write(Addr(addr), &bytes, 0).is_ok())
+                }
+                Request::AddBreakpoint(addr) => {
+                    self.breakpoints.insert(addr);
+                    Reply::Done(true)
+                }
+                Request::RemoveBreakpoint(addr) => Reply::Done(self.breakpoints.remove(&addr)),
+                Request::AddWatchpoint(addr, kind) => {
+                    // gdb only tells us the address of a watchpoint, not
+                    // its size, so we watch a single byte.
+                    let id = fix.vm.mem.add_watchpoint(Addr(addr), Addr(addr + 1), kind);
+                    if let Some(old) = self.watchpoints.insert((addr, kind), id) {
+                        fix.vm.mem.rm_watchpoint(old);
+                    }
+                    Reply::Done(true)
+                }
+                Request::RemoveWatchpoint(addr, kind) => {
+                    match self.watchpoints.remove(&(addr, kind)) {
+                        Some(id) => Reply::Done(fix.vm.mem.rm_watchpoint(id)),
+                        None => Reply::Done(false),
+                    }
+                }
+                Request::Monitor(cmd) => Reply::Text(self.monitor(fix, &cmd)),
+                Request::Resume(action) => {
+                    self.mode = match action {
+                        // gdb steps with the signal it last saw after a
+                        // fault, eg, a stepi after SIGSEGV.
+                        ResumeAction::Step | ResumeAction::StepWithSignal(_) => Mode::Step,
+                        ResumeAction::Continue | ResumeAction::ContinueWithSignal(_) => {
+                            Mode::Continue
+                        }
+                    };
+                    self.resumed_from = Some(fix.vm.pc().0);
+                    return true;
+                }
+
+                // Reversing only moves through the recording, so the
+                // guest stays stopped and we carry on servicing.
+                Request::ReverseStep => {
+                    self.fault = None;
+                    if fix.vm.reverse_step() {
+                        Reply::Stopped(Stop::DoneStep)
+                    } else {
+                        Reply::Stopped(Stop::ReplayStart)
+                    }
+                }
+                Request::ReverseCont => {
+                    self.fault = None;
+                    Reply::Stopped(self.reverse_cont(fix))
+                }
+            };
+
+            if self.replies.send(reply).is_err() {
+                return false;
+            }
+        }
+    }
+
+    // Winds back to the last of gdb's breakpoints, stopping early if
+    // gdb interrupts us.
+    fn reverse_cont(&mut self, fix: &mut Fixture) -> Stop {
+        let breakpoints = &self.breakpoints;
+        let interrupt = &self.interrupt;
+        let hit = fix.vm.reverse_until(|vm| {
+            interrupt.load(Ordering::SeqCst) || breakpoints.contains(&vm.pc().0)
+        });
+
+        if self.interrupt.swap(false, Ordering::SeqCst) {
+            Stop::Interrupted
+        } else if hit {
+            Stop::Breakpoint
+        } else {
+            Stop::ReplayStart
+        }
+    }
+
+    // Runs a 'monitor' command from gdb, returning its output.
+    fn monitor(&self, fix: &Fixture, cmd: &str) -> String {
+        let words: Vec<&str> = cmd.split_whitespace().collect();
+        match words.as_slice() {
+            ["fault"] => self
+                .fault
+                .clone()
+                .unwrap_or_else(|| "no fault to explain".to_string()),
+            ["describe", addr] => match parse_addr(addr) {
+                Some(addr) => fix
+                    .describe_addr(addr)
+                    .unwrap_or_else(|| format!("nothing at {:#x}", addr.0)),
+                None => format!("bad address '{}'", addr),
+            },
+            _ => [
+                "commands:",
+                "  describe ADDR  what an address belongs to",
+                "  fault          why the guest stopped with a signal",
+            ]
+            .join("\n"),
+        }
+    }
+
+    // Tells gdb the guest has stopped, and then waits for it to be
+    // resumed.  Returns false if gdb has disconnected.
+    fn stop(&mut self, fix: &mut Fixture, stop: Stop) -> bool {
+        debug!("gdb stop: {:?} at {:?}", stop, fix.vm.pc());
+
+        // A freshly attached gdb isn't waiting on a resume.
+        if self.mode != Mode::Stopped && self.replies.send(Reply::Stopped(stop)).is_err() {
+            return false;
+        }
+        self.mode = Mode::Stopped;
+        self.service(fix)
+    }
+
+    /// Stops the freshly attached guest, so breakpoints can be set
+    /// before the test gets going.  Returns false if gdb has disconnected.
+    pub(crate) fn attach(&mut self, fix: &mut Fixture) -> bool {
+        self.stop(fix, Stop::Halted)
+    }
+
+    /// Called before every instruction, stops if gdb wants us to.
+    /// Returns false if gdb has disconnected.
+    pub(crate) fn check(&mut self, fix: &mut Fixture) -> bool {
+        match self.should_stop(fix.vm.pc().0) {
+            Some(stop) => self.stop(fix, stop),
+            None => true,
+        }
+    }
+
+    /// Called after every instruction.  Breakpoint callbacks count as a
+    /// single step, since they usually emulate a whole function.
+    pub(crate) fn stepped(&mut self, r: &crate::vm::Result<()>) {
+        if self.mode == Mode::Step {
+            match r {
+                Ok(()) | Err(VmErr::Breakpoint) | Err(VmErr::Watchpoint(_)) => {
+                    self.mode = Mode::StepDone
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Called with the watchpoint hits of an instruction, or stub.  If
+    /// any of them are gdb's we stop before the next instruction.
+    pub(crate) fn watch_hits(&mut self, hits: &[WatchHit]) {
+        for hit in hits {
+            for ((addr, kind), id) in &self.watchpoints {
+                if *id == hit.id {
+                    self.watch_stop = Some((*addr, *kind));
+                }
+            }
+        }
+    }
+
+    /// Gives gdb a chance to look at a fault before the error is
+    /// returned to the test.  Returns false if gdb has disconnected.
+    pub(crate) fn fault(&mut self, fix: &mut Fixture, e: &VmErr) -> bool {
+        match fault_signal(e) {
+            Some(sig) => {
+                self.fault = Some(fix.explain_fault(e).unwrap_or_else(|| e.to_string()));
+                let r = self.stop(fix, Stop::Signal(sig));
+                self.fault = None;
+                r
+            }
+            None => true,
+        }
+    }
+
+    /// Tells gdb the guest has finished, and waits for the stub to shut down.
+    pub(crate) fn finish(&mut self) {
+        if self.mode != Mode::Stopped {
+            let _ = self.replies.send(Reply::Stopped(Stop::Halted));
+        }
+
+        if let Some(thread) = self.thread.take() {
+            match thread.join() {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => warn!("{}", e),
+                Err(_) => warn!("gdbstub thread panicked"),
+            }
+        }
+    }
+}
+
+//-------------------------------
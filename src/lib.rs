@@ -8,12 +8,21 @@ extern crate regex;
 extern crate thiserror;
 
 pub mod block_manager;
+pub mod core_dump;
+pub mod cost;
+pub mod coverage;
+pub mod csr;
 pub mod decode;
+pub mod dwarf;
 pub mod fixture;
+pub mod gdb;
 pub mod guest;
 pub mod loader;
 pub mod memory;
 pub mod primitive;
+pub mod profile;
+pub mod replay;
+pub mod semihost;
 pub mod stats;
 pub mod stubs;
 pub mod test_runner;
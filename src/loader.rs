@@ -4,7 +4,7 @@ use elf::types::*;
 use log::debug;
 use nom::{number::complete::*, IResult};
 use std::collections::BTreeMap;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use crate::memory::{Addr, Memory, PERM_EXEC, PERM_READ, PERM_WRITE};
 
@@ -18,6 +18,175 @@ fn next_word(ptr: u64) -> u64 {
     ((ptr + 3) / 4) * 4
 }
 
+/// Where a section of the module ended up in guest memory.
+#[derive(Clone, Copy, Debug)]
+pub struct LoadedSection {
+    pub base: Addr,
+    pub len: u64,
+}
+
+/// An entry from the module's __bug_table, describing the ebreak that a
+/// BUG() or WARN() compiles to.
+#[derive(Clone, Debug)]
+pub struct BugEntry {
+    // Address of the ebreak.
+    pub addr: Addr,
+
+    // Address of the entry itself, so flags can be updated.
+    pub entry: Addr,
+
+    pub file: Option<String>,
+    pub line: u16,
+    pub flags: u16,
+}
+
+pub const BUGFLAG_WARNING: u16 = 1 << 0;
+pub const BUGFLAG_ONCE: u16 = 1 << 1;
+pub const BUGFLAG_DONE: u16 = 1 << 2;
+
+// Offset of the flags within a bug_entry.
+pub const BUG_FLAGS_OFFSET: u64 = 10;
+
+impl BugEntry {
+    pub fn is_warning(&self) -> bool {
+        (self.flags & BUGFLAG_WARNING) != 0
+    }
+
+    /// 'file:line', or None if the kernel wasn't built with
+    /// CONFIG_DEBUG_BUGVERBOSE.
+    pub fn location(&self) -> Option<String> {
+        self.file
+            .as_ref()
+            .map(|file| format!("{}:{}", file, self.line))
+    }
+}
+
+/// A module that has been loaded and relocated.
+pub struct Module {
+    pub path: PathBuf,
+
+    // Symbol addresses reflect where the sections were loaded.
+    pub symbols: BTreeMap<String, Symbol>,
+    pub sections: BTreeMap<String, LoadedSection>,
+
+    // Function start address -> (name, size), for symbolizing addresses.
+    functions: BTreeMap<u64, (String, u64)>,
+
+    // The full symbol table, in elf order, so relocations can refer
+    // to it.
+    symtab: Vec<Symbol>,
+
+    // __bug_table entries, indexed by the address of their ebreak.
+    bugs: BTreeMap<u64, BugEntry>,
+}
+
+impl Module {
+    /// Finds the function containing 'addr', returning its name and
+    /// the offset into it.
+    pub fn lookup_addr(&self, addr: u64) -> Option<(&str, u64)> {
+        let (begin, (name, size)) = self.functions.range(..=addr).next_back()?;
+        let offset = addr - begin;
+        if offset < u64::max(*size, 1) {
+            Some((name, offset))
+        } else {
+            None
+        }
+    }
+
+    /// Reads the .debug_* sections of the module, and applies their
+    /// relocations so addresses reflect where the module was loaded.
+    /// These sections aren't loaded into the guest, so this is done
+    /// on demand.
+    pub fn debug_sections(&self) -> Result<BTreeMap<String, Vec<u8>>> {
+        let file =
+            elf::File::open_path(&self.path).map_err(|_e| anyhow!("couldn't read elf file"))?;
+
+        let mut sections = BTreeMap::new();
+        for s in &file.sections {
+            if s.shdr.name.starts_with(".debug_") {
+                sections.insert(s.shdr.name.clone(), s.data.clone());
+            }
+        }
+
+        for r in &file.sections {
+            if r.shdr.shtype != SHT_RELA {
+                continue;
+            }
+
+            let target = &file.sections[r.shdr.info as usize].shdr.name;
+            if let Some(data) = sections.get_mut(target) {
+                let (_, rlocs) = nom::multi::many0(parse_relocation)(&r.data)
+                    .map_err(|_| anyhow!("couldn't parse relocations"))?;
+                for rloc in rlocs {
+                    let sym = &self.symtab[rloc.sym as usize];
+                    relocate_data(data, &rloc, sym.value.wrapping_add(rloc.addend))?;
+                }
+            }
+        }
+
+        Ok(sections)
+    }
+
+    /// A module of functions that are already in guest memory, for
+    /// tests that don't have a .ko.
+    #[cfg(test)]
+    pub fn from_functions(funcs: &[(&str, Addr, u64)]) -> Self {
+        let mut symbols = BTreeMap::new();
+        let mut functions = BTreeMap::new();
+        let mut symtab = Vec::new();
+        for (name, addr, size) in funcs {
+            let sym = Symbol {
+                name: name.to_string(),
+                value: addr.0,
+                size: *size,
+                shndx: 1,
+                symtype: STT_FUNC,
+                bind: STB_GLOBAL,
+                vis: STV_DEFAULT,
+            };
+            functions.insert(addr.0, (name.to_string(), *size));
+            symbols.insert(name.to_string(), sym.clone());
+            symtab.push(sym);
+        }
+
+        Module {
+            path: PathBuf::new(),
+            symbols,
+            sections: BTreeMap::new(),
+            functions,
+            symtab,
+            bugs: BTreeMap::new(),
+        }
+    }
+
+    /// The BUG() or WARN() whose ebreak is at 'addr'.
+    pub fn bug_at(&self, addr: Addr) -> Option<&BugEntry> {
+        self.bugs.get(&addr.0)
+    }
+
+    /// Formats an address as 'func+0x1c', or just hex if it's not in
+    /// a function we know about.
+    pub fn symbolize(&self, addr: u64) -> String {
+        match self.lookup_addr(addr) {
+            Some((name, 0)) => name.to_string(),
+            Some((name, offset)) => format!("{}+0x{:x}", name, offset),
+            None => format!("0x{:x}", addr),
+        }
+    }
+
+    /// Builds a gdb command that loads the module's debug info at the
+    /// addresses we loaded it to.
+    pub fn add_symbol_file_cmd(&self) -> String {
+        let mut cmd = format!("add-symbol-file {}", self.path.display());
+        for (name, s) in &self.sections {
+            if s.len > 0 {
+                cmd.push_str(&format!(" -s {} 0x{:x}", name, s.base.0));
+            }
+        }
+        cmd
+    }
+}
+
 // Layout of module in memory:
 //    [text] [ro-data] [w-data]
 //
@@ -30,7 +199,7 @@ fn load_sections(
     mem: &mut Memory,
     ss: Vec<&elf::Section>,
     perms: u8,
-    bases: &mut BTreeMap<String, Addr>,
+    bases: &mut BTreeMap<String, LoadedSection>,
 ) -> Result<()> {
     let mut len = 0;
     for s in ss {
@@ -61,7 +230,13 @@ fn load_sections(
             begin,
             Addr(begin.0 + s.shdr.size)
         );
-        bases.insert(s.shdr.name.clone(), begin);
+        bases.insert(
+            s.shdr.name.clone(),
+            LoadedSection {
+                base: begin,
+                len: s.shdr.size,
+            },
+        );
 
         len = next_word(len + s.shdr.size);
     }
@@ -295,6 +470,48 @@ fn relocate(
     Ok(())
 }
 
+// Relocates data that isn't loaded into the guest, eg, debug info.
+// These only use the simple data relocations.
+fn relocate_data(data: &mut [u8], rloc: &Relocation, v: u64) -> Result<()> {
+    use RelocationType::*;
+
+    let offset = rloc.offset as usize;
+    let width = match rloc.rtype {
+        R64 | RADD64 | RSUB64 => 8,
+        R32 | RADD32 | RSUB32 | RSET32 => 4,
+        RADD16 | RSUB16 | RSET16 => 2,
+        RADD8 | RSUB8 | RSET8 | RSET6 | RSUB6 => 1,
+        RNONE | RRELAX => return Ok(()),
+        _ => {
+            return Err(anyhow!(
+                "unsupported debug relocation type: {:?}",
+                rloc.rtype
+            ))
+        }
+    };
+
+    if offset + width > data.len() {
+        return Err(anyhow!("relocation out of bounds"));
+    }
+    let loc = &mut data[offset..offset + width];
+
+    let mut bytes = [0u8; 8];
+    bytes[0..width].copy_from_slice(loc);
+    let old = u64::from_le_bytes(bytes);
+
+    let new = match rloc.rtype {
+        R64 | R32 | RSET32 | RSET16 | RSET8 => v,
+        RADD64 | RADD32 | RADD16 | RADD8 => old.wrapping_add(v),
+        RSUB64 | RSUB32 | RSUB16 | RSUB8 => old.wrapping_sub(v),
+        RSET6 => (old & !0x3f) | (v & 0x3f),
+        RSUB6 => (old & !0x3f) | (old.wrapping_sub(v) & 0x3f),
+        _ => unreachable!(),
+    };
+
+    loc.copy_from_slice(&new.to_le_bytes()[0..width]);
+    Ok(())
+}
+
 /// Some relocations need to be performed as a pair.  ie. the location of the hi
 /// relocation is used in the calculation for the low bits.
 enum CompoundRel {
@@ -336,7 +553,7 @@ fn exec_relocations(
     mem: &mut Memory,
     rs: Vec<&elf::Section>,
     indexes: &BTreeMap<u16, String>,
-    bases: &BTreeMap<String, Addr>,
+    bases: &BTreeMap<String, LoadedSection>,
     syms: &Vec<Symbol>,
 ) -> Result<()> {
     for r in rs {
@@ -347,10 +564,10 @@ fn exec_relocations(
         // mutates.  We need the base address that this section is loaded at.
         let index = r.shdr.info as u16;
         let base = match bases.get(indexes.get(&index).unwrap()) {
-            Some(base) => base,
+            Some(section) => section.base,
             None => {
                 debug!("No base found for section {}", r.shdr.name);
-                return Ok(());
+                continue;
             }
         };
 
@@ -405,6 +622,52 @@ fn exec_relocations(
     Ok(())
 }
 
+// Size of a bug_entry for riscv64 with CONFIG_GENERIC_BUG_RELATIVE_POINTERS
+// and CONFIG_DEBUG_BUGVERBOSE, as in misc/kernel.config:
+//
+//    s32 bug_addr_disp;
+//    s32 file_disp;
+//    u16 line;
+//    u16 flags;
+//
+// In this kernel the displacements are relative to the start of the entry.
+const BUG_ENTRY_SIZE: u64 = 12;
+
+// Reads the __bug_table from guest memory.  It must have been relocated.
+fn read_bug_table(mem: &mut Memory, table: &LoadedSection) -> Result<BTreeMap<u64, BugEntry>> {
+    let mut bugs = BTreeMap::new();
+
+    let mut entry = table.base.0;
+    while entry + BUG_ENTRY_SIZE <= table.base.0 + table.len {
+        let addr_disp = mem.read_into::<i32>(Addr(entry), PERM_READ)?;
+        let file_disp = mem.read_into::<i32>(Addr(entry + 4), PERM_READ)?;
+        let line = mem.read_into::<u16>(Addr(entry + 8), PERM_READ)?;
+        let flags = mem.read_into::<u16>(Addr(entry + BUG_FLAGS_OFFSET), PERM_READ)?;
+
+        let addr = Addr(entry.wrapping_add(addr_disp as i64 as u64));
+        let file = if file_disp != 0 {
+            mem.read_string(Addr(entry.wrapping_add(file_disp as i64 as u64)))
+                .ok()
+        } else {
+            None
+        };
+
+        bugs.insert(
+            addr.0,
+            BugEntry {
+                addr,
+                entry: Addr(entry),
+                file,
+                line,
+                flags,
+            },
+        );
+        entry += BUG_ENTRY_SIZE;
+    }
+
+    Ok(bugs)
+}
+
 fn read_symbols(file: &elf::File) -> Result<Vec<Symbol>> {
     for s in &file.sections {
         if s.shdr.name == ".symtab" {
@@ -418,7 +681,7 @@ fn read_symbols(file: &elf::File) -> Result<Vec<Symbol>> {
 }
 
 /// Loads an elf format file into memory.  Returns a symbol table.
-pub fn load_elf<P: AsRef<Path>>(mem: &mut Memory, path: P) -> Result<BTreeMap<String, Symbol>> {
+pub fn load_elf<P: AsRef<Path>>(mem: &mut Memory, path: P) -> Result<Module> {
     let file = elf::File::open_path(&path).map_err(|_e| anyhow!("couldn't read elf file"))?;
 
     let mut syms = read_symbols(&file)?;
@@ -499,9 +762,9 @@ pub fn load_elf<P: AsRef<Path>>(mem: &mut Memory, path: P) -> Result<BTreeMap<St
         if sym.shndx == 0 {
             globals.push(i);
         } else if let Some(section_name) = indexes.get(&sym.shndx) {
-            if let Some(base) = bases.get(section_name) {
+            if let Some(section) = bases.get(section_name) {
                 // info!("adjusting {}: {} += {}, section '{}'", sym.name, sym.value, base.0, section_name);
-                sym.value += base.0;
+                sym.value += section.base.0;
             }
         }
     }
@@ -533,14 +796,72 @@ pub fn load_elf<P: AsRef<Path>>(mem: &mut Memory, path: P) -> Result<BTreeMap<St
     // Execute all the relocation instructions to adjust the code.
     exec_relocations(mem, rela_sections, &indexes, &bases, &syms)?;
 
+    let bugs = match bases.get("__bug_table") {
+        Some(table) => read_bug_table(mem, table)?,
+        None => BTreeMap::new(),
+    };
+
     // Now we pull all the symbol info together to create a map from
     // symbol -> elf::Symbol, where the addr reflects where we've actually
     // loaded the sections.
     let mut symbols = BTreeMap::new();
-    for sym in syms {
+    let mut functions = BTreeMap::new();
+    for sym in &syms {
+        if sym.symtype == STT_FUNC && sym.shndx != 0 {
+            functions.insert(sym.value, (sym.name.clone(), sym.size));
+        } else if sym.shndx == 0 && !sym.name.is_empty() {
+            // An unstubbed global, these are 4 bytes each.
+            functions.insert(sym.value, (sym.name.clone(), 4));
+        }
         symbols.insert(sym.name.clone(), sym.clone());
     }
-    Ok(symbols)
+
+    Ok(Module {
+        path: path.as_ref().to_path_buf(),
+        symbols,
+        sections: bases,
+        functions,
+        symtab: syms,
+        bugs,
+    })
 }
 
 //--------------------------
+
+//--------------------------
+
+#[test]
+fn test_read_bug_table() {
+    let mut mem = Memory::new(Addr(0x10000), Addr(0x20000));
+
+    let file = b"dm-btree.c\0";
+    mem.mmap_bytes(Addr(0x2000), file, PERM_READ).unwrap();
+
+    // A WARN at 0x1004, then a BUG at 0x1010 without a file.
+    let mut table = Vec::new();
+    table.extend(&(0x1004i32 - 0x3000).to_le_bytes());
+    table.extend(&(0x2000i32 - 0x3000).to_le_bytes());
+    table.extend(&123u16.to_le_bytes());
+    table.extend(&BUGFLAG_WARNING.to_le_bytes());
+    table.extend(&(0x1010i32 - 0x300c).to_le_bytes());
+    table.extend(&0i32.to_le_bytes());
+    table.extend(&456u16.to_le_bytes());
+    table.extend(&0u16.to_le_bytes());
+    mem.mmap_bytes(Addr(0x3000), &table, PERM_READ | PERM_WRITE).unwrap();
+
+    let section = LoadedSection {
+        base: Addr(0x3000),
+        len: table.len() as u64,
+    };
+    let bugs = read_bug_table(&mut mem, &section).unwrap();
+    assert_eq!(bugs.len(), 2);
+
+    let warn = &bugs[&0x1004];
+    assert!(warn.is_warning());
+    assert_eq!(warn.entry, Addr(0x3000));
+    assert_eq!(warn.location(), Some("dm-btree.c:123".to_string()));
+
+    let bug = &bugs[&0x1010];
+    assert!(!bug.is_warning());
+    assert_eq!(bug.location(), None);
+}
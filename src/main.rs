@@ -1,12 +1,13 @@
 extern crate dm_unit;
 extern crate log;
 
+use dm_unit::fixture::MemLayout;
 use dm_unit::test_runner::*;
 use dm_unit::tests::block_manager;
 use dm_unit::tests::btree;
 use dm_unit::tests::space_map;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use clap::{App, Arg};
 use regex::Regex;
 use std::path::Path;
@@ -20,6 +21,19 @@ fn register_tests(runner: &mut TestRunner) -> Result<()> {
     Ok(())
 }
 
+// Sizes in bytes, with an optional K, M or G suffix.
+fn parse_size(s: &str) -> Result<u64> {
+    let (digits, shift) = match s.chars().last() {
+        Some('K') | Some('k') => (&s[..s.len() - 1], 10),
+        Some('M') | Some('m') => (&s[..s.len() - 1], 20),
+        Some('G') | Some('g') => (&s[..s.len() - 1], 30),
+        _ => (s, 0),
+    };
+    let n: u64 = digits.parse().map_err(|_| anyhow!("bad size '{}'", s))?;
+    n.checked_mul(1 << shift)
+        .ok_or_else(|| anyhow!("size '{}' is too large", s))
+}
+
 fn main() -> Result<()> {
     env_logger::init();
 
@@ -39,6 +53,56 @@ fn main() -> Result<()> {
                 .long("gdb")
                 .help("Listen on a socket for a gdb connection"),
         )
+        .arg(
+            Arg::with_name("BUDGET")
+                .long("budget")
+                .help("Maximum number of instructions each test may execute")
+                .value_name("INSTRS"),
+        )
+        .arg(
+            Arg::with_name("COVERAGE")
+                .long("coverage")
+                .help("Write lcov coverage data, and a per function summary, to this directory")
+                .value_name("DIR"),
+        )
+        .arg(
+            Arg::with_name("PROFILE")
+                .long("profile")
+                .help("Write the instructions executed by each function, and folded stacks for flamegraphs, to this directory")
+                .value_name("DIR"),
+        )
+        .arg(
+            Arg::with_name("CORE")
+                .long("core")
+                .help("Write a core file, and a gdb script to load it, to this directory for each failed test")
+                .value_name("DIR"),
+        )
+        .arg(
+            Arg::with_name("LEAKS")
+                .long("leaks")
+                .help("What to do about heap blocks a passing test leaves allocated")
+                .possible_values(&["off", "warn", "fail"])
+                .default_value("warn")
+                .value_name("ACTION"),
+        )
+        .arg(
+            Arg::with_name("HEAP_SIZE")
+                .long("heap-size")
+                .help("Size of the guest heap, a power of two, eg, 64M")
+                .value_name("SIZE"),
+        )
+        .arg(
+            Arg::with_name("HEAP_LIMIT")
+                .long("heap-limit")
+                .help("Let the guest heap grow, doubling when it's full, up to this size")
+                .value_name("SIZE"),
+        )
+        .arg(
+            Arg::with_name("STACK_SIZE")
+                .long("stack-size")
+                .help("Size of the guest stack, eg, 16K")
+                .value_name("SIZE"),
+        )
         .arg(
             Arg::with_name("FILTER")
                 .short("t")
@@ -59,6 +123,41 @@ fn main() -> Result<()> {
         runner.enable_gdb();
     }
 
+    if let Some(budget) = matches.value_of("BUDGET") {
+        runner.set_budget(budget.parse()?);
+    }
+
+    if let Some(dir) = matches.value_of("COVERAGE") {
+        runner.enable_coverage(dir);
+    }
+
+    if let Some(dir) = matches.value_of("PROFILE") {
+        runner.enable_profile(dir);
+    }
+
+    if let Some(dir) = matches.value_of("CORE") {
+        runner.enable_core_files(dir);
+    }
+
+    runner.set_leak_check(match matches.value_of("LEAKS").unwrap() {
+        "off" => LeakCheck::Off,
+        "fail" => LeakCheck::Fail,
+        _ => LeakCheck::Warn,
+    });
+
+    let mut layout = MemLayout::default();
+    if let Some(size) = matches.value_of("HEAP_SIZE") {
+        layout.heap_size = parse_size(size)?;
+        layout.heap_limit = layout.heap_size;
+    }
+    if let Some(size) = matches.value_of("HEAP_LIMIT") {
+        layout.heap_limit = parse_size(size)?;
+    }
+    if let Some(size) = matches.value_of("STACK_SIZE") {
+        layout.stack_size = parse_size(size)?;
+    }
+    runner.set_layout(layout);
+
     register_tests(&mut runner)?;
 
     let (pass, fail) = runner.exec()?;
@@ -2,9 +2,11 @@ use fixedbitset::FixedBitSet;
 use intrusive_collections::intrusive_adapter;
 use intrusive_collections::{Bound, KeyAdapter, RBTree, RBTreeLink};
 use log::debug;
+use std::cell::{Cell, RefCell};
 use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use std::fmt;
 use std::result;
+use std::sync::Arc;
 use thiserror::Error;
 
 use crate::primitive::Primitive;
@@ -52,8 +54,17 @@ pub enum MemErr {
     #[error("Unable to allocate enough space")]
     OutOfSpace,
 
+    #[error("Guest heap exhausted: couldn't allocate {len} bytes from the {size} byte heap")]
+    HeapExhausted { len: usize, size: u64 },
+
     #[error("Bad free requested {0:?}")]
     BadFree(Addr),
+
+    #[error("Read of uninitialised memory at {0:?}")]
+    UninitRead(Addr),
+
+    #[error("Access to freed memory at {0:?}")]
+    UseAfterFree(Addr),
 }
 
 pub type Result<T> = result::Result<T, MemErr>;
@@ -65,13 +76,15 @@ pub const PERM_READ: u8 = 1 << 0;
 pub const PERM_WRITE: u8 = 1 << 1;
 pub const PERM_EXEC: u8 = 1 << 2;
 
-/// Memory for a region of the address space.
+/// Memory for a region of the address space.  The data is shared
+/// copy-on-write between snapshots.
+#[derive(Clone)]
 struct MMap {
     perms: u8,
     begin: u64,
     end: u64,
-    bytes: Vec<u8>,
-    written: FixedBitSet,
+    bytes: Arc<Vec<u8>>,
+    written: Arc<FixedBitSet>,
 }
 
 impl MMap {
@@ -81,12 +94,13 @@ impl MMap {
             perms,
             begin,
             end,
-            bytes: vec![0u8; len],
-            written: FixedBitSet::with_capacity(len),
+            bytes: Arc::new(vec![0u8; len]),
+            written: Arc::new(FixedBitSet::with_capacity(len)),
         }
     }
 
     /// Checks all 'perms' are present for this region.
+    #[inline]
     fn check_perms(&self, begin: u64, perms: u8) -> Result<()> {
         if (self.perms & perms) != perms {
             return Err(MemErr::BadPerms(Addr(begin), perms));
@@ -97,11 +111,9 @@ impl MMap {
 
     /// Checks that the bytes have been written for the given range.
     fn check_written(&self, begin: u64, end: u64) -> Result<()> {
-        let begin = begin - self.begin;
-        let end = end - self.begin;
         for b in begin..end {
-            if !self.written.contains(b as usize) {
-                // return Err(MemErr::BadRead(Addr(b + self.begin)));
+            if !self.written.contains((b - self.begin) as usize) {
+                return Err(MemErr::UninitRead(Addr(b)));
             }
         }
 
@@ -112,20 +124,23 @@ impl MMap {
     fn set_written(&mut self, begin: u64, end: u64, enabled: bool) {
         let begin = begin - self.begin;
         let end = end - self.begin;
-        for b in begin..end {
-            self.written.set(b as usize, enabled);
-        }
+        let written = Arc::make_mut(&mut self.written);
+        written.set_range((begin as usize)..(end as usize), enabled);
     }
 
     /// Reads data from the region.  Fails if all the data can't be read
-    /// from this region, or if any of the perms are not present.
-    fn read(&self, begin: u64, bytes: &mut [u8], perms: u8) -> Result<()> {
+    /// from this region, or if any of the perms are not present.  If
+    /// 'check' is set, it also fails if any of the data is uninitialised.
+    #[inline]
+    fn read(&self, begin: u64, bytes: &mut [u8], perms: u8, check: bool) -> Result<()> {
         let end = begin + (bytes.len() as u64);
         assert!(begin >= self.begin);
         assert!(end <= self.end);
 
         self.check_perms(begin, perms)?;
-        self.check_written(begin, end)?;
+        if check {
+            self.check_written(begin, end)?;
+        }
 
         let slice = &self.bytes[((begin - self.begin) as usize)..((end - self.begin) as usize)];
         bytes.copy_from_slice(slice);
@@ -142,7 +157,8 @@ impl MMap {
 
         self.check_perms(begin, perms)?;
 
-        let slice = &mut self.bytes[((begin - self.begin) as usize)..((end - self.begin) as usize)];
+        let data = Arc::make_mut(&mut self.bytes);
+        let slice = &mut data[((begin - self.begin) as usize)..((end - self.begin) as usize)];
         slice.copy_from_slice(bytes);
         self.set_written(begin, end, true);
 
@@ -160,17 +176,39 @@ impl MMap {
         self.write(begin, &zeroes, perms)
     }
 
+    /// Copies out the data, and written bits, for a range.
+    fn save(&self, begin: u64, end: u64) -> Contents {
+        let b = (begin - self.begin) as usize;
+        let e = (end - self.begin) as usize;
+        Contents {
+            bytes: self.bytes[b..e].to_vec(),
+            written: (b..e).map(|i| self.written.contains(i)).collect(),
+        }
+    }
+
+    /// Puts back data saved with save(), ignoring the permissions.
+    fn load(&mut self, begin: u64, contents: &Contents) {
+        let b = (begin - self.begin) as usize;
+        let e = b + contents.bytes.len();
+        Arc::make_mut(&mut self.bytes)[b..e].copy_from_slice(&contents.bytes);
+        let written = Arc::make_mut(&mut self.written);
+        for (i, w) in contents.written.iter().enumerate() {
+            written.set(b + i, *w);
+        }
+    }
+
     /// Trashes any data in the region, and clear the written bits.
     fn forget(&mut self, begin: u64, end: u64) {
         self.set_written(begin, end, false);
 
         let begin = begin - self.begin;
         let end = end - self.begin;
+        let bytes = Arc::make_mut(&mut self.bytes);
         for b in begin..end {
             if (b & 1) != 0 {
-                self.bytes[b as usize] = 0xde;
+                bytes[b as usize] = 0xde;
             } else {
-                self.bytes[b as usize] = 0xad;
+                bytes[b as usize] = 0xad;
             }
         }
     }
@@ -202,6 +240,180 @@ impl<'a> KeyAdapter<'a> for MMapAdapter {
 
 //-------------------------------------
 
+/// The accesses a watchpoint fires on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access,
+}
+
+/// An access to a watched range.  Only the watched bytes that were
+/// touched are reported, for reads old and new are the same.
+#[derive(Clone, Debug)]
+pub struct WatchHit {
+    // The watchpoint that fired.
+    pub id: u64,
+
+    // Either Read or Write.
+    pub kind: WatchKind,
+
+    pub addr: Addr,
+    pub old: Vec<u8>,
+    pub new: Vec<u8>,
+}
+
+impl WatchHit {
+    pub fn size(&self) -> usize {
+        self.new.len()
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Watchpoint {
+    begin: u64,
+    end: u64,
+    kind: WatchKind,
+}
+
+/// The set of watchpoints.  These are configuration rather than
+/// state, so get carried across when memory is restored from a
+/// snapshot.
+#[derive(Clone, Default)]
+pub struct Watchpoints {
+    points: BTreeMap<u64, Watchpoint>,
+    next_id: u64,
+}
+
+//-------------------------------------
+
+#[derive(Clone)]
+struct Contents {
+    bytes: Vec<u8>,
+    written: Vec<bool>,
+}
+
+#[derive(Clone)]
+enum Change {
+    Write {
+        begin: u64,
+        old: Contents,
+        new: Contents,
+    },
+    Map(MMap),
+    Unmap(MMap),
+
+    // Heap blocks, by the address of their leading redzone.
+    Alloc {
+        ptr: u64,
+        block: Block,
+    },
+    Free {
+        ptr: u64,
+        block: Block,
+    },
+
+    // A block leaving quarantine, and going back to the heap.
+    Release {
+        ptr: u64,
+        block: Block,
+    },
+}
+
+/// A change made to memory, with enough information to undo or redo it.
+/// See Memory::record_changes().
+#[derive(Clone)]
+pub struct MemChange(Change);
+
+impl MemChange {
+    /// Does this change write to any of [begin, end)?
+    pub fn writes(&self, begin: Addr, end: Addr) -> bool {
+        match &self.0 {
+            Change::Write { begin: b, new, .. } => {
+                *b < end.0 && begin.0 < *b + new.bytes.len() as u64
+            }
+            _ => false,
+        }
+    }
+}
+
+//-------------------------------------
+
+// Bytes of freed blocks held back from reuse, see set_quarantine().
+const DEFAULT_QUARANTINE: usize = 1024 * 1024;
+
+// Unmapped bytes either side of each heap block, see set_redzone().
+const DEFAULT_REDZONE: usize = 4;
+
+// A heap block.  The part handed out by alloc() sits between two
+// unmapped redzones, which catch overruns.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Block {
+    len: usize,
+    redzone: usize,
+}
+
+impl Block {
+    // The bytes taken from the heap, including the redzones.
+    fn heap_len(&self) -> usize {
+        self.len + 2 * self.redzone
+    }
+
+    // The address handed out, given where the block starts in the heap.
+    fn ptr(&self, heap_ptr: u64) -> u64 {
+        heap_ptr + self.redzone as u64
+    }
+
+    fn contains(&self, heap_ptr: u64, addr: u64) -> bool {
+        let ptr = self.ptr(heap_ptr);
+        addr >= ptr && addr < ptr + self.len as u64
+    }
+}
+
+/// Where an address lies relative to the nearest heap block, see
+/// Memory::nearest_block().
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NearBlock {
+    /// The block, as returned by alloc().
+    pub ptr: Addr,
+    pub len: usize,
+
+    /// Whether the block has been freed, and is in quarantine.
+    pub freed: bool,
+
+    /// The address less ptr, negative if it's before the block.
+    pub offset: i64,
+}
+
+impl NearBlock {
+    pub fn inside(&self) -> bool {
+        self.offset >= 0 && self.offset < self.len as i64
+    }
+
+    /// Where the address is, eg, "4 bytes past the end of".
+    pub fn position(&self) -> String {
+        if self.offset < 0 {
+            format!("{} bytes before the start of", -self.offset)
+        } else if self.inside() {
+            format!("{} bytes into", self.offset)
+        } else {
+            format!("{} bytes past the end of", self.offset - self.len as i64)
+        }
+    }
+
+    // Bytes between the address and the block.  Just past the end is
+    // further than inside, so adjoining blocks aren't ambiguous.
+    fn distance(&self) -> u64 {
+        if self.offset < 0 {
+            (-self.offset) as u64
+        } else if self.inside() {
+            0
+        } else {
+            (self.offset - self.len as i64) as u64 + 1
+        }
+    }
+}
+
 /// Manages memory for the vm.  Tracks permissions at the byte level.
 /// Checks memory has been initialised before it's read.
 pub struct Memory {
@@ -214,11 +426,87 @@ pub struct Memory {
     // We always want a heap, so I'm embedding it in the mmu.
     heap: Heap,
 
-    // Maps allocation block to len.  FIXME: this is ugly.
-    allocations: BTreeMap<u64, usize>,
+    // The live heap blocks, by the address of their leading redzone.
+    allocations: BTreeMap<u64, Block>,
+
+    // Redzone given to new blocks, see set_redzone().
+    redzone: usize,
+
+    // The guest backtraces blocks were allocated from, if the allocator
+    // said, indexed by the pointer returned from alloc().  Entries
+    // outlive the block, so undoing a free doesn't lose them, but are
+    // cleared on reuse.
+    alloc_traces: BTreeMap<u64, Arc<Vec<Addr>>>,
+    free_traces: BTreeMap<u64, Arc<Vec<Addr>>>,
+
+    // Freed blocks aren't returned to the heap straight away, so a use
+    // after free hits unmapped memory rather than the next owner's
+    // data.  Indexed like allocations, with the order they were freed
+    // in, oldest first.
+    freed: BTreeMap<u64, Block>,
+    quarantine: VecDeque<u64>,
+    quarantine_bytes: usize,
+    quarantine_limit: usize,
+
+    // Whether guest reads of uninitialised memory fail, see
+    // check_uninit().
+    check_uninit: bool,
+
+    watchpoints: Watchpoints,
+
+    // Accesses to watched ranges, waiting to be collected.  Reads only
+    // have a shared reference, hence the RefCell.
+    watch_hits: RefCell<Vec<WatchHit>>,
+    recording_hits: bool,
+
+    // Changes made since the last take_changes(), if recording.
+    changes: Option<Vec<MemChange>>,
+
+    // Ranges of executable memory unmapped or written since the last
+    // take_code_changes().
+    code_changes: Vec<(u64, u64)>,
+
+    // The range and index of the mapping last accessed.  Indexes are
+    // never reused, so this is only stale if the mapping has gone.
+    last_mm: Cell<Option<(u64, u64, usize)>>,
+
+    // The range reserved by the last load-reserved, see reserve().
+    reservation: Option<(u64, u64)>,
 }
 
-// FIXME: implement snapshotting.
+/// Cloning is cheap, since the mapped data is shared copy-on-write.
+/// This is how the vm is snapshotted.
+impl Clone for Memory {
+    fn clone(&self) -> Self {
+        let mut index = RBTree::new(MMapAdapter::new());
+        for (i, mm) in &self.mmaps {
+            index.insert(Box::new(MMapIndex::new(mm.begin, *i)));
+        }
+
+        Memory {
+            index,
+            total_allocations: self.total_allocations,
+            mmaps: self.mmaps.clone(),
+            heap: self.heap.clone(),
+            allocations: self.allocations.clone(),
+            redzone: self.redzone,
+            alloc_traces: self.alloc_traces.clone(),
+            free_traces: self.free_traces.clone(),
+            freed: self.freed.clone(),
+            quarantine: self.quarantine.clone(),
+            quarantine_bytes: self.quarantine_bytes,
+            quarantine_limit: self.quarantine_limit,
+            check_uninit: self.check_uninit,
+            watchpoints: self.watchpoints.clone(),
+            watch_hits: RefCell::new(Vec::new()),
+            recording_hits: self.recording_hits,
+            changes: None,
+            code_changes: Vec::new(),
+            last_mm: Cell::new(None),
+            reservation: self.reservation,
+        }
+    }
+}
 
 impl Memory {
     pub fn new(heap_begin: Addr, heap_end: Addr) -> Self {
@@ -228,9 +516,38 @@ impl Memory {
             mmaps: BTreeMap::new(),
             heap: Heap::new(heap_begin, heap_end),
             allocations: BTreeMap::new(),
+            redzone: DEFAULT_REDZONE,
+            alloc_traces: BTreeMap::new(),
+            free_traces: BTreeMap::new(),
+            freed: BTreeMap::new(),
+            quarantine: VecDeque::new(),
+            quarantine_bytes: 0,
+            quarantine_limit: DEFAULT_QUARANTINE,
+            check_uninit: false,
+            watchpoints: Watchpoints::default(),
+            watch_hits: RefCell::new(Vec::new()),
+            recording_hits: true,
+            changes: None,
+            code_changes: Vec::new(),
+            last_mm: Cell::new(None),
+            reservation: None,
         }
     }
 
+    fn log(&mut self, change: Change) {
+        if let Some(changes) = &mut self.changes {
+            changes.push(MemChange(change));
+        }
+    }
+
+    /// Inserts a MMap, logging the change.
+    fn add_mm(&mut self, mm: MMap) {
+        if self.changes.is_some() {
+            self.log(Change::Map(mm.clone()));
+        }
+        self.insert_mm(mm);
+    }
+
     /// Inserts a MMap into both the mmaps vec, and the index rbtree.
     fn insert_mm(&mut self, mm: MMap) {
         let index = self.total_allocations;
@@ -268,15 +585,68 @@ impl Memory {
 
     /// Remove an mmapped area.
     pub fn unmap(&mut self, begin: Addr) -> Result<()> {
-        let mut cur = self.index.find_mut(&begin.0);
+        let mm = self.remove_mm(begin.0).ok_or(MemErr::BadFree(begin))?;
+        self.log(Change::Unmap(mm));
+        Ok(())
+    }
+
+    fn remove_mm(&mut self, begin: u64) -> Option<MMap> {
+        let mut cur = self.index.find_mut(&begin);
 
         if cur.is_null() {
-            Err(MemErr::BadFree(begin))
+            None
         } else {
             let index = cur.get().unwrap().index;
             cur.remove();
-            self.mmaps.remove(&index);
-            Ok(())
+            let mm = self.mmaps.remove(&index)?;
+            self.note_code_change(mm.perms, mm.begin, mm.end);
+            self.clobber_reservation(mm.begin, mm.end);
+            Some(mm)
+        }
+    }
+
+    fn note_code_change(&mut self, perms: u8, begin: u64, end: u64) {
+        if (perms & PERM_EXEC) != 0 {
+            self.code_changes.push((begin, end));
+        }
+    }
+
+    /// Whether executable memory has been unmapped or written to since
+    /// the last take_code_changes().
+    pub fn code_changed(&self) -> bool {
+        !self.code_changes.is_empty()
+    }
+
+    /// The ranges of executable memory unmapped or written to since the
+    /// last call, so the vm knows which instructions it's decoded are
+    /// stale.  Mapping memory isn't a change, nothing can have been
+    /// decoded from it.
+    pub fn take_code_changes(&mut self) -> Vec<(u64, u64)> {
+        std::mem::take(&mut self.code_changes)
+    }
+
+    /// Reserves a range for a later store-conditional, replacing any
+    /// previous reservation.  The vm has a single hart, so writes from
+    /// the host, eg, by a stub, stand in for those from other harts.
+    /// Any write to the range loses the reservation.
+    pub fn reserve(&mut self, begin: Addr, end: Addr) {
+        self.reservation = Some((begin.0, end.0));
+    }
+
+    /// Clears the reservation, returning true if it was intact and
+    /// covered [begin, end).
+    pub fn take_reservation(&mut self, begin: Addr, end: Addr) -> bool {
+        match self.reservation.take() {
+            Some((b, e)) => b <= begin.0 && end.0 <= e,
+            None => false,
+        }
+    }
+
+    fn clobber_reservation(&mut self, begin: u64, end: u64) {
+        if let Some((b, e)) = self.reservation {
+            if begin < e && b < end {
+                self.reservation = None;
+            }
         }
     }
 
@@ -294,7 +664,7 @@ impl Memory {
 
         let mm = MMap::new(begin.0, end.0, perms);
 
-        self.insert_mm(mm);
+        self.add_mm(mm);
         Ok(())
     }
 
@@ -303,7 +673,7 @@ impl Memory {
         assert!(begin.0 <= end.0);
         let mut mm = MMap::new(begin.0, end.0, perms);
         mm.zero(begin.0, end.0, 0)?;
-        self.insert_mm(mm);
+        self.add_mm(mm);
         Ok(())
     }
 
@@ -314,7 +684,7 @@ impl Memory {
     pub fn mmap_bytes(&mut self, begin: Addr, bytes: &[u8], perms: u8) -> Result<()> {
         let mut mm = MMap::new(begin.0, begin.0 + (bytes.len() as u64), perms);
         mm.write(begin.0, bytes, 0)?;
-        self.insert_mm(mm);
+        self.add_mm(mm);
         Ok(())
     }
 
@@ -357,8 +727,49 @@ impl Memory {
         self.get_indexes_(begin, end, perms, false)
     }
 
+    // The index of the mapping holding all of [begin, end), if there is
+    // one.  Most accesses are within a single mapping, and this saves
+    // building a list of them.
+    #[inline]
+    fn single_mm(&self, begin: u64, end: u64) -> Option<(usize, &MMap)> {
+        if let Some((b, e, index)) = self.last_mm.get() {
+            if begin >= b && end <= e {
+                if let Some(mm) = self.mmaps.get(&index) {
+                    return Some((index, mm));
+                }
+            }
+        }
+
+        let mi = self.index.upper_bound(Bound::Included(&begin)).get()?;
+        let mm = self.mmaps.get(&mi.index)?;
+        if begin >= mm.begin && end <= mm.end {
+            self.last_mm.set(Some((mm.begin, mm.end, mi.index)));
+            Some((mi.index, mm))
+        } else {
+            None
+        }
+    }
+
+    // Blames a failed access on a use after free, if it hit a block in
+    // quarantine.
+    fn check_freed(&self, e: MemErr) -> MemErr {
+        match e {
+            MemErr::UnmappedRegion(addr, _) | MemErr::BadPerms(addr, _)
+                if self.freed_block(addr).is_some() =>
+            {
+                MemErr::UseAfterFree(addr)
+            }
+            e => e,
+        }
+    }
+
     // Checks that a memory region is mapped with the particular permissions.
-    pub fn check_perms(&self, mut begin: Addr, end: Addr, perms: u8) -> Result<()> {
+    pub fn check_perms(&self, begin: Addr, end: Addr, perms: u8) -> Result<()> {
+        self.check_perms_(begin, end, perms)
+            .map_err(|e| self.check_freed(e))
+    }
+
+    fn check_perms_(&self, mut begin: Addr, end: Addr, perms: u8) -> Result<()> {
         let mut indexes = self.get_indexes(begin.0, end.0, perms)?;
 
         while begin < end {
@@ -384,9 +795,38 @@ impl Memory {
 
     /// Reads bytes from a memory range.  Fails if the bits in 'perms' are
     /// not set for any byte in the range.
-    pub fn read(&self, begin: Addr, mut bytes: &mut [u8], perms: u8) -> Result<()> {
+    #[inline]
+    pub fn read(&self, begin: Addr, bytes: &mut [u8], perms: u8) -> Result<()> {
+        self.read_(begin, bytes, perms)
+            .map_err(|e| self.check_freed(e))?;
+
+        if self.watching(perms, PERM_READ) {
+            let b = begin.0;
+            let mut hits = self.watch_hits.borrow_mut();
+            for (id, wb, we) in self.watched(WatchKind::Read, b, b + bytes.len() as u64) {
+                let v = bytes[((wb - b) as usize)..((we - b) as usize)].to_vec();
+                hits.push(WatchHit {
+                    id,
+                    kind: WatchKind::Read,
+                    addr: Addr(wb),
+                    old: v.clone(),
+                    new: v,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    #[inline]
+    fn read_(&self, begin: Addr, mut bytes: &mut [u8], perms: u8) -> Result<()> {
         let mut begin = begin.0;
         let end = begin + (bytes.len() as u64);
+        let check = self.check_uninit && (perms & PERM_READ) != 0;
+        if let Some((_, mm)) = self.single_mm(begin, end) {
+            return mm.read(begin, bytes, perms, check);
+        }
+
         let mut indexes = self.get_indexes(begin, end, perms)?;
 
         while begin < end {
@@ -403,7 +843,7 @@ impl Memory {
             }
 
             let len = std::cmp::min(end, mm.end) - begin;
-            mm.read(begin, &mut bytes[0..(len as usize)], perms)?;
+            mm.read(begin, &mut bytes[0..(len as usize)], perms, check)?;
 
             bytes = &mut bytes[(len as usize)..];
             begin += len;
@@ -422,9 +862,41 @@ impl Memory {
 
     /// Writes bytes to a memory range.  Fails in the bits in 'perms' are
     /// not set for any byte in the range.
-    pub fn write(&mut self, begin: Addr, mut bytes: &[u8], perms: u8) -> Result<()> {
+    pub fn write(&mut self, begin: Addr, bytes: &[u8], perms: u8) -> Result<()> {
+        if !self.watching(perms, PERM_WRITE) {
+            return self
+                .write_(begin, bytes, perms)
+                .map_err(|e| self.check_freed(e));
+        }
+
+        let b = begin.0;
+        let mut hits = Vec::new();
+        for (id, wb, we) in self.watched(WatchKind::Write, b, b + bytes.len() as u64) {
+            // If this fails, so will the write.
+            let mut old = vec![0; (we - wb) as usize];
+            let _ = self.read_(Addr(wb), &mut old, 0);
+            hits.push(WatchHit {
+                id,
+                kind: WatchKind::Write,
+                addr: Addr(wb),
+                old,
+                new: bytes[((wb - b) as usize)..((we - b) as usize)].to_vec(),
+            });
+        }
+
+        self.write_(begin, bytes, perms)
+            .map_err(|e| self.check_freed(e))?;
+        self.watch_hits.get_mut().extend(hits);
+        Ok(())
+    }
+
+    fn write_(&mut self, begin: Addr, mut bytes: &[u8], perms: u8) -> Result<()> {
         let mut begin = begin.0;
         let end = begin + (bytes.len() as u64);
+        self.clobber_reservation(begin, end);
+        if let Some((index, _)) = self.single_mm(begin, end) {
+            return self.write_mm(index, begin, bytes, perms);
+        }
 
         let mut indexes = self.get_indexes(begin, end, perms)?;
 
@@ -434,15 +906,15 @@ impl Memory {
             }
 
             let index = indexes.pop_front().unwrap();
-            let mm = &mut self.mmaps.get_mut(&index).unwrap();
+            let mm_end = self.mmaps.get(&index).unwrap().end;
 
             // begin must be within mm, otherwise we have a gap.
-            if begin >= mm.end {
+            if begin >= mm_end {
                 return Err(MemErr::BadPerms(Addr(begin), perms));
             }
 
-            let len = std::cmp::min(end, mm.end) - begin;
-            mm.write(begin, &bytes[0..(len as usize)], perms)?;
+            let len = std::cmp::min(end, mm_end) - begin;
+            self.write_mm(index, begin, &bytes[0..(len as usize)], perms)?;
 
             bytes = &bytes[(len as usize)..];
             begin += len;
@@ -451,12 +923,210 @@ impl Memory {
         Ok(())
     }
 
+    // Writes to a single mapping, logging the change if recording.
+    fn write_mm(&mut self, index: usize, begin: u64, bytes: &[u8], perms: u8) -> Result<()> {
+        let mm = self.mmaps.get_mut(&index).unwrap();
+        let end = begin + bytes.len() as u64;
+        if let Some(changes) = &mut self.changes {
+            let old = mm.save(begin, end);
+            mm.write(begin, bytes, perms)?;
+            let new = mm.save(begin, end);
+            changes.push(MemChange(Change::Write { begin, old, new }));
+        } else {
+            mm.write(begin, bytes, perms)?;
+        }
+
+        let perms = mm.perms;
+        self.note_code_change(perms, begin, end);
+        Ok(())
+    }
+
+    /// Turns checking for uninitialised reads on or off, returning the
+    /// old setting.  When on, reads made with PERM_READ fail with
+    /// UninitRead if any byte has never been written.  The fixture turns
+    /// this on while the guest is running, so the test itself can still
+    /// look at anything.
+    pub fn check_uninit(&mut self, enable: bool) -> bool {
+        std::mem::replace(&mut self.check_uninit, enable)
+    }
+
+    /// Watches [begin, end) for accesses that are checked for read or
+    /// write permission, ie. those made by the guest or stubs, but not
+    /// the debugger.  Returns an id for rm_watchpoint().
+    pub fn add_watchpoint(&mut self, begin: Addr, end: Addr, kind: WatchKind) -> u64 {
+        let id = self.watchpoints.next_id;
+        self.watchpoints.next_id += 1;
+        self.watchpoints.points.insert(
+            id,
+            Watchpoint {
+                begin: begin.0,
+                end: end.0,
+                kind,
+            },
+        );
+        id
+    }
+
+    pub fn rm_watchpoint(&mut self, id: u64) -> bool {
+        self.watchpoints.points.remove(&id).is_some()
+    }
+
+    pub fn has_watchpoint(&self, id: u64) -> bool {
+        self.watchpoints.points.contains_key(&id)
+    }
+
+    /// Used to carry the watchpoints over when restoring a snapshot.
+    pub fn take_watchpoints(&mut self) -> Watchpoints {
+        std::mem::take(&mut self.watchpoints)
+    }
+
+    pub fn set_watchpoints(&mut self, watchpoints: Watchpoints) {
+        self.watchpoints = watchpoints;
+    }
+
+    /// Turns recording of watchpoint hits on or off, returning the old
+    /// setting.  The fixture only records while the guest is running, so
+    /// accesses made by the test itself don't count.
+    pub fn record_watch_hits(&mut self, enable: bool) -> bool {
+        std::mem::replace(&mut self.recording_hits, enable)
+    }
+
+    pub fn has_watch_hits(&self) -> bool {
+        !self.watch_hits.borrow().is_empty()
+    }
+
+    /// Returns the hits recorded since the last call, oldest first.
+    pub fn take_watch_hits(&self) -> Vec<WatchHit> {
+        std::mem::take(&mut *self.watch_hits.borrow_mut())
+    }
+
+    /// Turns logging of changes on or off.  Used to record execution so
+    /// it can be stepped backwards.
+    pub fn record_changes(&mut self, enable: bool) {
+        if enable != self.changes.is_some() {
+            self.changes = if enable { Some(Vec::new()) } else { None };
+        }
+    }
+
+    /// Returns the changes logged since the last call, oldest first.
+    pub fn take_changes(&mut self) -> Vec<MemChange> {
+        match &mut self.changes {
+            Some(changes) => std::mem::take(changes),
+            None => Vec::new(),
+        }
+    }
+
+    fn load(&mut self, begin: u64, contents: &Contents) {
+        let index = self
+            .index
+            .upper_bound(Bound::Included(&begin))
+            .get()
+            .expect("change to unmapped memory")
+            .index;
+        let mm = self.mmaps.get_mut(&index).unwrap();
+        mm.load(begin, contents);
+        let perms = mm.perms;
+        self.note_code_change(perms, begin, begin + contents.bytes.len() as u64);
+    }
+
+    fn free_block(&mut self, ptr: u64) {
+        self.allocations.remove(&ptr);
+        self.heap
+            .free(Addr(ptr))
+            .expect("change to unallocated block");
+    }
+
+    fn alloc_block(&mut self, ptr: u64, block: Block) {
+        self.heap
+            .alloc_at(Addr(ptr), block.heap_len())
+            .expect("change to allocated block");
+        self.allocations.insert(ptr, block);
+    }
+
+    fn unfree_block(&mut self, ptr: u64, block: Block) {
+        self.unquarantine(ptr);
+        self.allocations.insert(ptr, block);
+    }
+
+    fn refree_block(&mut self, ptr: u64, block: Block) {
+        self.allocations.remove(&ptr);
+        self.quarantine_block(ptr, block);
+    }
+
+    fn unrelease_block(&mut self, ptr: u64, block: Block) {
+        self.heap
+            .alloc_at(Addr(ptr), block.heap_len())
+            .expect("change to allocated block");
+        self.freed.insert(ptr, block);
+        self.quarantine.push_front(ptr);
+        self.quarantine_bytes += block.heap_len();
+    }
+
+    fn rerelease_block(&mut self, ptr: u64) {
+        self.unquarantine(ptr);
+        self.heap
+            .free(Addr(ptr))
+            .expect("change to unallocated block");
+    }
+
+    /// Reverses a change.  Changes must be undone newest first, from the
+    /// state they left memory in.
+    pub fn undo(&mut self, change: &MemChange) {
+        match &change.0 {
+            Change::Write { begin, old, .. } => self.load(*begin, old),
+            Change::Map(mm) => {
+                self.remove_mm(mm.begin);
+            }
+            Change::Unmap(mm) => self.insert_mm(mm.clone()),
+            Change::Alloc { ptr, .. } => self.free_block(*ptr),
+            Change::Free { ptr, block } => self.unfree_block(*ptr, *block),
+            Change::Release { ptr, block } => self.unrelease_block(*ptr, *block),
+        }
+    }
+
+    /// Makes an undone change again.
+    pub fn redo(&mut self, change: &MemChange) {
+        match &change.0 {
+            Change::Write { begin, new, .. } => self.load(*begin, new),
+            Change::Map(mm) => self.insert_mm(mm.clone()),
+            Change::Unmap(mm) => {
+                self.remove_mm(mm.begin);
+            }
+            Change::Alloc { ptr, block } => self.alloc_block(*ptr, *block),
+            Change::Free { ptr, block } => self.refree_block(*ptr, *block),
+            Change::Release { ptr, .. } => self.rerelease_block(*ptr),
+        }
+    }
+
+    fn watching(&self, perms: u8, perm: u8) -> bool {
+        (perms & perm) != 0 && self.recording_hits && !self.watchpoints.points.is_empty()
+    }
+
+    // Watchpoints that fire on 'kind' and overlap [begin, end).  Returns
+    // the id, and the overlapping range, of each.
+    fn watched(&self, kind: WatchKind, begin: u64, end: u64) -> Vec<(u64, u64, u64)> {
+        self.watchpoints
+            .points
+            .iter()
+            .filter(|(_, wp)| wp.kind == kind || wp.kind == WatchKind::Access)
+            .filter(|(_, wp)| wp.begin < end && begin < wp.end)
+            .map(|(id, wp)| (*id, u64::max(begin, wp.begin), u64::min(end, wp.end)))
+            .collect()
+    }
+
     /// Clears the 'written' bits for a region.  Used by the heap code when
     /// a block of memory is deallocated.
     pub fn forget(&mut self, begin: Addr, end: Addr) -> Result<()> {
-        let mut begin = begin.0;
-        let end = end.0;
+        self.update(begin.0, end.0, |mm, b, e| mm.forget(b, e))
+    }
 
+    // Changes each mapping in [begin, end) with 'f', which is given the
+    // part of the range in that mapping, logging the changes if
+    // recording.
+    fn update<F>(&mut self, mut begin: u64, end: u64, f: F) -> Result<()>
+    where
+        F: Fn(&mut MMap, u64, u64),
+    {
         let mut indexes = self.get_indexes(begin, end, 0)?;
 
         let mut mmaps = BTreeMap::new();
@@ -476,7 +1146,16 @@ impl Memory {
             }
 
             let len = std::cmp::min(end, mm.end) - begin;
-            mm.forget(begin, end);
+            if let Some(changes) = &mut self.changes {
+                let old = mm.save(begin, begin + len);
+                f(mm, begin, begin + len);
+                let new = mm.save(begin, begin + len);
+                changes.push(MemChange(Change::Write { begin, old, new }));
+            } else {
+                f(mm, begin, begin + len);
+            }
+            let perms = mm.perms;
+            self.note_code_change(perms, begin, begin + len);
             begin += len;
         }
 
@@ -484,6 +1163,47 @@ impl Memory {
         Ok(())
     }
 
+    /// The runs of bytes in [begin, end) that have never been written.
+    pub fn unwritten(&self, begin: Addr, end: Addr) -> Result<Vec<(Addr, Addr)>> {
+        let mut runs: Vec<(Addr, Addr)> = Vec::new();
+        for index in self.get_indexes(begin.0, end.0, 0)? {
+            let mm = self.mmaps.get(&index).unwrap();
+            let b = std::cmp::max(begin.0, mm.begin);
+            let e = std::cmp::min(end.0, mm.end);
+            for addr in b..e {
+                if mm.written.contains((addr - mm.begin) as usize) {
+                    continue;
+                }
+                match runs.last_mut() {
+                    Some((_, run_end)) if run_end.0 == addr => *run_end = Addr(addr + 1),
+                    _ => runs.push((Addr(addr), Addr(addr + 1))),
+                }
+            }
+        }
+        Ok(runs)
+    }
+
+    /// Copies 'len' bytes from 'src' to 'dest', as the guest's memcpy
+    /// would.  Bytes that were never written stay that way, so copying,
+    /// eg, the padding in a struct, isn't a read of uninitialised memory,
+    /// but a later read of the copy is.
+    pub fn copy(&mut self, dest: Addr, src: Addr, len: u64) -> Result<()> {
+        let mut bytes = vec![0u8; len as usize];
+        let old = self.check_uninit(false);
+        let r = self.read(src, &mut bytes, PERM_READ);
+        self.check_uninit(old);
+        r?;
+
+        let unwritten = self.unwritten(src, Addr(src.0 + len))?;
+        self.write(dest, &bytes, PERM_WRITE)?;
+        for (b, e) in unwritten {
+            let b = dest.0 + (b.0 - src.0);
+            let e = dest.0 + (e.0 - src.0);
+            self.update(b, e, |mm, b, e| mm.set_written(b, e, false))?;
+        }
+        Ok(())
+    }
+
     /// Accesses a primitive, loc must be 4 byte aligned.  `perm` checked.
     pub fn read_into<T: Primitive>(&mut self, loc: Addr, perm: u8) -> Result<T> {
         let mut dest = [0u8; 16];
@@ -494,19 +1214,182 @@ impl Memory {
 
     // Allocates a block on the heap with specific permissions.
     pub fn alloc_perms(&mut self, len: usize, perms: u8) -> Result<Addr> {
-        // We allocate an extra word before and after the block to
-        // detect overwrites.
-        let extra_len = len + 8;
-        let ptr = self.heap.alloc(extra_len)?;
+        // We allocate a redzone before and after the block to detect
+        // overwrites.
+        let block = Block {
+            len,
+            redzone: self.redzone,
+        };
+        let ptr = loop {
+            match self.heap.alloc(block.heap_len()) {
+                // Reuse quarantined blocks rather than fail.
+                Err(MemErr::OutOfSpace) if !self.quarantine.is_empty() => {
+                    self.release_oldest()?;
+                }
+                Err(MemErr::OutOfSpace) => {
+                    return Err(MemErr::HeapExhausted {
+                        len,
+                        size: self.heap.size(),
+                    });
+                }
+                r => break r?,
+            }
+        };
         assert!(!self.allocations.contains_key(&ptr.0));
-        self.allocations.insert(ptr.0, extra_len);
+        self.allocations.insert(ptr.0, block);
+        self.log(Change::Alloc { ptr: ptr.0, block });
 
         // mmap just the central part that may be used.
-        let ptr = Addr(ptr.0 + 4);
+        let ptr = Addr(block.ptr(ptr.0));
+        self.alloc_traces.remove(&ptr.0);
+        self.free_traces.remove(&ptr.0);
         self.mmap(ptr, Addr(ptr.0 + len as u64), perms)?;
         Ok(ptr)
     }
 
+    /// Records the guest backtrace, innermost first, that a block
+    /// returned by alloc() was allocated from.  Blocks with a backtrace
+    /// belong to the guest, and are reported if they leak.
+    pub fn set_alloc_trace(&mut self, ptr: Addr, trace: Vec<Addr>) {
+        self.alloc_traces.insert(ptr.0, Arc::new(trace));
+    }
+
+    /// The backtrace the heap allocation containing 'addr' was made
+    /// from, if known.
+    pub fn alloc_trace(&self, addr: Addr) -> Option<&[Addr]> {
+        let (ptr, _) = self.allocation(addr).or_else(|| self.freed_block(addr))?;
+        self.alloc_traces.get(&ptr.0).map(|t| &t[..])
+    }
+
+    /// Records the guest backtrace a block was freed from.
+    pub fn set_free_trace(&mut self, ptr: Addr, trace: Vec<Addr>) {
+        self.free_traces.insert(ptr.0, Arc::new(trace));
+    }
+
+    /// The backtrace the quarantined block containing 'addr' was freed
+    /// from, if known.
+    pub fn free_trace(&self, addr: Addr) -> Option<&[Addr]> {
+        let (ptr, _) = self.freed_block(addr)?;
+        self.free_traces.get(&ptr.0).map(|t| &t[..])
+    }
+
+    /// The freed block in quarantine containing 'addr', as returned by
+    /// alloc(), and its length.
+    pub fn freed_block(&self, addr: Addr) -> Option<(Addr, usize)> {
+        Self::find_block(&self.freed, addr)
+    }
+
+    // The block in 'blocks' containing 'addr', as returned by alloc().
+    fn find_block(blocks: &BTreeMap<u64, Block>, addr: Addr) -> Option<(Addr, usize)> {
+        let (heap_ptr, block) = blocks.range(..=addr.0).next_back()?;
+        if block.contains(*heap_ptr, addr.0) {
+            Some((Addr(block.ptr(*heap_ptr)), block.len))
+        } else {
+            None
+        }
+    }
+
+    /// Lets the heap double in size whenever it's full, until it
+    /// reaches 'bytes'.  The limit is effectively rounded down to the
+    /// current size times a power of two.
+    pub fn set_heap_limit(&mut self, bytes: u64) {
+        self.heap.limit = self.heap.base + bytes;
+    }
+
+    /// The bytes the heap currently spans, including free space.
+    pub fn heap_size(&self) -> u64 {
+        self.heap.size()
+    }
+
+    /// Sets the unmapped bytes left either side of blocks allocated from
+    /// now on.  Accesses that stray into them fail, and are reported
+    /// relative to the block, see nearest_block().  Keep it a multiple
+    /// of 8 if blocks need to be aligned.
+    pub fn set_redzone(&mut self, bytes: usize) {
+        self.redzone = bytes;
+    }
+
+    /// The heap block, live or in quarantine, nearest to 'addr'.  None
+    /// if 'addr' isn't in the heap, or the heap is empty.
+    pub fn nearest_block(&self, addr: Addr) -> Option<NearBlock> {
+        if !self.heap.contains(addr) {
+            return None;
+        }
+
+        let mut nearest: Option<NearBlock> = None;
+        for (blocks, freed) in [(&self.allocations, false), (&self.freed, true)].iter() {
+            let below = blocks.range(..=addr.0).next_back();
+            let above = blocks.range((addr.0 + 1)..).next();
+            for (heap_ptr, block) in below.into_iter().chain(above) {
+                let ptr = block.ptr(*heap_ptr);
+                let near = NearBlock {
+                    ptr: Addr(ptr),
+                    len: block.len,
+                    freed: *freed,
+                    offset: addr.0.wrapping_sub(ptr) as i64,
+                };
+                let closer = match nearest {
+                    Some(n) => near.distance() < n.distance(),
+                    None => true,
+                };
+                if closer {
+                    nearest = Some(near);
+                }
+            }
+        }
+        nearest
+    }
+
+    /// Sets how many bytes of freed blocks are held back from reuse, so
+    /// accesses to them can be caught.  Zero returns blocks to the heap
+    /// as soon as they're freed.
+    pub fn set_quarantine(&mut self, bytes: usize) -> Result<()> {
+        self.quarantine_limit = bytes;
+        self.trim_quarantine()
+    }
+
+    fn trim_quarantine(&mut self) -> Result<()> {
+        while self.quarantine_bytes > self.quarantine_limit {
+            self.release_oldest()?;
+        }
+        Ok(())
+    }
+
+    // Returns the block that's been in quarantine longest to the heap.
+    fn release_oldest(&mut self) -> Result<()> {
+        let ptr = self.quarantine.pop_front().unwrap();
+        let block = self.freed.remove(&ptr).unwrap();
+        self.quarantine_bytes -= block.heap_len();
+        self.heap.free(Addr(ptr))?;
+        self.log(Change::Release { ptr, block });
+        Ok(())
+    }
+
+    fn quarantine_block(&mut self, ptr: u64, block: Block) {
+        self.freed.insert(ptr, block);
+        self.quarantine.push_back(ptr);
+        self.quarantine_bytes += block.heap_len();
+    }
+
+    fn unquarantine(&mut self, ptr: u64) {
+        let block = self.freed.remove(&ptr).expect("block not in quarantine");
+        self.quarantine.retain(|p| *p != ptr);
+        self.quarantine_bytes -= block.heap_len();
+    }
+
+    /// Where the heap allocation containing 'addr' was made, if known.
+    pub fn alloc_site(&self, addr: Addr) -> Option<Addr> {
+        self.alloc_trace(addr)?.first().copied()
+    }
+
+    /// The live heap allocations, as returned by alloc(), with their
+    /// lengths.
+    pub fn allocations(&self) -> impl Iterator<Item = (Addr, usize)> + '_ {
+        self.allocations
+            .iter()
+            .map(|(heap_ptr, block)| (Addr(block.ptr(*heap_ptr)), block.len))
+    }
+
     // Allocates a block on the heap with read/write permissions.  The
     // common case.
     pub fn alloc(&mut self, len: usize) -> Result<Addr> {
@@ -514,18 +1397,60 @@ impl Memory {
     }
 
     pub fn free(&mut self, ptr: Addr) -> Result<()> {
-        let heap_ptr = Addr(ptr.0 - 4);
-
-        if let Some(extra_len) = self.allocations.remove(&heap_ptr.0) {
-            self.heap.free(heap_ptr)?;
+        // Blocks may have different redzones, so look for the block
+        // that starts nearest below ptr.
+        let found = self
+            .allocations
+            .range(..=ptr.0)
+            .next_back()
+            .map(|(heap_ptr, block)| (*heap_ptr, *block))
+            .filter(|(heap_ptr, block)| block.ptr(*heap_ptr) == ptr.0);
+
+        if let Some((heap_ptr, block)) = found {
+            self.allocations.remove(&heap_ptr);
+            self.quarantine_block(heap_ptr, block);
+            self.log(Change::Free {
+                ptr: heap_ptr,
+                block,
+            });
             self.unmap(ptr)?;
-            assert!(self.no_mappings(ptr.0, ptr.0 + extra_len as u64 - 8_u64));
-            Ok(())
+            assert!(self.no_mappings(ptr.0, ptr.0 + block.len as u64));
+            self.trim_quarantine()
         } else {
             Err(MemErr::BadFree(ptr))
         }
     }
 
+    /// The heap allocation containing 'addr', as returned by alloc(),
+    /// and its length.
+    pub fn allocation(&self, addr: Addr) -> Option<(Addr, usize)> {
+        Self::find_block(&self.allocations, addr)
+    }
+
+    /// The mapped regions in address order, with their permissions and
+    /// data.
+    pub fn mappings(&self) -> impl Iterator<Item = (Addr, u8, &[u8])> {
+        self.index.iter().map(move |mi| {
+            let mm = &self.mmaps[&mi.index];
+            (Addr(mm.begin), mm.perms, &mm.bytes[..])
+        })
+    }
+
+    /// The mapped region containing 'addr'.
+    pub fn mapping(&self, addr: Addr) -> Option<(Addr, Addr)> {
+        let index = self
+            .index
+            .upper_bound(Bound::Included(&addr.0))
+            .get()?
+            .index;
+        let mm = self.mmaps.get(&index)?;
+        if addr.0 < mm.end {
+            Some((Addr(mm.begin), Addr(mm.end)))
+        } else {
+            None
+        }
+    }
+
     /// This is a bit of a hack for use by printk and friends.
     pub fn read_string(&mut self, ptr: Addr) -> Result<String> {
         // We assume the string is short, and grab the indexes for that max range.
@@ -573,6 +1498,7 @@ impl Memory {
 
 //-------------------------------------
 
+#[derive(Clone)]
 pub struct BuddyAllocator {
     // free_blocks[0] holds blocks of size 'block_size',
     // free_blocks[1]         "            2 * 'block_size' etc.
@@ -605,6 +1531,17 @@ impl BuddyAllocator {
         }
     }
 
+    /// Doubles the space managed, the new half starts free.
+    fn grow(&mut self) {
+        let order = self.free_blocks.len() - 1;
+        self.free_blocks.push(BTreeSet::new());
+        if self.free_blocks[order].remove(&0) {
+            self.free_blocks[order + 1].insert(0);
+        } else {
+            self.free_blocks[order].insert(1 << order);
+        }
+    }
+
     fn alloc(&mut self, order: usize) -> Result<u64> {
         // We search up through the orders looking for one that
         // contains some free blocks.  We then split this block
@@ -636,6 +1573,34 @@ impl BuddyAllocator {
         Ok(index)
     }
 
+    /// Allocates a particular block, which must be free.
+    fn alloc_at(&mut self, index: u64, order: usize) -> Result<()> {
+        // Find the free block that contains it ...
+        let mut high_order = order;
+        loop {
+            if high_order >= self.free_blocks.len() {
+                return Err(MemErr::OutOfSpace);
+            }
+
+            let mask = !((1u64 << high_order) - 1);
+            if self.free_blocks[high_order].remove(&(index & mask)) {
+                break;
+            }
+
+            high_order += 1;
+        }
+
+        // ... and split it, freeing the halves that don't.
+        while high_order != order {
+            high_order -= 1;
+            let mask = !((1u64 << high_order) - 1);
+            self.free_blocks[high_order].insert(get_buddy(index & mask, high_order));
+        }
+
+        self.allocated.insert(index, order);
+        Ok(())
+    }
+
     pub fn free(&mut self, mut index: u64) -> Result<()> {
         let order = self.allocated.remove(&index);
         if order.is_none() {
@@ -697,8 +1662,13 @@ const MIN_BLOCK_MASK: u64 = 0b111;
 /// A simple buddy allocator.  This is not attached to the memory directly
 /// so the layer above this needs to allocate via this heap, and then mmap
 /// the new chunk of memory.  Likewise the caller of free needs to unmap.
+/// The heap doubles in size when it's full, as long as it stays below
+/// 'limit'.
+#[derive(Clone)]
 pub struct Heap {
     base: u64,
+    end: u64,
+    limit: u64,
     allocator: BuddyAllocator,
 }
 
@@ -712,10 +1682,31 @@ impl Heap {
 
         Heap {
             base: begin.0,
+            end: end.0,
+            limit: end.0,
             allocator,
         }
     }
 
+    pub fn contains(&self, addr: Addr) -> bool {
+        addr.0 >= self.base && addr.0 < self.end
+    }
+
+    pub fn size(&self) -> u64 {
+        self.end - self.base
+    }
+
+    // Doubles the heap, returning false if that would pass the limit.
+    fn grow(&mut self) -> bool {
+        let end = self.base + 2 * self.size();
+        if end > self.limit {
+            return false;
+        }
+        self.allocator.grow();
+        self.end = end;
+        true
+    }
+
     fn addr_to_index(&self, ptr: Addr) -> u64 {
         let ptr = ptr.0 - self.base;
         assert!((ptr & MIN_BLOCK_MASK) == 0);
@@ -726,18 +1717,39 @@ impl Heap {
         Addr(self.base + (index << MIN_BLOCK_SHIFT))
     }
 
-    // Allocate a block of memory in the heap.
-    pub fn alloc(&mut self, mut size: usize) -> Result<Addr> {
+    fn size_to_order(mut size: usize) -> usize {
         if size < MIN_BLOCK_SIZE {
             size = MIN_BLOCK_SIZE;
         }
-        let order = size.next_power_of_two().trailing_zeros() - (MIN_BLOCK_SHIFT as u32);
-        let index = self.allocator.alloc(order as usize)?;
+        (size.next_power_of_two().trailing_zeros() - (MIN_BLOCK_SHIFT as u32)) as usize
+    }
+
+    // Allocate a block of memory in the heap.
+    pub fn alloc(&mut self, size: usize) -> Result<Addr> {
+        let order = Self::size_to_order(size);
+        let index = loop {
+            match self.allocator.alloc(order) {
+                Err(MemErr::OutOfSpace) if self.grow() => {}
+                r => break r?,
+            }
+        };
         let ptr = self.index_to_addr(index);
 
         Ok(ptr)
     }
 
+    /// Allocates the block at 'ptr', as returned by an earlier alloc()
+    /// of the same size.  The heap grows back if it has to.
+    pub fn alloc_at(&mut self, ptr: Addr, size: usize) -> Result<()> {
+        while ptr.0 + size as u64 > self.end {
+            if !self.grow() {
+                return Err(MemErr::OutOfSpace);
+            }
+        }
+        let index = self.addr_to_index(ptr);
+        self.allocator.alloc_at(index, Self::size_to_order(size))
+    }
+
     pub fn free(&mut self, ptr: Addr) -> Result<()> {
         let index = self.addr_to_index(ptr);
         match self.allocator.free(index) {
@@ -788,6 +1800,214 @@ fn test_adjacent_mmap() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn test_clone_is_cow() -> Result<()> {
+    let mut mem = Memory::new(Addr(0x10000), Addr(0x10000 + (1 << 12)));
+    mem.mmap_zeroes(Addr(64), Addr(128), PERM_READ | PERM_WRITE)?;
+    mem.write(Addr(64), &[1, 2, 3, 4], PERM_WRITE)?;
+    let ptr = mem.alloc(16)?;
+
+    let mut snap = mem.clone();
+    mem.write(Addr(64), &[5, 6, 7, 8], PERM_WRITE)?;
+    mem.free(ptr)?;
+
+    let mut buf = [0u8; 4];
+    snap.read(Addr(64), &mut buf, PERM_READ)?;
+    assert_eq!(buf, [1, 2, 3, 4]);
+    mem.read(Addr(64), &mut buf, PERM_READ)?;
+    assert_eq!(buf, [5, 6, 7, 8]);
+
+    // The allocation is still live in the snapshot.
+    snap.read(ptr, &mut buf, 0)?;
+    snap.free(ptr)?;
+    Ok(())
+}
+
+#[test]
+fn test_undo_changes() -> Result<()> {
+    let mut mem = Memory::new(Addr(0x10000), Addr(0x10000 + (1 << 12)));
+    mem.record_changes(true);
+    let a = mem.alloc(16)?;
+    mem.write(a, &[1; 16], PERM_WRITE)?;
+    let b = mem.alloc(16)?;
+    mem.write(b, &[2; 16], PERM_WRITE)?;
+    mem.free(a)?;
+
+    let changes = mem.take_changes();
+    for c in changes.iter().rev() {
+        mem.undo(c);
+    }
+
+    // Back to an empty heap.
+    assert!(mem.check_perms(b, Addr(b.0 + 16), 0).is_err());
+    assert_eq!(mem.clone().alloc(16)?, a);
+
+    for c in &changes {
+        mem.redo(c);
+    }
+
+    let mut buf = [0u8; 16];
+    mem.read(b, &mut buf, PERM_READ)?;
+    assert_eq!(buf, [2; 16]);
+    assert!(mem.free(a).is_err());
+    mem.free(b)?;
+    Ok(())
+}
+
+#[test]
+fn test_watchpoints() -> Result<()> {
+    let mut mem = Memory::new(Addr(0x10000), Addr(0x10000 + (1 << 12)));
+    mem.mmap_zeroes(Addr(64), Addr(128), PERM_READ | PERM_WRITE)?;
+    let id = mem.add_watchpoint(Addr(70), Addr(72), WatchKind::Write);
+
+    // Reads, and writes that aren't permission checked, don't fire.
+    let mut buf = [0u8; 8];
+    mem.read(Addr(64), &mut buf, PERM_READ)?;
+    mem.write(Addr(64), &[9; 8], 0)?;
+    assert!(!mem.has_watch_hits());
+
+    mem.write(Addr(68), &[1, 2, 3, 4], PERM_WRITE)?;
+    let hits = mem.take_watch_hits();
+    assert_eq!(hits.len(), 1);
+    assert_eq!(hits[0].id, id);
+    assert_eq!(hits[0].kind, WatchKind::Write);
+    assert_eq!(hits[0].addr, Addr(70));
+    assert_eq!(hits[0].old, vec![9, 9]);
+    assert_eq!(hits[0].new, vec![3, 4]);
+
+    mem.write(Addr(72), &[0; 8], PERM_WRITE)?;
+    assert!(!mem.has_watch_hits());
+
+    assert!(mem.rm_watchpoint(id));
+    mem.write(Addr(70), &[0; 2], PERM_WRITE)?;
+    assert!(!mem.has_watch_hits());
+    Ok(())
+}
+
+#[test]
+fn test_uninit_read() -> Result<()> {
+    let mut mem = Memory::new(Addr(0x10000), Addr(0x10000 + (1 << 12)));
+    let ptr = mem.alloc(16)?;
+    mem.set_alloc_trace(ptr, vec![Addr(0x1234), Addr(0x5678)]);
+    mem.write(ptr, &[1; 6], PERM_WRITE)?;
+
+    // Only checked reads fail, and only when checking is on.
+    let mut buf = [0u8; 8];
+    mem.read(ptr, &mut buf, PERM_READ)?;
+    mem.check_uninit(true);
+    mem.read(ptr, &mut buf, 0)?;
+    match mem.read(ptr, &mut buf, PERM_READ) {
+        Err(MemErr::UninitRead(addr)) => assert_eq!(addr, Addr(ptr.0 + 6)),
+        r => panic!("unexpected result: {:?}", r),
+    }
+    mem.read(ptr, &mut buf[..6], PERM_READ)?;
+    assert_eq!(mem.alloc_site(Addr(ptr.0 + 6)), Some(Addr(0x1234)));
+
+    // Reallocating the block forgets the site.
+    mem.set_quarantine(0)?;
+    mem.free(ptr)?;
+    assert_eq!(mem.alloc(16)?, ptr);
+    assert_eq!(mem.alloc_site(ptr), None);
+    Ok(())
+}
+
+#[test]
+fn test_copy_uninit() -> Result<()> {
+    let mut mem = Memory::new(Addr(0x10000), Addr(0x10000 + (1 << 12)));
+    let src = mem.alloc(16)?;
+    let dest = mem.alloc(16)?;
+    mem.write(src, &[1; 4], PERM_WRITE)?;
+    mem.write(Addr(src.0 + 8), &[2; 4], PERM_WRITE)?;
+    mem.write(dest, &[3; 16], PERM_WRITE)?;
+
+    // Copying the gaps isn't a read of them, but they stay unwritten.
+    mem.check_uninit(true);
+    mem.copy(dest, src, 16)?;
+    assert_eq!(
+        mem.unwritten(dest, Addr(dest.0 + 16))?,
+        vec![
+            (Addr(dest.0 + 4), Addr(dest.0 + 8)),
+            (Addr(dest.0 + 12), Addr(dest.0 + 16))
+        ]
+    );
+
+    let mut buf = [0u8; 4];
+    mem.read(Addr(dest.0 + 8), &mut buf, PERM_READ)?;
+    assert_eq!(buf, [2; 4]);
+    match mem.read(dest, &mut [0u8; 8], PERM_READ) {
+        Err(MemErr::UninitRead(addr)) => assert_eq!(addr, Addr(dest.0 + 4)),
+        r => panic!("unexpected result: {:?}", r),
+    }
+    Ok(())
+}
+
+#[test]
+fn test_quarantine() -> Result<()> {
+    let mut mem = Memory::new(Addr(0x10000), Addr(0x10000 + (1 << 12)));
+    mem.set_quarantine(64)?;
+    let a = mem.alloc(16)?;
+    mem.set_alloc_trace(a, vec![Addr(0x1000)]);
+    mem.free(a)?;
+    mem.set_free_trace(a, vec![Addr(0x2000)]);
+
+    // The block isn't reused while it's in quarantine.
+    let mut buf = [0u8; 8];
+    match mem.read(Addr(a.0 + 8), &mut buf, PERM_READ) {
+        Err(MemErr::UseAfterFree(addr)) => assert_eq!(addr, Addr(a.0 + 8)),
+        r => panic!("unexpected result: {:?}", r),
+    }
+    assert!(matches!(
+        mem.write(a, &buf, PERM_WRITE),
+        Err(MemErr::UseAfterFree(_))
+    ));
+    assert_eq!(mem.freed_block(Addr(a.0 + 8)), Some((a, 16)));
+    assert_eq!(mem.alloc_site(a), Some(Addr(0x1000)));
+    assert_eq!(mem.free_trace(a), Some(&[Addr(0x2000)][..]));
+    let b = mem.alloc(16)?;
+    assert_ne!(a, b);
+
+    // Freeing enough pushes it out.
+    let c = mem.alloc(40)?;
+    mem.free(b)?;
+    mem.free(c)?;
+    assert_eq!(mem.freed_block(a), None);
+    assert!(matches!(
+        mem.read(a, &mut buf, PERM_READ),
+        Err(MemErr::UnmappedRegion(_, _))
+    ));
+    assert_eq!(mem.alloc(16)?, a);
+    Ok(())
+}
+
+#[test]
+fn test_redzone() -> Result<()> {
+    let mut mem = Memory::new(Addr(0x10000), Addr(0x10000 + (1 << 12)));
+    mem.set_redzone(16);
+    let a = mem.alloc(64)?;
+    assert_eq!(a.0 % 16, 0);
+
+    // Overruns into either redzone fail, and are placed relative to the
+    // block.
+    let buf = [0u8; 8];
+    assert!(mem.write(Addr(a.0 + 60), &buf, PERM_WRITE).is_err());
+    let near = mem.nearest_block(Addr(a.0 + 68)).unwrap();
+    assert_eq!((near.ptr, near.len, near.freed), (a, 64, false));
+    assert_eq!(near.position(), "4 bytes past the end of");
+    let near = mem.nearest_block(Addr(a.0 - 2)).unwrap();
+    assert_eq!(near.position(), "2 bytes before the start of");
+    assert!(mem.nearest_block(Addr(0x1000)).is_none());
+
+    // Blocks with different redzones can be freed.
+    mem.set_redzone(4);
+    let b = mem.alloc(8)?;
+    assert!(matches!(mem.free(Addr(b.0 + 4)), Err(MemErr::BadFree(_))));
+    mem.free(a)?;
+    mem.free(b)?;
+    let near = mem.nearest_block(Addr(a.0 + 8)).unwrap();
+    assert!(near.freed && near.inside());
+    Ok(())
+}
+
 #[test]
 fn test_heap_create() -> Result<()> {
     let h = Heap::new(Addr(0x1000), Addr(0x1000 + (1 << 12)));
@@ -804,4 +2024,30 @@ fn test_heap_alloc() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn test_heap_grow() -> Result<()> {
+    let mut mem = Memory::new(Addr(0x10000), Addr(0x10000 + (1 << 12)));
+    mem.set_quarantine(0)?;
+    let a = mem.alloc(3000)?;
+    assert!(matches!(
+        mem.alloc(3000),
+        Err(MemErr::HeapExhausted { len: 3000, .. })
+    ));
+
+    // Growing keeps existing blocks where they are.
+    mem.set_heap_limit(1 << 14);
+    let b = mem.alloc(3000)?;
+    assert_eq!(mem.heap_size(), 1 << 13);
+    let c = mem.alloc(6000)?;
+    assert_eq!(mem.heap_size(), 1 << 14);
+    assert!(mem.alloc(6000).is_err());
+
+    // Once everything is freed the whole heap is available again.
+    for ptr in [a, b, c].iter() {
+        mem.free(*ptr)?;
+    }
+    mem.alloc(15000)?;
+    Ok(())
+}
+
 //-------------------------------------
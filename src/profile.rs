@@ -0,0 +1,270 @@
+use anyhow::Result;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::io::Write;
+
+//-------------------------------
+
+// Calls to these are counted as block manager locks.
+const READ_LOCKS: &[&str] = &["dm_bm_read_lock", "dm_bm_read_try_lock"];
+const WRITE_LOCKS: &[&str] = &["dm_bm_write_lock", "dm_bm_write_lock_zero"];
+
+// A call stack.  Nodes are only ever appended, so a node's parent
+// always has a lower index.
+struct Node {
+    // Address of the function called, 0 for the root.
+    func: u64,
+    parent: usize,
+    children: BTreeMap<u64, usize>,
+    calls: u64,
+
+    // Instructions executed with this as the innermost call.
+    instrs: u64,
+}
+
+/// Instructions executed, broken down by call stack.  Built by the vm
+/// as it executes calls and returns, see VM::enable_profile().  Calls
+/// are only seen if they link through ra, so a tail call is counted
+/// against its caller.
+pub struct Profile {
+    nodes: Vec<Node>,
+    stack: Vec<usize>,
+
+    // The instruction count when the innermost call was last charged.
+    charged: u64,
+}
+
+impl Profile {
+    pub fn new(instrs: u64) -> Self {
+        Profile {
+            nodes: vec![Node {
+                func: 0,
+                parent: 0,
+                children: BTreeMap::new(),
+                calls: 0,
+                instrs: 0,
+            }],
+            stack: vec![0],
+            charged: instrs,
+        }
+    }
+
+    /// Charges the instructions executed since the last call or return
+    /// to the innermost call.  Counts may go backwards if the vm is
+    /// restored or reversed, those instructions are lost.
+    pub fn charge(&mut self, instrs: u64) {
+        let top = *self.stack.last().unwrap();
+        self.nodes[top].instrs += instrs.saturating_sub(self.charged);
+        self.charged = instrs;
+    }
+
+    pub fn call(&mut self, func: u64, instrs: u64) {
+        self.charge(instrs);
+        let top = *self.stack.last().unwrap();
+        let child = match self.nodes[top].children.get(&func) {
+            Some(child) => *child,
+            None => {
+                let child = self.nodes.len();
+                self.nodes.push(Node {
+                    func,
+                    parent: top,
+                    children: BTreeMap::new(),
+                    calls: 0,
+                    instrs: 0,
+                });
+                self.nodes[top].children.insert(func, child);
+                child
+            }
+        };
+        self.nodes[child].calls += 1;
+        self.stack.push(child);
+    }
+
+    pub fn ret(&mut self, instrs: u64) {
+        self.charge(instrs);
+        if self.stack.len() > 1 {
+            self.stack.pop();
+        }
+    }
+
+    /// Returns from the innermost 'n' calls at once, eg, when a call
+    /// from the host is abandoned.
+    pub fn unwind(&mut self, n: usize, instrs: u64) {
+        self.charge(instrs);
+        let len = usize::max(self.stack.len().saturating_sub(n), 1);
+        self.stack.truncate(len);
+    }
+
+    // The functions on the stack leading to a node, outermost first.
+    fn path(&self, mut n: usize) -> Vec<u64> {
+        let mut funcs = Vec::new();
+        while n != 0 {
+            funcs.push(self.nodes[n].func);
+            n = self.nodes[n].parent;
+        }
+        funcs.reverse();
+        funcs
+    }
+
+    // Whether the function at a node is already further up the stack,
+    // in which case its inclusive count has been taken already.
+    fn is_recursive(&self, n: usize) -> bool {
+        let func = self.nodes[n].func;
+        let mut p = self.nodes[n].parent;
+        while p != 0 {
+            if self.nodes[p].func == func {
+                return true;
+            }
+            p = self.nodes[p].parent;
+        }
+        false
+    }
+
+    /// Instruction and lock counts for each function, most expensive
+    /// first.  'names' turns a function address into a name.
+    pub fn summary(&self, names: &dyn Fn(u64) -> String) -> Vec<FunctionProfile> {
+        let names: Vec<String> = self.nodes.iter().map(|n| names(n.func)).collect();
+
+        // (instrs, read locks, write locks) for each node and the calls
+        // beneath it.
+        let mut totals: Vec<(u64, u64, u64)> = self
+            .nodes
+            .iter()
+            .zip(&names)
+            .map(|(n, name)| {
+                let calls_to = |funcs: &[&str]| {
+                    if funcs.contains(&name.as_str()) {
+                        n.calls
+                    } else {
+                        0
+                    }
+                };
+                (n.instrs, calls_to(READ_LOCKS), calls_to(WRITE_LOCKS))
+            })
+            .collect();
+        for n in (1..self.nodes.len()).rev() {
+            let (instrs, read, write) = totals[n];
+            let parent = &mut totals[self.nodes[n].parent];
+            parent.0 += instrs;
+            parent.1 += read;
+            parent.2 += write;
+        }
+
+        let mut index: BTreeMap<&str, usize> = BTreeMap::new();
+        let mut funcs: Vec<FunctionProfile> = Vec::new();
+        for n in 1..self.nodes.len() {
+            let node = &self.nodes[n];
+            let i = *index.entry(names[n].as_str()).or_insert_with(|| {
+                funcs.push(FunctionProfile {
+                    name: names[n].clone(),
+                    ..Default::default()
+                });
+                funcs.len() - 1
+            });
+            let f = &mut funcs[i];
+            f.calls += node.calls;
+            f.exclusive += node.instrs;
+            if !self.is_recursive(n) {
+                let (instrs, read, write) = totals[n];
+                f.inclusive += instrs;
+                f.read_locks += read;
+                f.write_locks += write;
+            }
+        }
+
+        funcs.sort_by_key(|f| Reverse(f.inclusive));
+        funcs
+    }
+
+    pub fn write_summary<W: Write>(&self, names: &dyn Fn(u64) -> String, w: &mut W) -> Result<()> {
+        writeln!(
+            w,
+            "{:<50} {:>8} {:>12} {:>12} {:>8} {:>8}",
+            "function", "calls", "inclusive", "exclusive", "rlocks", "wlocks"
+        )?;
+        let mut total = 0;
+        for f in self.summary(names) {
+            writeln!(
+                w,
+                "{:<50} {:>8} {:>12} {:>12} {:>8} {:>8}",
+                f.name, f.calls, f.inclusive, f.exclusive, f.read_locks, f.write_locks
+            )?;
+            total += f.exclusive;
+        }
+        writeln!(w, "{:<50} {:>8} {:>12}", "TOTAL", "", total)?;
+        Ok(())
+    }
+
+    /// Writes the stacks in the folded format that flamegraph.pl and
+    /// inferno understand, eg, "dm_btree_insert;insert;btree_insert_raw 1234".
+    pub fn write_folded<W: Write>(&self, names: &dyn Fn(u64) -> String, w: &mut W) -> Result<()> {
+        for (n, node) in self.nodes.iter().enumerate().skip(1) {
+            if node.instrs > 0 {
+                let path: Vec<String> = self.path(n).into_iter().map(names).collect();
+                writeln!(w, "{} {}", path.join(";"), node.instrs)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Counts for a single function, see Profile::summary().  Inclusive
+/// counts take in the functions it calls, exclusive ones don't.
+#[derive(Clone, Debug, Default)]
+pub struct FunctionProfile {
+    pub name: String,
+    pub calls: u64,
+    pub inclusive: u64,
+    pub exclusive: u64,
+    pub read_locks: u64,
+    pub write_locks: u64,
+}
+
+//-------------------------------
+
+#[test]
+fn test_profile() {
+    let names = |addr: u64| match addr {
+        0x100 => "insert".to_string(),
+        0x200 => "dm_bm_read_lock".to_string(),
+        0x300 => "recurse".to_string(),
+        _ => format!("{:#x}", addr),
+    };
+
+    let mut p = Profile::new(10);
+    p.call(0x100, 12);
+    p.call(0x200, 15);
+    p.ret(15);
+    p.call(0x300, 20);
+    p.call(0x300, 22);
+    p.call(0x200, 25);
+    p.ret(25);
+    p.ret(30);
+    p.ret(31);
+    p.ret(40);
+
+    let summary = p.summary(&names);
+    let get = |name: &str| summary.iter().find(|f| f.name == name).unwrap().clone();
+
+    let insert = get("insert");
+    assert_eq!(insert.calls, 1);
+    assert_eq!(insert.inclusive, 28);
+    assert_eq!(insert.exclusive, 17);
+    assert_eq!(insert.read_locks, 2);
+
+    let recurse = get("recurse");
+    assert_eq!(recurse.calls, 2);
+    assert_eq!(recurse.inclusive, 11);
+    assert_eq!(recurse.exclusive, 11);
+    assert_eq!(recurse.read_locks, 1);
+    assert_eq!(summary[0].name, "insert");
+
+    let mut folded = Vec::new();
+    p.write_folded(&names, &mut folded).unwrap();
+    assert_eq!(
+        String::from_utf8(folded).unwrap(),
+        "insert 17\ninsert;recurse 3\ninsert;recurse;recurse 8\n"
+    );
+}
+
+//-------------------------------
@@ -0,0 +1,211 @@
+use crate::memory::MemChange;
+use crate::vm::{Frame, Stats, VmSnapshot};
+
+use std::collections::VecDeque;
+
+//-------------------------------
+
+// How often, in steps, a snapshot of the whole vm is taken, so seeking
+// doesn't have to replay every step.
+const CHECKPOINT_INTERVAL: u64 = 100_000;
+
+/// Everything that changed between two steps of the vm.  A step is
+/// usually a single instruction, but the changes made by a stub form a
+/// step of their own.
+pub struct Step {
+    // (register, old value, new value)
+    pub regs: Vec<(usize, u64, u64)>,
+    pub mem: Vec<MemChange>,
+    pub frames: Option<(Vec<Frame>, Vec<Frame>)>,
+    pub stats: (Stats, Stats),
+}
+
+struct Checkpoint {
+    // The number of steps taken since recording started.
+    step: u64,
+    instrs: u64,
+    snap: VmSnapshot,
+}
+
+/// A log of the last 'limit' steps taken by the vm.  The vm can be
+/// wound backwards through the log, and then forwards again to where
+/// it started.
+pub struct History {
+    limit: usize,
+
+    // The number of steps dropped from the front of the log.
+    base: u64,
+    steps: VecDeque<Step>,
+
+    // Steps before the cursor have been applied, those after it undone.
+    cursor: usize,
+
+    // The state at the cursor.  Changes are worked out against this.
+    reg: Vec<u64>,
+    frames: Vec<Frame>,
+    stats: Stats,
+
+    checkpoints: VecDeque<Checkpoint>,
+    next_checkpoint: u64,
+}
+
+impl History {
+    pub fn new(limit: usize, reg: &[u64], frames: &[Frame], stats: Stats) -> Self {
+        History {
+            limit,
+            base: 0,
+            steps: VecDeque::new(),
+            cursor: 0,
+            reg: reg.to_vec(),
+            frames: frames.to_vec(),
+            stats,
+            checkpoints: VecDeque::new(),
+            next_checkpoint: CHECKPOINT_INTERVAL,
+        }
+    }
+
+    /// Drops the log.  Used when the vm is restored from a snapshot.
+    pub fn clear(&mut self, reg: &[u64], frames: &[Frame], stats: Stats) {
+        *self = History::new(self.limit, reg, frames, stats);
+    }
+
+    /// Logs the changes since the last call.  Returns true if it's time
+    /// for a checkpoint.
+    pub fn record(
+        &mut self,
+        reg: &[u64],
+        frames: &[Frame],
+        stats: Stats,
+        mem: Vec<MemChange>,
+    ) -> bool {
+        let regs: Vec<(usize, u64, u64)> = self
+            .reg
+            .iter()
+            .zip(reg)
+            .enumerate()
+            .filter(|(_, (old, new))| old != new)
+            .map(|(r, (old, new))| (r, *old, *new))
+            .collect();
+        let frames = if self.frames[..] != *frames {
+            Some((
+                std::mem::replace(&mut self.frames, frames.to_vec()),
+                frames.to_vec(),
+            ))
+        } else {
+            None
+        };
+
+        if regs.is_empty() && mem.is_empty() && frames.is_none() && stats == self.stats {
+            return false;
+        }
+
+        // Anything beyond the cursor is a future that won't now happen.
+        if self.cursor < self.steps.len() {
+            self.truncate();
+        }
+
+        self.reg.copy_from_slice(reg);
+        self.steps.push_back(Step {
+            regs,
+            mem,
+            frames,
+            stats: (self.stats, stats),
+        });
+        self.stats = stats;
+
+        if self.steps.len() > self.limit {
+            self.steps.pop_front();
+            self.base += 1;
+            while matches!(self.checkpoints.front(), Some(cp) if cp.step < self.base) {
+                self.checkpoints.pop_front();
+            }
+        }
+        self.cursor = self.steps.len();
+
+        self.position() >= self.next_checkpoint
+    }
+
+    fn truncate(&mut self) {
+        self.steps.truncate(self.cursor);
+        let end = self.position();
+        while matches!(self.checkpoints.back(), Some(cp) if cp.step > end) {
+            self.checkpoints.pop_back();
+        }
+        let last = self.checkpoints.back().map_or(0, |cp| cp.step);
+        self.next_checkpoint = last + CHECKPOINT_INTERVAL;
+    }
+
+    // The number of steps taken to reach the cursor.
+    fn position(&self) -> u64 {
+        self.base + self.cursor as u64
+    }
+
+    /// Saves the state at the cursor.
+    pub fn add_checkpoint(&mut self, snap: VmSnapshot) {
+        self.next_checkpoint = self.position() + CHECKPOINT_INTERVAL;
+        self.checkpoints.push_back(Checkpoint {
+            step: self.position(),
+            instrs: self.stats.instrs,
+            snap,
+        });
+    }
+
+    /// The step before the cursor.
+    pub fn prev(&self) -> Option<&Step> {
+        self.cursor.checked_sub(1).and_then(|i| self.steps.get(i))
+    }
+
+    /// Moves the cursor back a step, returning the step to undo.
+    pub fn back(&mut self) -> Option<&Step> {
+        self.cursor = self.cursor.checked_sub(1)?;
+        self.steps.get(self.cursor)
+    }
+
+    /// Moves the cursor forward a step, returning the step to redo.
+    pub fn forward(&mut self) -> Option<&Step> {
+        let step = self.steps.get(self.cursor)?;
+        self.cursor += 1;
+        Some(step)
+    }
+
+    /// Updates the state at the cursor, after the vm has moved.
+    pub fn sync(&mut self, reg: &[u64], frames: &[Frame], stats: Stats) {
+        self.reg.copy_from_slice(reg);
+        if self.frames[..] != *frames {
+            self.frames = frames.to_vec();
+        }
+        self.stats = stats;
+    }
+
+    /// The range of instruction counts covered by the log.
+    pub fn instrs_range(&self) -> (u64, u64) {
+        let first = self
+            .steps
+            .front()
+            .map_or(self.stats.instrs, |s| s.stats.0.instrs);
+        let last = self
+            .steps
+            .back()
+            .map_or(self.stats.instrs, |s| s.stats.1.instrs);
+        (first, last)
+    }
+
+    /// The last checkpoint at or before 'instrs', along with where it
+    /// puts the cursor.
+    pub fn checkpoint_before(&self, instrs: u64) -> Option<(usize, u64, &VmSnapshot)> {
+        self.checkpoints
+            .iter()
+            .rev()
+            .find(|cp| cp.instrs <= instrs)
+            .map(|cp| ((cp.step - self.base) as usize, cp.instrs, &cp.snap))
+    }
+
+    /// Moves the cursor to a checkpoint that the vm has been restored
+    /// from.
+    pub fn jump(&mut self, cursor: usize, reg: &[u64], frames: &[Frame], stats: Stats) {
+        self.cursor = cursor;
+        self.sync(reg, frames, stats);
+    }
+}
+
+//-------------------------------
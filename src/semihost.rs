@@ -0,0 +1,89 @@
+use crate::decode::Reg;
+use crate::memory::Addr;
+use crate::vm::VM;
+
+use Reg::*;
+
+//-------------------------------
+
+// The semihosting ABI, which lets guest code call into the test
+// harness with an ECALL:
+//
+//   a7      the service number
+//   a0..a5  arguments
+//   a0      the result, on return
+//
+// The built in services are listed below.  Numbers from SYS_USER upwards
+// are free for tests to use, see Fixture::on_ecall().
+
+/// Logs the NUL terminated string at a0.
+pub const SYS_LOG: u64 = 1;
+
+/// Fails the test if a0 is zero.  a1 points to a message, or is zero.
+pub const SYS_ASSERT: u64 = 2;
+
+/// Ends the current call from the harness.  A zero status in a0 means
+/// it passed, and the call returns normally, anything else fails it.
+pub const SYS_EXIT: u64 = 3;
+
+/// Returns the test parameter named by the string at a0, or a1 if the
+/// test hasn't set it.
+pub const SYS_GET_PARAM: u64 = 4;
+
+/// The first service number available to tests.
+pub const SYS_USER: u64 = 0x1000;
+
+/// A semihosting request made by the guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Ecall {
+    pub service: u64,
+    pub args: [u64; 6],
+}
+
+impl Ecall {
+    /// Reads the request from the registers of a vm that has stopped
+    /// at an ECALL.
+    pub fn from_vm(vm: &VM) -> Self {
+        Ecall {
+            service: vm.reg(A7),
+            args: [
+                vm.reg(A0),
+                vm.reg(A1),
+                vm.reg(A2),
+                vm.reg(A3),
+                vm.reg(A4),
+                vm.reg(A5),
+            ],
+        }
+    }
+
+    /// Returns 'v' to the guest and steps over the ECALL.
+    pub fn complete(vm: &mut VM, v: u64) {
+        vm.set_reg(A0, v);
+        vm.set_pc(Addr(vm.pc().0 + 4));
+    }
+}
+
+//-------------------------------
+
+#[test]
+fn test_ecall() {
+    use crate::vm::{vm_with_code, VmErr};
+
+    // li a7,4; li a0,7; li a1,9; ecall; addi a0,a0,1; ebreak
+    let mut vm = vm_with_code(&[
+        0x00400893, 0x00700513, 0x00900593, 0x00000073, 0x00150513, 0x00100073,
+    ]);
+    assert!(matches!(vm.run(), Err(VmErr::ECall)));
+    assert_eq!(vm.pc(), Addr(0x100c));
+
+    let call = Ecall::from_vm(&vm);
+    assert_eq!(call.service, SYS_GET_PARAM);
+    assert_eq!(&call.args[..2], &[7, 9]);
+
+    Ecall::complete(&mut vm, 41);
+    assert!(matches!(vm.run(), Err(VmErr::EBreak)));
+    assert_eq!(vm.reg(A0), 42);
+}
+
+//-------------------------------
@@ -5,6 +5,7 @@ use crate::stubs::block_manager::*;
 
 pub struct Stats {
     pub instrs: u64,
+    pub cycles: u64,
     pub read_locks: u64,
     pub write_locks: u64,
 }
@@ -14,6 +15,7 @@ impl Stats {
         let bm = get_bm().unwrap();
         Stats {
             instrs: fix.vm.stats.instrs,
+            cycles: fix.vm.stats.cycles,
             read_locks: bm.nr_read_locks,
             write_locks: bm.nr_write_locks,
         }
@@ -23,6 +25,7 @@ impl Stats {
         let rhs = Stats::collect_stats(fix);
         Stats {
             instrs: rhs.instrs - self.instrs,
+            cycles: rhs.cycles - self.cycles,
             read_locks: rhs.read_locks - self.read_locks,
             write_locks: rhs.write_locks - self.write_locks,
         }
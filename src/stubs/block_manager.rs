@@ -8,6 +8,7 @@ use anyhow::{anyhow, Result};
 use crc32c::crc32c;
 use log::*;
 use std::sync::Arc;
+use thinp::io_engine::IoEngine;
 
 use Reg::*;
 
@@ -61,6 +62,21 @@ pub fn clear_bm() {
     }
 }
 
+/// Copies the block manager, if there is one, for a fixture snapshot.
+pub fn snapshot_bm() -> Option<(Addr, BlockManager)> {
+    unsafe {
+        BLOCK_MANAGER
+            .as_ref()
+            .map(|(gptr, bm)| (*gptr, bm.snapshot()))
+    }
+}
+
+pub fn restore_bm(snap: &Option<(Addr, BlockManager)>) {
+    unsafe {
+        BLOCK_MANAGER = snap.as_ref().map(|(gptr, bm)| (*gptr, bm.snapshot()));
+    }
+}
+
 pub fn bm_create(fix: &mut Fixture) -> Result<()> {
     let bdev_ptr = fix.vm.reg(A0);
     let _block_size = fix.vm.reg(A1);
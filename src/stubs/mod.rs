@@ -1,9 +1,10 @@
+use crate::cost::InstClass;
 use crate::fixture::*;
 use crate::memory::*;
 use crate::decode::*;
 
 use anyhow::Result;
-use log::info;
+use log::{info, warn};
 
 pub mod block_manager;
 
@@ -25,6 +26,41 @@ pub fn printk(fix: &mut Fixture) -> Result<()> {
     Ok(())
 }
 
+pub fn dump_stack(fix: &mut Fixture) -> Result<()> {
+    warn!("dump_stack:\n  {}", fix.backtrace().join("\n  "));
+    fix.vm.ret(0);
+    Ok(())
+}
+
+/// Used for WARN() with a message, eg, __warn_printk or warn_slowpath_fmt.
+pub fn warn_printk(fix: &mut Fixture) -> Result<()> {
+    let msg = fix.vm.mem.read_string(Addr(fix.vm.reg(A0)))?;
+    warn!(
+        "WARNING: {}\n  {}",
+        msg.trim_end(),
+        fix.backtrace().join("\n  ")
+    );
+    fix.vm.ret(0);
+    Ok(())
+}
+
+/// warn_slowpath_fmt(const char *file, int line, unsigned taint,
+///                   const char *fmt, ...)
+pub fn warn_slowpath_fmt(fix: &mut Fixture) -> Result<()> {
+    let file = fix.vm.mem.read_string(Addr(fix.vm.reg(A0)))?;
+    let line = fix.vm.reg(A1) as i32;
+    let msg = fix.vm.mem.read_string(Addr(fix.vm.reg(A3)))?;
+    warn!(
+        "WARNING: {}:{}: {}\n  {}",
+        file,
+        line,
+        msg.trim_end(),
+        fix.backtrace().join("\n  ")
+    );
+    fix.vm.ret(0);
+    Ok(())
+}
+
 pub fn memcpy(fix: &mut Fixture) -> Result<()> {
     let dest = Addr(fix.vm.reg(A0));
     let src = Addr(fix.vm.reg(A1));
@@ -36,19 +72,34 @@ pub fn memcpy(fix: &mut Fixture) -> Result<()> {
         .mem
         .check_perms(dest, Addr(dest.0 + len), PERM_WRITE)?;
 
-    let mut bytes = vec![0u8; len as usize];
-    fix.vm.mem.read(src, &mut bytes, PERM_READ)?;
-    fix.vm.mem.write(dest, &bytes, PERM_WRITE)?;
+    // Uninitialised bytes, eg, struct padding, are copied as such.
+    fix.vm.mem.copy(dest, src, len)?;
     fix.vm.ret(dest.0);
 
-    // The vm instr count should reflect this expensive operation.
-    fix.vm.stats.instrs += (len * 3) / 8;
+    // Charge as though the guest copied a word at a time.
+    let words = len / 8;
+    fix.vm.charge(InstClass::Load, words);
+    fix.vm.charge(InstClass::Store, words);
+    fix.vm.charge(InstClass::Alu, words);
+    fix.vm.charge_access(src, len, false);
+    fix.vm.charge_access(dest, len, true);
     Ok(())
 }
 
+// kzalloc() and friends pass this in the gfp flags.
+const GFP_ZERO: u64 = 0x100;
+
 pub fn kmalloc(fix: &mut Fixture) -> Result<()> {
     let len = fix.vm.reg(Reg::A0);
+    let flags = fix.vm.reg(Reg::A1);
     let ptr = fix.vm.mem.alloc(len as usize)?;
+    if (flags & GFP_ZERO) != 0 {
+        fix.vm.mem.write(ptr, &vec![0u8; len as usize], 0)?;
+    }
+
+    // Skip the frame for kmalloc itself.
+    let trace = fix.vm.backtrace().split_off(1);
+    fix.vm.mem.set_alloc_trace(ptr, trace);
     fix.vm.ret(ptr.0);
     Ok(())
 }
@@ -56,6 +107,8 @@ pub fn kmalloc(fix: &mut Fixture) -> Result<()> {
 pub fn kfree(fix: &mut Fixture) -> Result<()> {
     let ptr = Addr(fix.vm.reg(Reg::A0));
     fix.vm.mem.free(ptr)?;
+    let trace = fix.vm.backtrace().split_off(1);
+    fix.vm.mem.set_free_trace(ptr, trace);
     fix.vm.ret(0);
     Ok(())
 }
@@ -109,5 +162,16 @@ pub fn standard_globals(fix: &mut Fixture) -> Result<()> {
     fix.at_func("dm_bm_set_read_write", Box::new(bm_set_read_write))?;
     fix.at_func("dm_bm_checksum", Box::new(bm_checksum))?;
     fix.at_func("printk", Box::new(printk))?;
+
+    // Only present if the module uses them.
+    if fix.has_symbol("dump_stack") {
+        fix.at_func("dump_stack", Box::new(dump_stack))?;
+    }
+    if fix.has_symbol("__warn_printk") {
+        fix.at_func("__warn_printk", Box::new(warn_printk))?;
+    }
+    if fix.has_symbol("warn_slowpath_fmt") {
+        fix.at_func("warn_slowpath_fmt", Box::new(warn_slowpath_fmt))?;
+    }
     Ok(())
 }
@@ -1,13 +1,13 @@
+use crate::coverage::*;
 use crate::fixture::*;
+use crate::memory::Addr;
+use crate::profile::Profile;
 use anyhow::Result;
-use gdbstub::arch::riscv::Riscv64;
-use gdbstub::arch::Arch;
-use gdbstub::target::ext::base::singlethread::{SingleThreadOps, StopReason};
-use gdbstub::target::ext::base::{self, ResumeAction};
-use gdbstub::target::{Target, TargetResult};
-use log::{debug, info};
+use log::debug;
 use regex::Regex;
 use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Write;
 use std::net::{TcpListener, TcpStream};
 use std::path::{Path, PathBuf};
 
@@ -79,62 +79,21 @@ impl PathFormatter {
 //-------------------------------
 // GDB support
 
-#[allow(dead_code)]
-struct TestTarget<'a> {
-    fix: Fixture,
-    test_fn: &'a mut Box<dyn Fn(&mut Fixture) -> Result<()>>,
-}
+const GDB_PORT: u16 = 9001;
+const GDB_SCRIPT: &str = "dm-unit.gdb";
 
-impl<'a> SingleThreadOps for TestTarget<'a> {
-    fn resume(
-        &mut self,
-        _action: ResumeAction,
-        _check_gdb_interrupt: &mut dyn FnMut() -> bool,
-    ) -> Result<StopReason<<Self::Arch as Arch>::Usize>, Self::Error> {
-        todo!();
-    }
+// Writes a gdb script that loads the module's symbols at the addresses we
+// loaded it to, and connects to the stub.
+fn write_gdb_script(fix: &Fixture) -> std::io::Result<()> {
+    let mut file = File::create(GDB_SCRIPT)?;
+    writeln!(file, "{}", fix.add_symbol_file_cmd())?;
+    writeln!(file, "target remote localhost:{}", GDB_PORT)?;
 
-    fn read_registers(
-        &mut self,
-        _regs: &mut <Self::Arch as Arch>::Registers,
-    ) -> TargetResult<(), Self> {
-        todo!();
-    }
-
-    fn write_registers(
-        &mut self,
-        _regs: &<Self::Arch as Arch>::Registers,
-    ) -> TargetResult<(), Self> {
-        todo!();
-    }
-
-    fn read_addrs(
-        &mut self,
-        _start_addr: <Self::Arch as Arch>::Usize,
-        _data: &mut [u8],
-    ) -> TargetResult<(), Self> {
-        todo!();
-    }
-
-    fn write_addrs(
-        &mut self,
-        _start_addr: <Self::Arch as Arch>::Usize,
-        _data: &[u8],
-    ) -> TargetResult<(), Self> {
-        todo!();
-    }
+    eprintln!("{}", fix.add_symbol_file_cmd());
+    eprintln!("(or 'gdb -x {}' to load symbols and connect)", GDB_SCRIPT);
+    Ok(())
 }
 
-impl<'a> Target for TestTarget<'a> {
-    type Arch = Riscv64;
-    type Error = anyhow::Error;
-
-    fn base_ops(&mut self) -> base::BaseOps<Self::Arch, Self::Error> {
-        base::BaseOps::SingleThread(self)
-    }
-}
-
-#[allow(dead_code)]
 fn wait_for_gdb_connection(port: u16) -> std::io::Result<TcpStream> {
     let sockaddr = format!("localhost:{}", port);
     eprintln!("Waiting for a GDB connection on {:?}...", sockaddr);
@@ -149,12 +108,83 @@ fn wait_for_gdb_connection(port: u16) -> std::io::Result<TcpStream> {
 
 //-------------------------------
 
-#[allow(dead_code)]
+// Test paths become file names by flattening the '/'s.
+fn test_file(dir: &Path, test: &str, ext: &str) -> PathBuf {
+    let name = test.trim_matches('/').replace('/', ".");
+    dir.join(format!("{}.{}", name, ext))
+}
+
+//-------------------------------
+// Coverage support
+
+fn write_coverage(dir: &Path, map: &CoverageMap, test: &str, hits: &Hits) -> Result<()> {
+    let mut file = File::create(test_file(dir, test, "info"))?;
+    map.write_lcov(test, hits, &mut file)?;
+    Ok(())
+}
+
+//-------------------------------
+// Profiling
+
+// Writes a table of the instructions executed by each function, and
+// the stacks in folded form for flamegraphs.
+fn write_profile(dir: &Path, fix: &Fixture, test: &str, profile: &Profile) -> Result<()> {
+    let names = |addr| fix.symbolize(Addr(addr));
+    let mut file = File::create(test_file(dir, test, "profile"))?;
+    profile.write_summary(&names, &mut file)?;
+    let mut file = File::create(test_file(dir, test, "folded"))?;
+    profile.write_folded(&names, &mut file)?;
+    Ok(())
+}
+
+//-------------------------------
+// Core files
+
+// Writes a core file for a failed test, along with a gdb script that
+// loads it and the module's symbols.
+fn write_core_files(dir: &Path, fix: &Fixture, test: &str, e: &anyhow::Error) -> Result<()> {
+    let core = test_file(dir, test, "core");
+    fix.write_core(&core, e)?;
+
+    let script = test_file(dir, test, "gdb");
+    let mut file = File::create(&script)?;
+    writeln!(file, "{}", fix.add_symbol_file_cmd())?;
+    writeln!(file, "core-file {}", core.display())?;
+
+    eprintln!(
+        "core written to {} ('gdb -x {}' to load it)",
+        core.display(),
+        script.display()
+    );
+    Ok(())
+}
+
+//-------------------------------
+
+/// What to do about heap blocks the guest leaves allocated when a test
+/// passes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LeakCheck {
+    Off,
+    Warn,
+    Fail,
+}
+
 pub struct TestRunner<'a> {
     kernel_dir: PathBuf,
     filter_fn: Box<dyn Fn(&str) -> bool + 'a>,
     tests: BTreeMap<String, TestFn>,
     gdb: bool,
+    budget: Option<u64>,
+    coverage_dir: Option<PathBuf>,
+    profile_dir: Option<PathBuf>,
+    core_dir: Option<PathBuf>,
+    leak_check: LeakCheck,
+
+    // The memory layout for tests that weren't registered with their
+    // own.
+    layout: MemLayout,
+    layouts: BTreeMap<String, MemLayout>,
 }
 
 pub type TestFn = Box<dyn Fn(&mut Fixture) -> Result<()>>;
@@ -171,13 +201,52 @@ impl<'a> TestRunner<'a> {
             filter_fn,
             tests: BTreeMap::new(),
             gdb: false,
+            budget: None,
+            coverage_dir: None,
+            profile_dir: None,
+            core_dir: None,
+            leak_check: LeakCheck::Warn,
+            layout: MemLayout::default(),
+            layouts: BTreeMap::new(),
         }
     }
 
+    /// Writes lcov coverage for each test, and for the whole run, to
+    /// the given directory, along with a per function summary.
+    pub fn enable_coverage<P: AsRef<Path>>(&mut self, dir: P) {
+        self.coverage_dir = Some(dir.as_ref().to_path_buf());
+    }
+
+    /// Writes a profile of the instructions executed by each test to
+    /// the given directory, both as a table and as folded stacks.
+    pub fn enable_profile<P: AsRef<Path>>(&mut self, dir: P) {
+        self.profile_dir = Some(dir.as_ref().to_path_buf());
+    }
+
+    /// Writes a core file for each failed test to the given directory.
+    pub fn enable_core_files<P: AsRef<Path>>(&mut self, dir: P) {
+        self.core_dir = Some(dir.as_ref().to_path_buf());
+    }
+
     pub fn enable_gdb(&mut self) {
         self.gdb = true;
     }
 
+    /// Fails any test that executes more than this many instructions.
+    pub fn set_budget(&mut self, budget: u64) {
+        self.budget = Some(budget);
+    }
+
+    /// Leaks are reported, but don't fail tests, by default.
+    pub fn set_leak_check(&mut self, check: LeakCheck) {
+        self.leak_check = check;
+    }
+
+    /// Sets the heap and stack for tests that don't ask for their own.
+    pub fn set_layout(&mut self, layout: MemLayout) {
+        self.layout = layout;
+    }
+
     pub fn set_filter(&mut self, filter: Regex) {
         self.filter_fn = Box::new(move |p| filter.is_match(p));
     }
@@ -190,10 +259,29 @@ impl<'a> TestRunner<'a> {
         self.tests.insert(path.to_string(), t);
     }
 
+    /// Registers a test that needs a particular heap or stack, eg, a
+    /// bigger heap for a large btree.
+    pub fn register_with_layout(&mut self, path: &str, layout: MemLayout, t: TestFn) {
+        self.layouts.insert(path.to_string(), layout);
+        self.register(path, t);
+    }
+
     pub fn exec(&mut self) -> Result<(usize, usize)> {
         let mut pass = 0;
         let mut fail = 0;
         let mut formatter = PathFormatter::new();
+        let mut coverage_map = None;
+        let mut total_hits = Hits::new();
+
+        if let Some(dir) = &self.coverage_dir {
+            std::fs::create_dir_all(dir)?;
+        }
+        if let Some(dir) = &self.profile_dir {
+            std::fs::create_dir_all(dir)?;
+        }
+        if let Some(dir) = &self.core_dir {
+            std::fs::create_dir_all(dir)?;
+        }
 
         for (p, t) in &mut self.tests {
             if !(*self.filter_fn)(p) {
@@ -204,19 +292,73 @@ impl<'a> TestRunner<'a> {
             let components = path_components(p);
             formatter.print(&components);
 
-            let mut fix = Fixture::new(&self.kernel_dir)?;
+            let layout = self.layouts.get(p).unwrap_or(&self.layout);
+            let mut fix = Fixture::with_layout(&self.kernel_dir, layout)?;
+            fix.set_test_budget(self.budget);
+            if self.coverage_dir.is_some() {
+                fix.vm.enable_coverage();
+            }
+            if self.profile_dir.is_some() {
+                fix.vm.enable_profile();
+            }
+            if self.gdb {
+                write_gdb_script(&fix)?;
+                let stream = wait_for_gdb_connection(GDB_PORT)?;
+                fix.attach_debugger(stream);
+            }
+
+            let r = (*t)(&mut fix);
+            fix.detach_debugger();
+
+            // Only passing tests are checked, a failure may have
+            // abandoned the guest part way through.
+            let leaks = if r.is_ok() && self.leak_check != LeakCheck::Off {
+                fix.leaks()
+            } else {
+                Vec::new()
+            };
+
+            if let Some(dir) = &self.coverage_dir {
+                if coverage_map.is_none() {
+                    coverage_map = Some(fix.coverage_map()?);
+                }
+                let hits = fix.vm.take_coverage();
+                write_coverage(dir, coverage_map.as_ref().unwrap(), p, &hits)?;
+                merge_hits(&mut total_hits, &hits);
+            }
+
+            if let Some(dir) = &self.profile_dir {
+                if let Some(profile) = fix.vm.take_profile() {
+                    write_profile(dir, &fix, p, &profile)?;
+                }
+            }
 
-            if let Err(e) = (*t)(&mut fix) {
+            if let Err(e) = r {
+                fail += 1;
+                println!(" FAIL");
+                eprintln!("{}", fix.failure_report(&e));
+                if let Some(dir) = &self.core_dir {
+                    write_core_files(dir, &fix, p, &e)?;
+                }
+            } else if !leaks.is_empty() && self.leak_check == LeakCheck::Fail {
                 fail += 1;
                 println!(" FAIL");
-                info!("{}", e);
-                debug!("{}", fix.vm);
+                eprintln!("{}", fix.leak_report(&leaks));
             } else {
                 pass += 1;
                 println!(" PASS");
+                if !leaks.is_empty() {
+                    eprintln!("{}", fix.leak_report(&leaks));
+                }
             }
         }
 
+        if let (Some(dir), Some(map)) = (&self.coverage_dir, &coverage_map) {
+            write_coverage(dir, map, "total", &total_hits)?;
+            let mut file = File::create(dir.join("summary.txt"))?;
+            map.write_summary(&total_hits, &mut file)?;
+        }
+
         Ok((pass, fail))
     }
 }
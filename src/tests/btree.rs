@@ -353,6 +353,12 @@ impl<'a> BTreeTest<'a> {
         Ok(())
     }
 
+    fn remove(&mut self, key: u64) -> Result<()> {
+        let ks = vec![key];
+        self.root = dm_btree_remove(self.fix, &self.info, self.root, &ks)?;
+        Ok(())
+    }
+
     fn lookup(&mut self, key: u64) -> Result<()> {
         let keys = vec![key];
         let v = dm_btree_lookup(self.fix, &self.info, self.root, &keys)?;
@@ -380,10 +386,11 @@ impl<'a> BTreeTest<'a> {
     fn stats_report(&self, desc: &str, count: u64) -> Result<()> {
         let delta = self.baseline.delta(self.fix);
         info!(
-            "{}: residency = {}, instrs = {}, read_locks = {:.1}, write_locks = {:.1}",
+            "{}: residency = {}, instrs = {}, cycles = {}, read_locks = {:.1}, write_locks = {:.1}",
             desc,
             self.residency()?,
             delta.instrs / count,
+            delta.cycles / count,
             delta.read_locks as f64 / count as f64,
             delta.write_locks as f64 / count as f64
         );
@@ -503,6 +510,55 @@ fn test_insert_runs(fix: &mut Fixture) -> Result<()> {
 
 //-------------------------------
 
+// Populating a tree is slow, so we do it once and use snapshots to run
+// several removal scenarios against the same tree.
+fn test_remove_from_snapshot(fix: &mut Fixture) -> Result<()> {
+    standard_globals(fix)?;
+    let mut bt = BTreeTest::new(fix)?;
+
+    let keys: Vec<u64> = (0..KEY_COUNT).collect();
+    for k in &keys {
+        bt.insert(*k)?;
+    }
+    bt.commit()?;
+
+    let root = bt.root;
+    let snap = bt.fix.snapshot();
+
+    let scenarios: Vec<(&str, Vec<u64>)> = vec![
+        ("ascending", (0..KEY_COUNT / 4).collect()),
+        ("descending", (KEY_COUNT / 4..KEY_COUNT / 2).rev().collect()),
+        ("alternate", (0..KEY_COUNT).step_by(2).collect()),
+    ];
+
+    for (desc, removes) in scenarios {
+        bt.fix.restore(&snap);
+        bt.root = root;
+
+        bt.stats_start();
+        for k in &removes {
+            bt.remove(*k)?;
+        }
+        bt.stats_report(desc, removes.len() as u64)?;
+
+        ensure!(bt.lookup(removes[0]).is_err());
+        let removes: BTreeSet<u64> = removes.into_iter().collect();
+        let remaining: Vec<u64> = keys
+            .iter()
+            .cloned()
+            .filter(|k| !removes.contains(k))
+            .collect();
+        bt.check_keys_present(&remaining)?;
+    }
+
+    // Put things back as they were, so teardown sees a consistent tm.
+    bt.fix.restore(&snap);
+    bt.root = root;
+    Ok(())
+}
+
+//-------------------------------
+
 // comsume_cursor() tests
 fn test_cc_empty_cursor_fails(fix: &mut Fixture) -> Result<()> {
     let mut cursor = CopyCursor {
@@ -783,6 +839,8 @@ pub fn register_tests(runner: &mut TestRunner) -> Result<()> {
             test!("runs", test_insert_runs)
         }
 
+        test!("remove/from-snapshot", test_remove_from_snapshot)
+
         test_section! {
             "consume_cursor/",
             test!(
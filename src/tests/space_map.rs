@@ -15,9 +15,10 @@ use log::*;
 fn stats_report(fix: &Fixture, baseline: &Stats, desc: &str, count: u64) {
     let delta = baseline.delta(fix);
     info!(
-        "{}: instrs = {}, read_locks = {:.1}, write_locks = {:.1}",
+        "{}: instrs = {}, cycles = {}, read_locks = {:.1}, write_locks = {:.1}",
         desc,
         delta.instrs / count,
+        delta.cycles / count,
         delta.read_locks as f64 / count as f64,
         delta.write_locks as f64 / count as f64
     );
@@ -1,27 +1,179 @@
+use crate::cost::*;
+use crate::coverage::Hits;
+use crate::csr::*;
 use crate::decode::*;
 use crate::memory::*;
+use crate::profile::Profile;
+use crate::replay::History;
 
 use log::debug;
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt;
+use std::rc::Rc;
 use thiserror::Error;
 
 //-----------------------------
 
 use Reg::*;
 
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
 pub struct Stats {
     pub instrs: u64,
+
+    // Estimated by the cost model, see VM::set_cost_model().
+    pub cycles: u64,
 }
 
 pub struct VM {
-    reg: Vec<u64>,
+    reg: [u64; 33],
     pub mem: Memory,
     breakpoints: BTreeSet<Addr>,
     last_bp: Option<Addr>,
     pub stats: Stats,
+    pub csrs: CsrFile,
+
+    // The stats before the instruction being executed, which is what
+    // the counter csrs read, see also unwind_fault().
+    stats_before: Stats,
+    extensions: BTreeSet<Extension>,
+    cost: Box<dyn CostModel>,
+
+    // Whether the cost model wants to see data accesses.  Asked once,
+    // rather than every instruction.
+    cost_memory: bool,
+
+    // Decoded blocks by start address.
+    blocks: BTreeMap<u64, Rc<Block>>,
+
+    // A direct mapped cache in front of 'blocks', which is too slow to
+    // search every time execution moves to another block.
+    block_cache: Vec<Option<Rc<Block>>>,
+
+    // The block being executed, and the index of the next instruction
+    // in it.
+    cursor: Option<(Rc<Block>, usize)>,
+
+    // Store-conditionals that will fail regardless of the reservation,
+    // see fail_next_sc().
+    sc_failures: u64,
+
+    // Execution stops once stats.instrs reaches this.
+    instr_limit: Option<u64>,
+
+    // Counts of backward jumps by destination, only collected while
+    // there's a limit.  Used to point at the loop that ate the budget.
+    loop_counts: BTreeMap<u64, u64>,
+
+    // Execution counts per instruction, if coverage is enabled.
+    coverage: Option<Hits>,
+
+    // Instructions per call stack, if profiling is enabled.
+    profile: Option<Profile>,
+
+    // Calls made by the guest, innermost last.  Maintained as calls
+    // and returns are executed, so backtraces don't need to unwind
+    // the guest stack.
+    frames: Vec<Frame>,
+
+    // Execution so far, if recording.
+    history: Option<History>,
+
+    // The last few instructions executed.  A ring, with the oldest at
+    // recent_next once it's full.
+    recent: Vec<Executed>,
+    recent_len: usize,
+    recent_next: usize,
+
+    // The last register, other than the pc, written by set_reg().
+    last_write: Option<(Reg, u64)>,
+}
+
+// How many instructions are remembered by default, see VM::recent().
+const DEFAULT_RECENT_LEN: usize = 32;
+
+// An instruction decoded ahead of being executed.
+struct Decoded {
+    pc: u64,
+    bits: u32,
+    inst: Inst,
+    len: u64,
+    class: InstClass,
+    ext: Option<Extension>,
+}
+
+// A run of instructions ending in a jump, branch or trap, so once the
+// first is executed the rest usually follow.  Decoding each instruction
+// once, rather than every time it's executed, is the vm's main
+// optimisation.
+struct Block {
+    instrs: Vec<Decoded>,
+
+    // Whether any instruction in the block has a breakpoint.  Blocks
+    // without one skip the check before each instruction.
+    has_breakpoint: bool,
 }
 
+impl Block {
+    // Address just past the last instruction.
+    fn end(&self) -> u64 {
+        let last = self.instrs.last().unwrap();
+        last.pc + last.len
+    }
+}
+
+// Limits the size of a block, and so the blocks a breakpoint can be in.
+const MAX_BLOCK_INSTRS: usize = 64;
+const MAX_BLOCK_BYTES: u64 = MAX_BLOCK_INSTRS as u64 * 4;
+
+const BLOCK_CACHE_SIZE: usize = 4096;
+
+fn block_cache_slot(pc: u64) -> usize {
+    ((pc >> 1) as usize) & (BLOCK_CACHE_SIZE - 1)
+}
+
+/// An instruction executed by the vm, see VM::recent().
+#[derive(Clone, Debug)]
+pub struct Executed {
+    pub pc: Addr,
+    pub bits: u32,
+
+    // The register written, and its new value.  None for instructions
+    // that don't write one, or that faulted.
+    pub rd: Option<(Reg, u64)>,
+}
+
+impl Executed {
+    pub fn inst(&self) -> Option<Inst> {
+        decode_instr(self.bits).map(|(inst, _)| inst)
+    }
+}
+
+/// A call made by the guest.
+#[derive(Clone, PartialEq)]
+pub struct Frame {
+    // The address of the call instruction.
+    call_site: Addr,
+
+    // Where the call returns to, and the sp when it was made.
+    ret: u64,
+    sp: u64,
+}
+
+/// The state of the vm at a point in time, see VM::snapshot().
+/// Breakpoints and limits are configuration rather than state, so
+/// aren't included.
+#[derive(Clone)]
+pub struct VmSnapshot {
+    reg: [u64; 33],
+    mem: Memory,
+    csrs: CsrFile,
+    stats: Stats,
+    frames: Vec<Frame>,
+}
+
+// How many loops are reported when the budget runs out.
+const NR_HOT_LOOPS: usize = 5;
+
 impl fmt::Display for VM {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
@@ -84,6 +236,9 @@ pub enum VmErr {
     #[error("Unimplemented instruction: {0:?}")]
     UnimplementedInstruction(Inst),
 
+    #[error("Illegal access to csr 0x{0:x}")]
+    IllegalCsr(u32),
+
     #[error("ecall")]
     ECall,
 
@@ -92,6 +247,19 @@ pub enum VmErr {
 
     #[error("User defined breakpoint")]
     Breakpoint,
+
+    // The instruction at this address accessed a watched range.  It has
+    // completed, collect the hits with Memory::take_watch_hits().
+    #[error("Watchpoint hit by instruction at {0:?}")]
+    Watchpoint(Addr),
+
+    #[error("Instruction budget exhausted at {pc:?}")]
+    BudgetExhausted {
+        pc: Addr,
+
+        // The most frequently taken backward jumps, with their counts.
+        hot_loops: Vec<(Addr, u64)>,
+    },
 }
 
 pub type Result<T> = std::result::Result<T, VmErr>;
@@ -99,22 +267,289 @@ pub type Result<T> = std::result::Result<T, VmErr>;
 impl VM {
     pub fn new(mem: Memory) -> Self {
         VM {
-            reg: vec![0; 33],
+            reg: [0; 33],
             mem,
             breakpoints: BTreeSet::new(),
             last_bp: None,
-            stats: Stats { instrs: 0 },
+            stats: Stats::default(),
+            csrs: CsrFile::new(),
+            stats_before: Stats::default(),
+            extensions: [
+                Extension::Zba,
+                Extension::Zbb,
+                Extension::Zicond,
+                Extension::Zicbom,
+            ]
+            .iter()
+            .cloned()
+            .collect(),
+            cost: Box::new(SimpleCost::default()),
+            cost_memory: false,
+            blocks: BTreeMap::new(),
+            block_cache: vec![None; BLOCK_CACHE_SIZE],
+            cursor: None,
+            sc_failures: 0,
+            instr_limit: None,
+            loop_counts: BTreeMap::new(),
+            coverage: None,
+            profile: None,
+            frames: Vec::new(),
+            history: None,
+            recent: Vec::new(),
+            recent_len: DEFAULT_RECENT_LEN,
+            recent_next: 0,
+            last_write: None,
+        }
+    }
+
+    /// Starts recording which instructions are executed.
+    pub fn enable_coverage(&mut self) {
+        if self.coverage.is_none() {
+            self.coverage = Some(Hits::new());
+        }
+    }
+
+    /// Returns the instructions executed so far, and resets the counts.
+    pub fn take_coverage(&mut self) -> Hits {
+        match &mut self.coverage {
+            Some(hits) => std::mem::take(hits),
+            None => Hits::new(),
+        }
+    }
+
+    /// Replaces the model used to estimate stats.cycles.  The default
+    /// charges a cycle per instruction.
+    pub fn set_cost_model(&mut self, model: Box<dyn CostModel>) {
+        self.cost_memory = model.models_memory();
+        self.cost = model;
+    }
+
+    /// Charges for work a stub does on the guest's behalf, as though the
+    /// guest had executed 'n' instructions of the given class.
+    pub fn charge(&mut self, class: InstClass, n: u64) {
+        self.stats.instrs += n;
+        self.stats.cycles += self.cost.instr(class, false) * n;
+    }
+
+    /// Charges for a stub reading or writing guest memory, eg, memcpy.
+    pub fn charge_access(&mut self, addr: Addr, len: u64, write: bool) {
+        if self.cost_memory {
+            let access = Access {
+                addr: addr.0,
+                len,
+                write,
+            };
+            self.stats.cycles += self.cost.access(&access);
+        }
+    }
+
+    /// The data access the instruction at the pc makes, given the
+    /// current registers.  Used to say what a faulting load or store
+    /// was trying to do.
+    pub fn access_at_pc(&mut self) -> Option<Access> {
+        let decoded = self.decode_at(self.pc().0).ok()?;
+        Access::of(&decoded.inst, &|r| self.reg(r))
+    }
+
+    /// Starts counting the instructions executed by each call stack.
+    pub fn enable_profile(&mut self) {
+        if self.profile.is_none() {
+            self.profile = Some(Profile::new(self.stats.instrs));
+        }
+    }
+
+    /// Returns the profile so far, and starts a new one.  None if
+    /// profiling isn't enabled.
+    pub fn take_profile(&mut self) -> Option<Profile> {
+        let instrs = self.stats.instrs;
+        self.profile.as_mut().map(|p| {
+            p.charge(instrs);
+            std::mem::replace(p, Profile::new(instrs))
+        })
+    }
+
+    /// Tells the profiler the host is calling into the guest, which it
+    /// can't see as there's no call instruction.
+    pub fn note_host_call(&mut self, code: Addr) {
+        if let Some(p) = &mut self.profile {
+            p.call(code.0, self.stats.instrs);
         }
     }
 
+    /// Tells the profiler a call from the host has finished, after
+    /// unwind_to() has dropped any calls it left active.
+    pub fn note_host_return(&mut self) {
+        if let Some(p) = &mut self.profile {
+            p.ret(self.stats.instrs);
+        }
+    }
+
+    /// Sets how many of the most recently executed instructions are
+    /// remembered.  Zero turns it off.
+    pub fn set_recent_len(&mut self, len: usize) {
+        let mut recent: Vec<Executed> = self.recent().cloned().collect();
+        if recent.len() > len {
+            recent.drain(0..(recent.len() - len));
+        }
+        self.recent_next = if recent.len() == len { 0 } else { recent.len() };
+        self.recent = recent;
+        self.recent_len = len;
+    }
+
+    /// The most recently executed instructions, oldest first.
+    pub fn recent(&self) -> impl Iterator<Item = &Executed> {
+        let (newer, older) = self.recent.split_at(self.recent_next);
+        older.iter().chain(newer.iter())
+    }
+
+    /// Takes a copy of the registers and memory.  Memory is shared
+    /// copy-on-write, so this is cheap.
+    pub fn snapshot(&self) -> VmSnapshot {
+        VmSnapshot {
+            reg: self.reg,
+            mem: self.mem.clone(),
+            csrs: self.csrs.clone(),
+            stats: self.stats,
+            frames: self.frames.clone(),
+        }
+    }
+
+    /// Restores a snapshot.  Any recording of execution is dropped.
+    pub fn restore(&mut self, snap: &VmSnapshot) {
+        self.load(snap);
+        if let Some(history) = &mut self.history {
+            history.clear(&self.reg, &self.frames, self.stats);
+        }
+    }
+
+    fn load(&mut self, snap: &VmSnapshot) {
+        let snap = snap.clone();
+        let watchpoints = self.mem.take_watchpoints();
+        self.reg = snap.reg;
+        self.mem = snap.mem;
+        self.mem.set_watchpoints(watchpoints);
+        self.mem.record_changes(self.history.is_some());
+        self.csrs = snap.csrs;
+        self.stats = snap.stats;
+        self.frames = snap.frames;
+        self.last_bp = None;
+        self.flush_blocks();
+    }
+
+    /// The pc, followed by the call site of each active call, innermost
+    /// first.
+    pub fn backtrace(&self) -> Vec<Addr> {
+        let mut addrs = vec![self.pc()];
+        addrs.extend(self.frames.iter().rev().map(|f| f.call_site));
+        addrs
+    }
+
+    /// The number of active calls, see unwind_to().
+    pub fn call_depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Forgets any calls beyond the given depth.  Used when a call
+    /// from the host is abandoned part way through.
+    pub fn unwind_to(&mut self, depth: usize) {
+        if let Some(p) = &mut self.profile {
+            p.unwind(self.frames.len().saturating_sub(depth), self.stats.instrs);
+        }
+        self.frames.truncate(depth);
+    }
+
+    fn push_frame(&mut self, rd: Reg, call_site: Addr, ret: u64, dest: u64) {
+        if rd == Ra {
+            let sp = self.reg(Sp);
+            self.frames.push(Frame { call_site, ret, sp });
+            if let Some(p) = &mut self.profile {
+                p.call(dest, self.stats.instrs);
+            }
+        }
+    }
+
+    // Drops the frames of calls that have returned.  Checking sp stops a
+    // recursive call that jumps to its own return address being taken
+    // for a return.
+    fn pop_frames(&mut self, pc: Addr) {
+        let sp = self.reg(Sp);
+        while let Some(frame) = self.frames.last() {
+            if frame.ret != pc.0 || sp < frame.sp {
+                break;
+            }
+            self.frames.pop();
+            if let Some(p) = &mut self.profile {
+                p.ret(self.stats.instrs);
+            }
+        }
+    }
+
+    /// Execution fails with BudgetExhausted once stats.instrs reaches
+    /// the limit.  Loop counts are reset whenever a limit is imposed
+    /// where there wasn't one.
+    pub fn set_instr_limit(&mut self, limit: Option<u64>) {
+        if self.instr_limit.is_none() {
+            self.loop_counts.clear();
+        }
+        self.instr_limit = limit;
+    }
+
+    pub fn instr_limit(&self) -> Option<u64> {
+        self.instr_limit
+    }
+
+    /// Makes the next 'count' store-conditionals fail, whether or not
+    /// their reservation is intact, so tests can exercise the retry
+    /// paths of atomics.  Call from a hook to fail them at a chosen
+    /// point.
+    pub fn fail_next_sc(&mut self, count: u64) {
+        self.sc_failures = count;
+    }
+
+    fn note_jump(&mut self, pc: Addr, dest: u64) {
+        if self.instr_limit.is_some() && dest <= pc.0 {
+            *self.loop_counts.entry(dest).or_insert(0) += 1;
+        }
+    }
+
+    fn budget_exhausted(&self, pc: Addr) -> VmErr {
+        let mut hot_loops: Vec<(Addr, u64)> = self
+            .loop_counts
+            .iter()
+            .map(|(dest, count)| (Addr(*dest), *count))
+            .collect();
+        hot_loops.sort_by_key(|l| std::cmp::Reverse(l.1));
+        hot_loops.truncate(NR_HOT_LOOPS);
+        VmErr::BudgetExhausted { pc, hot_loops }
+    }
+
+    /// All extensions are enabled by default.  Disabling one makes its
+    /// instructions fail with UnimplementedInstruction, which is useful
+    /// for checking a module doesn't depend on it.
+    pub fn enable_extension(&mut self, ext: Extension, enable: bool) {
+        if enable {
+            self.extensions.insert(ext);
+        } else {
+            self.extensions.remove(&ext);
+        }
+    }
+
+    pub fn extension_enabled(&self, ext: Extension) -> bool {
+        self.extensions.contains(&ext)
+    }
+
     pub fn setup_stack(&mut self, size: u64) -> Result<()> {
         // We put the stack just below the 4G mark.
-        let top = 1 << 32;
-        let base = top - size;
+        self.setup_stack_at(Addr(1 << 32), size)
+    }
+
+    /// Maps a stack of 'size' bytes that grows down from 'top'.
+    pub fn setup_stack_at(&mut self, top: Addr, size: u64) -> Result<()> {
+        let base = top.0 - size;
         self.mem
-            .mmap_zeroes(Addr(base), Addr(top), PERM_READ | PERM_WRITE)
+            .mmap_zeroes(Addr(base), top, PERM_READ | PERM_WRITE)
             .map_err(VmErr::BadAccess)?;
-        self.set_reg(Sp, top);
+        self.set_reg(Sp, top.0);
         Ok(())
     }
 
@@ -128,6 +563,7 @@ impl VM {
         Ok(())
     }
 
+    #[inline]
     pub fn reg(&self, r: Reg) -> u64 {
         if r == Zero {
             0u64
@@ -136,26 +572,39 @@ impl VM {
         }
     }
 
+    #[inline]
     pub fn set_reg(&mut self, r: Reg, v: u64) {
         if r != Zero {
             self.reg[r as usize] = v;
+            if r != PC {
+                self.last_write = Some((r, v));
+            }
         }
     }
 
+    /// Jumps to 'loc'.  A breakpoint there will trigger, even if it's the
+    /// one we've just stopped at, so a stub can call back into the function
+    /// it's stubbing.
     pub fn set_pc(&mut self, loc: Addr) {
         self.reg[PC as usize] = loc.0;
+        self.last_bp = None;
     }
 
+    #[inline]
     pub fn inc_pc(&mut self, delta: u64) {
         self.reg[PC as usize] = self.pc().0.wrapping_add(delta);
     }
 
+    #[inline]
     pub fn pc(&self) -> Addr {
         Addr(self.reg[PC as usize])
     }
 
+    #[inline]
     pub fn branch(&mut self, pred: bool, dest: u64, pc_increment: u64) {
+        self.stats.cycles += self.cost.instr(InstClass::Branch, pred);
         if pred {
+            self.note_jump(self.pc(), dest);
             self.set_reg(PC, dest);
         } else {
             self.inc_pc(pc_increment);
@@ -180,6 +629,35 @@ impl VM {
         Ok(u64::from_le_bytes(bytes))
     }
 
+    fn reserve(&mut self, r: Reg, len: u64) {
+        let begin = self.reg(r);
+        self.mem.reserve(Addr(begin), Addr(begin + len));
+    }
+
+    // Stores only if the reservation made by the last load-reserved is
+    // intact, setting rd to 0 on success and 1 on failure.
+    fn store_conditional(&mut self, rd: Reg, dest: Reg, bytes: &[u8]) -> Result<()> {
+        let begin = Addr(self.reg(dest));
+        let end = Addr(begin.0 + bytes.len() as u64);
+        self.mem
+            .check_perms(begin, end, PERM_WRITE)
+            .map_err(VmErr::BadAccess)?;
+
+        let mut ok = self.mem.take_reservation(begin, end);
+        if self.sc_failures > 0 {
+            self.sc_failures -= 1;
+            ok = false;
+        }
+
+        if ok {
+            self.mem
+                .write(begin, bytes, PERM_WRITE)
+                .map_err(VmErr::BadAccess)?;
+        }
+        self.set_reg(rd, if ok { 0 } else { 1 });
+        Ok(())
+    }
+
     fn set_deref_u32(&mut self, dest: Reg, v: u32) -> Result<()> {
         let dest = Addr(self.reg(dest));
         let bytes = v.to_le_bytes();
@@ -222,6 +700,49 @@ impl VM {
         Ok(())
     }
 
+    // Counters read as they were before the csr instruction retires.
+    fn csr_read(&self, csr: u32) -> Result<u64> {
+        self.csrs
+            .read(csr, self.stats_before.instrs)
+            .ok_or(VmErr::IllegalCsr(csr))
+    }
+
+    fn csr_write(&mut self, csr: u32, v: u64) -> Result<()> {
+        if self.csrs.write(csr, v) {
+            Ok(())
+        } else {
+            Err(VmErr::IllegalCsr(csr))
+        }
+    }
+
+    /// Reads and modifies a csr as per the Zicsr instructions.  'f' is
+    /// passed the old value, and returns the new value if the csr
+    /// should be written.
+    fn csr_op<F: FnOnce(u64) -> Option<u64>>(&mut self, rd: Reg, csr: u32, f: F) -> Result<()> {
+        let old = self.csr_read(csr)?;
+        if let Some(new) = f(old) {
+            self.csr_write(csr, new)?;
+        }
+        self.set_reg(rd, old);
+        Ok(())
+    }
+
+    /// CSRRW and CSRRWI.  These don't read the csr at all if rd is
+    /// x0, so a write only csr doesn't fault.
+    fn csr_swap(&mut self, rd: Reg, csr: u32, v: u64) -> Result<()> {
+        if rd == Zero {
+            self.csr_write(csr, v)
+        } else {
+            self.csr_op(rd, csr, |_| Some(v))
+        }
+    }
+
+    /// Is sstatus.SIE set?  Useful for checking functions restore the
+    /// interrupt state.
+    pub fn interrupts_enabled(&self) -> bool {
+        self.csrs.interrupts_enabled()
+    }
+
     // executes an ad-hoc 'ret' instruction after putting a return value in A0.  Useful for breakpoints.
     pub fn ret(&mut self, v: u64) {
         self.set_reg(A0, v);
@@ -259,8 +780,25 @@ impl VM {
     }
 
     pub fn step(&mut self) -> Result<()> {
+        if self.history.is_some() {
+            self.record();
+        }
+
         let pc = self.pc();
-        if self.breakpoints.contains(&pc) {
+        self.pop_frames(pc);
+
+        if self.mem.code_changed() {
+            for (begin, end) in self.mem.take_code_changes() {
+                self.forget_blocks(begin, end);
+            }
+        }
+
+        // Within a block that has no breakpoints, there's no need to
+        // look for one.
+        let next = self.peek_block(pc);
+        let check_bp = next.unwrap_or(true);
+
+        if check_bp && self.breakpoints.contains(&pc) {
             if self.last_bp.is_none() || self.last_bp.unwrap() != pc {
                 self.last_bp = Some(pc);
                 debug!("hit breakpoint at {:?}", pc);
@@ -270,27 +808,108 @@ impl VM {
             self.last_bp = None;
         }
 
-        let mut bits = self
-            .mem
-            .read_into::<u32>(pc, PERM_EXEC)
-            .map_err(VmErr::BadAccess)?;
+        if let Some(limit) = self.instr_limit {
+            if self.stats.instrs >= limit {
+                return Err(self.budget_exhausted(pc));
+            }
+        }
 
-        let (inst, pc_increment) = decode_instr(bits).ok_or(VmErr::DecodeError(bits))?;
-        if pc_increment == 2 {
-            bits &= 0xffff;
+        if next.is_none() {
+            self.fetch(pc)?;
+        }
+
+        // fetch() leaves the cursor at the start of the block.
+        let (block, i) = self.cursor.as_mut().unwrap();
+        let (block, index) = (block.clone(), *i);
+        *i += 1;
+        self.exec(&block.instrs[index])
+    }
+
+    /// Forgets that the instruction at the pc ran, after it faulted
+    /// without changing any registers or memory.  So it isn't counted
+    /// twice if it's run again, eg, once the fixture has decided a read
+    /// of uninitialised memory is allowed.
+    pub fn unwind_fault(&mut self) {
+        let pc = self.pc().0;
+        self.stats = self.stats_before;
+
+        if let Some(hits) = &mut self.coverage {
+            if let Some(count) = hits.get_mut(&pc) {
+                *count -= 1;
+                if *count == 0 {
+                    hits.remove(&pc);
+                }
+            }
         }
 
+        // The rerun overwrites the same entry.
+        if self.recent_len > 0 {
+            self.recent_next = match self.recent_next {
+                0 => self.recent_len - 1,
+                n => n - 1,
+            };
+        }
+
+        if let Some((block, i)) = &mut self.cursor {
+            if *i > 0 && block.instrs[*i - 1].pc == pc {
+                *i -= 1;
+            }
+        }
+    }
+
+    // Executes a decoded instruction at the pc.
+    fn exec(&mut self, d: &Decoded) -> Result<()> {
+        let pc = Addr(d.pc);
+        let (pc_increment, bits) = (d.len, d.bits);
+
         if pc_increment == 2 {
             // Compressed instruction
-            debug!("{:08x}: {:0>4x}    \t{}", pc, bits, inst);
+            debug!("{:08x}: {:0>4x}    \t{}", pc, bits, d.inst);
         } else {
-            debug!("{:08x}: {:0>8x}\t{}", pc, bits, inst);
+            debug!("{:08x}: {:0>8x}\t{}", pc, bits, d.inst);
+        }
+
+        if let Some(ext) = d.ext {
+            if !self.extensions.contains(&ext) {
+                return Err(VmErr::UnimplementedInstruction(d.inst));
+            }
         }
 
+        self.stats_before = self.stats;
         self.stats.instrs += 1;
 
+        // Branches are charged once it's known whether they're taken.
+        if d.class != InstClass::Branch {
+            self.stats.cycles += self.cost.instr(d.class, false);
+        }
+        if self.cost_memory {
+            if let Some(access) = Access::of(&d.inst, &|r| self.reg(r)) {
+                self.stats.cycles += self.cost.access(&access);
+            }
+        }
+
+        if let Some(hits) = &mut self.coverage {
+            *hits.entry(pc.0).or_insert(0) += 1;
+        }
+
+        let recent = self.recent_next;
+        if self.recent_len > 0 {
+            let e = Executed { pc, bits, rd: None };
+            if recent < self.recent.len() {
+                self.recent[recent] = e;
+            } else {
+                self.recent.push(e);
+            }
+            self.recent_next = if recent + 1 == self.recent_len {
+                0
+            } else {
+                recent + 1
+            };
+            self.last_write = None;
+        }
+
         use Inst::*;
-        match inst {
+        match d.inst {
             LUI { rd, imm } => {
                 self.set_reg(rd, (imm << 12) as i64 as u64);
                 self.inc_pc(pc_increment);
@@ -303,6 +922,11 @@ impl VM {
                 let dest = pc.0.wrapping_add(imm as i64 as u64);
                 let ret = pc.0.wrapping_add(pc_increment);
 
+                // Calls aren't loops, even when the callee is below us.
+                if rd == Zero {
+                    self.note_jump(pc, dest);
+                }
+                self.push_frame(rd, pc, ret, dest);
                 self.set_reg(PC, dest);
                 self.set_reg(rd, ret);
             }
@@ -310,6 +934,7 @@ impl VM {
                 let dest = self.reg(rs).wrapping_add(imm as i64 as u64);
                 let ret = pc.0.wrapping_add(pc_increment);
 
+                self.push_frame(rd, pc, ret, dest);
                 self.set_reg(rd, ret);
                 self.set_reg(PC, dest);
             }
@@ -688,6 +1313,168 @@ impl VM {
                 self.set_reg(rd, v as i32 as u64);
                 self.inc_pc(pc_increment);
             }
+            SH1ADD { rd, rs1, rs2 } => {
+                self.set_reg(rd, (self.reg(rs1) << 1).wrapping_add(self.reg(rs2)));
+                self.inc_pc(pc_increment);
+            }
+            SH2ADD { rd, rs1, rs2 } => {
+                self.set_reg(rd, (self.reg(rs1) << 2).wrapping_add(self.reg(rs2)));
+                self.inc_pc(pc_increment);
+            }
+            SH3ADD { rd, rs1, rs2 } => {
+                self.set_reg(rd, (self.reg(rs1) << 3).wrapping_add(self.reg(rs2)));
+                self.inc_pc(pc_increment);
+            }
+            ADDUW { rd, rs1, rs2 } => {
+                let rs1 = self.reg(rs1) as u32 as u64;
+                self.set_reg(rd, rs1.wrapping_add(self.reg(rs2)));
+                self.inc_pc(pc_increment);
+            }
+            SH1ADDUW { rd, rs1, rs2 } => {
+                let rs1 = self.reg(rs1) as u32 as u64;
+                self.set_reg(rd, (rs1 << 1).wrapping_add(self.reg(rs2)));
+                self.inc_pc(pc_increment);
+            }
+            SH2ADDUW { rd, rs1, rs2 } => {
+                let rs1 = self.reg(rs1) as u32 as u64;
+                self.set_reg(rd, (rs1 << 2).wrapping_add(self.reg(rs2)));
+                self.inc_pc(pc_increment);
+            }
+            SH3ADDUW { rd, rs1, rs2 } => {
+                let rs1 = self.reg(rs1) as u32 as u64;
+                self.set_reg(rd, (rs1 << 3).wrapping_add(self.reg(rs2)));
+                self.inc_pc(pc_increment);
+            }
+            SLLIUW { rd, rs, shamt } => {
+                let rs = self.reg(rs) as u32 as u64;
+                self.set_reg(rd, rs << shamt);
+                self.inc_pc(pc_increment);
+            }
+            ANDN { rd, rs1, rs2 } => {
+                self.set_reg(rd, self.reg(rs1) & !self.reg(rs2));
+                self.inc_pc(pc_increment);
+            }
+            ORN { rd, rs1, rs2 } => {
+                self.set_reg(rd, self.reg(rs1) | !self.reg(rs2));
+                self.inc_pc(pc_increment);
+            }
+            XNOR { rd, rs1, rs2 } => {
+                self.set_reg(rd, !(self.reg(rs1) ^ self.reg(rs2)));
+                self.inc_pc(pc_increment);
+            }
+            CLZ { rd, rs } => {
+                self.set_reg(rd, self.reg(rs).leading_zeros() as u64);
+                self.inc_pc(pc_increment);
+            }
+            CLZW { rd, rs } => {
+                self.set_reg(rd, (self.reg(rs) as u32).leading_zeros() as u64);
+                self.inc_pc(pc_increment);
+            }
+            CTZ { rd, rs } => {
+                self.set_reg(rd, self.reg(rs).trailing_zeros() as u64);
+                self.inc_pc(pc_increment);
+            }
+            CTZW { rd, rs } => {
+                self.set_reg(rd, (self.reg(rs) as u32).trailing_zeros() as u64);
+                self.inc_pc(pc_increment);
+            }
+            CPOP { rd, rs } => {
+                self.set_reg(rd, self.reg(rs).count_ones() as u64);
+                self.inc_pc(pc_increment);
+            }
+            CPOPW { rd, rs } => {
+                self.set_reg(rd, (self.reg(rs) as u32).count_ones() as u64);
+                self.inc_pc(pc_increment);
+            }
+            MAX { rd, rs1, rs2 } => {
+                let v = i64::max(self.reg(rs1) as i64, self.reg(rs2) as i64);
+                self.set_reg(rd, v as u64);
+                self.inc_pc(pc_increment);
+            }
+            MAXU { rd, rs1, rs2 } => {
+                self.set_reg(rd, u64::max(self.reg(rs1), self.reg(rs2)));
+                self.inc_pc(pc_increment);
+            }
+            MIN { rd, rs1, rs2 } => {
+                let v = i64::min(self.reg(rs1) as i64, self.reg(rs2) as i64);
+                self.set_reg(rd, v as u64);
+                self.inc_pc(pc_increment);
+            }
+            MINU { rd, rs1, rs2 } => {
+                self.set_reg(rd, u64::min(self.reg(rs1), self.reg(rs2)));
+                self.inc_pc(pc_increment);
+            }
+            SEXTB { rd, rs } => {
+                self.set_reg(rd, self.reg(rs) as i8 as i64 as u64);
+                self.inc_pc(pc_increment);
+            }
+            SEXTH { rd, rs } => {
+                self.set_reg(rd, self.reg(rs) as i16 as i64 as u64);
+                self.inc_pc(pc_increment);
+            }
+            ZEXTH { rd, rs } => {
+                self.set_reg(rd, self.reg(rs) as u16 as u64);
+                self.inc_pc(pc_increment);
+            }
+            ROL { rd, rs1, rs2 } => {
+                let shamt = (self.reg(rs2) & 0b111111) as u32;
+                self.set_reg(rd, self.reg(rs1).rotate_left(shamt));
+                self.inc_pc(pc_increment);
+            }
+            ROLW { rd, rs1, rs2 } => {
+                let shamt = (self.reg(rs2) & 0b11111) as u32;
+                let v = (self.reg(rs1) as u32).rotate_left(shamt);
+                self.set_reg(rd, v as i32 as i64 as u64);
+                self.inc_pc(pc_increment);
+            }
+            ROR { rd, rs1, rs2 } => {
+                let shamt = (self.reg(rs2) & 0b111111) as u32;
+                self.set_reg(rd, self.reg(rs1).rotate_right(shamt));
+                self.inc_pc(pc_increment);
+            }
+            RORW { rd, rs1, rs2 } => {
+                let shamt = (self.reg(rs2) & 0b11111) as u32;
+                let v = (self.reg(rs1) as u32).rotate_right(shamt);
+                self.set_reg(rd, v as i32 as i64 as u64);
+                self.inc_pc(pc_increment);
+            }
+            RORI { rd, rs, shamt } => {
+                self.set_reg(rd, self.reg(rs).rotate_right(shamt));
+                self.inc_pc(pc_increment);
+            }
+            RORIW { rd, rs, shamt } => {
+                let v = (self.reg(rs) as u32).rotate_right(shamt);
+                self.set_reg(rd, v as i32 as i64 as u64);
+                self.inc_pc(pc_increment);
+            }
+            ORCB { rd, rs } => {
+                let mut bytes = self.reg(rs).to_le_bytes();
+                for b in &mut bytes {
+                    if *b != 0 {
+                        *b = 0xff;
+                    }
+                }
+                self.set_reg(rd, u64::from_le_bytes(bytes));
+                self.inc_pc(pc_increment);
+            }
+            REV8 { rd, rs } => {
+                self.set_reg(rd, self.reg(rs).swap_bytes());
+                self.inc_pc(pc_increment);
+            }
+            CZEROEQZ { rd, rs1, rs2 } => {
+                let v = if self.reg(rs2) == 0 { 0 } else { self.reg(rs1) };
+                self.set_reg(rd, v);
+                self.inc_pc(pc_increment);
+            }
+            CZERONEZ { rd, rs1, rs2 } => {
+                let v = if self.reg(rs2) != 0 { 0 } else { self.reg(rs1) };
+                self.set_reg(rd, v);
+                self.inc_pc(pc_increment);
+            }
+            CBOCLEAN { .. } | CBOFLUSH { .. } | CBOINVAL { .. } => {
+                // No caches
+                self.inc_pc(pc_increment);
+            }
             FENCE {} => {
                 self.inc_pc(pc_increment);
             }
@@ -697,11 +1484,12 @@ impl VM {
 
             LRW { rd, rs } => {
                 self.set_reg(rd, self.deref_u32(rs)? as i32 as i64 as u64);
+                self.reserve(rs, 4);
                 self.inc_pc(pc_increment);
             }
             SCW { rd, rs1, rs2 } => {
-                self.set_deref_u32(rs1, self.reg(rs2) as u32)?;
-                self.set_reg(rd, 0);
+                let bytes = (self.reg(rs2) as u32).to_le_bytes();
+                self.store_conditional(rd, rs1, &bytes)?;
                 self.inc_pc(pc_increment);
             }
             AMOSWAPW { rd, rs1, rs2 } => {
@@ -744,11 +1532,12 @@ impl VM {
             }
             LRD { rd, rs } => {
                 self.set_reg(rd, self.deref_u64(rs)? as i64 as u64);
+                self.reserve(rs, 8);
                 self.inc_pc(pc_increment);
             }
             SCD { rd, rs1, rs2 } => {
-                self.set_deref_u64(rs1, self.reg(rs2))?;
-                self.set_reg(rd, 0);
+                let bytes = self.reg(rs2).to_le_bytes();
+                self.store_conditional(rd, rs1, &bytes)?;
                 self.inc_pc(pc_increment);
             }
             AMOSWAPD { rd, rs1, rs2 } => {
@@ -795,6 +1584,55 @@ impl VM {
             EBREAK => {
                 return Err(VmErr::EBreak);
             }
+            WFI => {
+                // Interrupts are never delivered, so there's nothing to wait for.
+                self.inc_pc(pc_increment);
+            }
+            SFENCEVMA { .. } => {
+                // No mmu
+                self.inc_pc(pc_increment);
+            }
+            CSRRW { rd, rs, csr } => {
+                let v = self.reg(rs);
+                self.csr_swap(rd, csr, v)?;
+                self.inc_pc(pc_increment);
+            }
+            CSRRS { rd, rs, csr } => {
+                let v = self.reg(rs);
+                let write = rs != Zero;
+                self.csr_op(rd, csr, |old| if write { Some(old | v) } else { None })?;
+                self.inc_pc(pc_increment);
+            }
+            CSRRC { rd, rs, csr } => {
+                let v = self.reg(rs);
+                let write = rs != Zero;
+                self.csr_op(rd, csr, |old| if write { Some(old & !v) } else { None })?;
+                self.inc_pc(pc_increment);
+            }
+            CSRRWI { rd, uimm, csr } => {
+                self.csr_swap(rd, csr, uimm as u64)?;
+                self.inc_pc(pc_increment);
+            }
+            CSRRSI { rd, uimm, csr } => {
+                let v = uimm as u64;
+                self.csr_op(rd, csr, |old| if v != 0 { Some(old | v) } else { None })?;
+                self.inc_pc(pc_increment);
+            }
+            CSRRCI { rd, uimm, csr } => {
+                let v = uimm as u64;
+                self.csr_op(rd, csr, |old| if v != 0 { Some(old & !v) } else { None })?;
+                self.inc_pc(pc_increment);
+            }
+        }
+
+        if self.recent_len > 0 {
+            if let Some((r, v)) = self.last_write {
+                self.recent[recent].rd = Some((r, v));
+            }
+        }
+
+        if self.mem.has_watch_hits() {
+            return Err(VmErr::Watchpoint(pc));
         }
 
         Ok(())
@@ -803,16 +1641,596 @@ impl VM {
     pub fn run(&mut self) -> Result<()> {
         loop {
             self.step()?;
+            self.run_block()?;
         }
     }
 
+    // Executes the rest of the current block, without the checks step()
+    // makes before each instruction.  Stops early if anything needs
+    // them, leaving step() to carry on.
+    fn run_block(&mut self) -> Result<()> {
+        let (block, mut i) = match &self.cursor {
+            Some((block, i)) => (block.clone(), *i),
+            None => return Ok(()),
+        };
+        if block.has_breakpoint || self.history.is_some() || self.last_bp.is_some() {
+            return Ok(());
+        }
+
+        // There's no need to pop frames, since a call always ends a block
+        // so return addresses start one.
+        while let Some(d) = block.instrs.get(i) {
+            if d.pc != self.reg[PC as usize] || self.mem.code_changed() {
+                break;
+            }
+
+            if let Some(limit) = self.instr_limit {
+                if self.stats.instrs >= limit {
+                    break;
+                }
+            }
+
+            i += 1;
+            if let Some((_, cursor)) = &mut self.cursor {
+                *cursor = i;
+            }
+            self.exec(d)?;
+        }
+
+        Ok(())
+    }
+
     pub fn add_breakpoint(&mut self, loc: Addr) {
         self.breakpoints.insert(loc);
+        self.forget_blocks(loc.0, loc.0 + 1);
     }
 
     pub fn rm_breakpoint(&mut self, loc: Addr) -> bool {
+        self.forget_blocks(loc.0, loc.0 + 1);
         self.breakpoints.remove(&loc)
     }
 }
 
 //------------------------
+// Decoded blocks
+
+impl VM {
+    fn flush_blocks(&mut self) {
+        self.blocks.clear();
+        for slot in &mut self.block_cache {
+            *slot = None;
+        }
+        self.cursor = None;
+        self.mem.take_code_changes();
+    }
+
+    // Drops the blocks overlapping a range, so they're decoded again,
+    // eg, with the current breakpoints, or from the new code.
+    fn forget_blocks(&mut self, begin: u64, end: u64) {
+        let first = begin.saturating_sub(MAX_BLOCK_BYTES);
+        let stale: Vec<u64> = self
+            .blocks
+            .range(first..end)
+            .filter(|(_, block)| block.end() > begin)
+            .map(|(pc, _)| *pc)
+            .collect();
+        for pc in stale {
+            self.blocks.remove(&pc);
+            self.block_cache[block_cache_slot(pc)] = None;
+        }
+        self.cursor = None;
+    }
+
+    // If the pc is at the next instruction in the block being executed,
+    // returns whether the block has any breakpoints.
+    fn peek_block(&self, pc: Addr) -> Option<bool> {
+        let (block, i) = self.cursor.as_ref()?;
+        let d = block.instrs.get(*i)?;
+        if d.pc == pc.0 {
+            Some(block.has_breakpoint)
+        } else {
+            None
+        }
+    }
+
+    // Moves the cursor to the block at pc, decoding it if it hasn't been
+    // already.
+    fn fetch(&mut self, pc: Addr) -> Result<()> {
+        let slot = block_cache_slot(pc.0);
+        let block = match &self.block_cache[slot] {
+            Some(block) if block.instrs[0].pc == pc.0 => block.clone(),
+            _ => {
+                let block = match self.blocks.get(&pc.0) {
+                    Some(block) => block.clone(),
+                    None => {
+                        let block = Rc::new(self.decode_block(pc)?);
+                        self.blocks.insert(pc.0, block.clone());
+                        block
+                    }
+                };
+                self.block_cache[slot] = Some(block.clone());
+                block
+            }
+        };
+        self.cursor = Some((block, 0));
+        Ok(())
+    }
+
+    fn decode_at(&mut self, pc: u64) -> Result<Decoded> {
+        let mut bits = self
+            .mem
+            .read_into::<u32>(Addr(pc), PERM_EXEC)
+            .map_err(VmErr::BadAccess)?;
+
+        let (inst, len) = decode_instr(bits).ok_or(VmErr::DecodeError(bits))?;
+        if len == 2 {
+            bits &= 0xffff;
+        }
+
+        Ok(Decoded {
+            pc,
+            bits,
+            inst,
+            len,
+            class: InstClass::of(&inst),
+            ext: inst.extension(),
+        })
+    }
+
+    // Decodes instructions up to the next jump, branch or trap.  Only
+    // a failure to decode the first instruction is an error; the block
+    // ends before any later one, which fails when it's reached.
+    fn decode_block(&mut self, pc: Addr) -> Result<Block> {
+        let mut instrs = vec![self.decode_at(pc.0)?];
+        loop {
+            let last = instrs.last().unwrap();
+            if instrs.len() >= MAX_BLOCK_INSTRS
+                || matches!(
+                    last.class,
+                    InstClass::Jump | InstClass::Branch | InstClass::System
+                )
+            {
+                break;
+            }
+
+            match self.decode_at(last.pc + last.len) {
+                Ok(d) => instrs.push(d),
+                Err(_) => break,
+            }
+        }
+
+        let end = instrs.last().map_or(pc.0, |d| d.pc + d.len);
+        let has_breakpoint = self.breakpoints.range(pc..Addr(end)).next().is_some();
+        Ok(Block {
+            instrs,
+            has_breakpoint,
+        })
+    }
+}
+
+//------------------------
+// Record and replay
+
+impl VM {
+    /// Starts recording execution, so it can be stepped backwards.  Only
+    /// the last 'limit' steps are kept.  A step is an instruction, or
+    /// the changes made by a stub.  CSRs aren't recorded.
+    pub fn start_recording(&mut self, limit: usize) {
+        self.history = Some(History::new(limit, &self.reg, &self.frames, self.stats));
+        self.mem.take_changes();
+        self.mem.record_changes(true);
+    }
+
+    pub fn stop_recording(&mut self) {
+        self.history = None;
+        self.mem.record_changes(false);
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.history.is_some()
+    }
+
+    // Logs the changes made since the last step.
+    fn record(&mut self) {
+        let mem = self.mem.take_changes();
+        let checkpoint = match &mut self.history {
+            Some(history) => history.record(&self.reg, &self.frames, self.stats, mem),
+            None => return,
+        };
+
+        if checkpoint {
+            let snap = self.snapshot();
+            if let Some(history) = &mut self.history {
+                history.add_checkpoint(snap);
+            }
+        }
+    }
+
+    /// Undoes the last step.  Returns false if there's nothing left in
+    /// the recording.
+    pub fn reverse_step(&mut self) -> bool {
+        self.record();
+        let history = match &mut self.history {
+            Some(history) => history,
+            None => return false,
+        };
+
+        match history.back() {
+            Some(step) => {
+                for (r, old, _) in &step.regs {
+                    self.reg[*r] = *old;
+                }
+                for change in step.mem.iter().rev() {
+                    self.mem.undo(change);
+                }
+                if let Some((old, _)) = &step.frames {
+                    self.frames = old.clone();
+                }
+                self.stats = step.stats.0;
+            }
+            None => return false,
+        }
+
+        history.sync(&self.reg, &self.frames, self.stats);
+        self.last_bp = None;
+        true
+    }
+
+    /// Redoes a step undone by reverse_step().  Returns false once the
+    /// end of the recording is reached; from there the vm must be run
+    /// as normal.
+    pub fn replay_step(&mut self) -> bool {
+        let history = match &mut self.history {
+            Some(history) => history,
+            None => return false,
+        };
+
+        match history.forward() {
+            Some(step) => {
+                for (r, _, new) in &step.regs {
+                    self.reg[*r] = *new;
+                }
+                for change in &step.mem {
+                    self.mem.redo(change);
+                }
+                if let Some((_, new)) = &step.frames {
+                    self.frames = new.clone();
+                }
+                self.stats = step.stats.1;
+            }
+            None => return false,
+        }
+
+        history.sync(&self.reg, &self.frames, self.stats);
+        self.last_bp = None;
+        true
+    }
+
+    /// Steps backwards until 'stop' holds.  Returns false if the start
+    /// of the recording is reached first.
+    pub fn reverse_until<F: FnMut(&VM) -> bool>(&mut self, mut stop: F) -> bool {
+        while self.reverse_step() {
+            if stop(self) {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Steps backwards to the last breakpoint.
+    pub fn reverse_continue(&mut self) -> bool {
+        self.reverse_until(|vm| vm.breakpoints.contains(&vm.pc()))
+    }
+
+    /// Steps backwards to just before the last write to [begin, end), so
+    /// the pc is that of the instruction, or stub, that made it.
+    pub fn reverse_to_write(&mut self, begin: Addr, end: Addr) -> bool {
+        self.record();
+        loop {
+            let wrote = match self.history.as_ref().and_then(|h| h.prev()) {
+                Some(step) => step.mem.iter().any(|c| c.writes(begin, end)),
+                None => return false,
+            };
+
+            self.reverse_step();
+            if wrote {
+                return true;
+            }
+        }
+    }
+
+    /// Moves backwards or forwards through the recording to the first
+    /// point where stats.instrs reached 'instrs'.  Returns false if
+    /// that's not in the recording.
+    pub fn seek(&mut self, instrs: u64) -> bool {
+        self.record();
+        let history = match &self.history {
+            Some(history) => history,
+            None => return false,
+        };
+
+        let (first, last) = history.instrs_range();
+        if instrs < first || instrs > last {
+            return false;
+        }
+
+        // Start from a checkpoint if it's closer.
+        let here = self.stats.instrs;
+        if let Some((cursor, cp_instrs, snap)) = history.checkpoint_before(instrs) {
+            let closer = if here <= instrs {
+                cp_instrs > here
+            } else {
+                instrs - cp_instrs < here - instrs
+            };
+
+            if closer {
+                let snap = snap.clone();
+                self.load(&snap);
+                if let Some(history) = &mut self.history {
+                    history.jump(cursor, &self.reg, &self.frames, self.stats);
+                }
+            }
+        }
+
+        while self.stats.instrs < instrs && self.replay_step() {}
+        while matches!(self.history.as_ref().and_then(|h| h.prev()), Some(step) if step.stats.0.instrs >= instrs)
+        {
+            self.reverse_step();
+        }
+        true
+    }
+}
+
+//------------------------
+
+#[cfg(test)]
+pub(crate) fn code_bytes(code: &[u32]) -> Vec<u8> {
+    code.iter().flat_map(|i| i.to_le_bytes().to_vec()).collect()
+}
+
+// A vm with 'code' mapped at 0x1000, and the pc pointing at it.
+#[cfg(test)]
+pub(crate) fn vm_with_code(code: &[u32]) -> VM {
+    let mut mem = Memory::new(Addr(0x10000), Addr(0x20000));
+    mem.mmap_bytes(Addr(0x1000), &code_bytes(code), PERM_EXEC)
+        .unwrap();
+    let mut vm = VM::new(mem);
+    vm.set_pc(Addr(0x1000));
+    vm
+}
+
+#[test]
+fn test_budget_exhausted() {
+    // loop: addi a0,a0,1; j loop
+    let mut vm = vm_with_code(&[0x00150513, 0xffdff06f]);
+    vm.set_instr_limit(Some(100));
+
+    match vm.run() {
+        Err(VmErr::BudgetExhausted { pc, hot_loops }) => {
+            assert_eq!(pc, Addr(0x1000));
+            assert_eq!(hot_loops, vec![(Addr(0x1000), 50)]);
+        }
+        r => panic!("unexpected result: {:?}", r),
+    }
+    assert_eq!(vm.reg(A0), 50);
+}
+
+#[test]
+fn test_budget_exhausted_ignores_calls() {
+    // f: ret; loop: jal f; j loop
+    let mut vm = vm_with_code(&[0x00008067, 0xffdff0ef, 0xffdff06f]);
+    vm.set_pc(Addr(0x1004));
+    vm.set_instr_limit(Some(99));
+
+    match vm.run() {
+        Err(VmErr::BudgetExhausted { hot_loops, .. }) => {
+            assert_eq!(hot_loops, vec![(Addr(0x1004), 33)]);
+        }
+        r => panic!("unexpected result: {:?}", r),
+    }
+}
+
+#[test]
+fn test_backtrace() {
+    // 0x1000: jal f; ebreak
+    // f:      jal g; ebreak
+    // g:      ret
+    let mut vm = vm_with_code(&[0x008000ef, 0x00100073, 0x008000ef, 0x00100073, 0x00008067]);
+    vm.setup_stack(4096).unwrap();
+    vm.step().unwrap();
+    vm.step().unwrap();
+    assert_eq!(
+        vm.backtrace(),
+        vec![Addr(0x1010), Addr(0x1008), Addr(0x1000)]
+    );
+
+    // g returns, and f hits the ebreak.
+    assert!(matches!(vm.run(), Err(VmErr::EBreak)));
+    assert_eq!(vm.backtrace(), vec![Addr(0x100c), Addr(0x1000)]);
+}
+
+#[test]
+fn test_breakpoint_reentry() {
+    // addi a0,a0,1
+    let mut vm = vm_with_code(&[0x00150513]);
+    vm.add_breakpoint(Addr(0x1000));
+    assert!(matches!(vm.step(), Err(VmErr::Breakpoint)));
+
+    // Resuming runs the instruction.
+    vm.step().unwrap();
+    assert_eq!(vm.reg(A0), 1);
+
+    // But jumping back triggers the breakpoint again.
+    vm.set_pc(Addr(0x1000));
+    assert!(matches!(vm.step(), Err(VmErr::Breakpoint)));
+    vm.set_pc(Addr(0x1000));
+    assert!(matches!(vm.step(), Err(VmErr::Breakpoint)));
+}
+
+#[test]
+fn test_reverse_step() {
+    // addi a0,a0,1; sd a0,-8(sp); addi a0,a0,1; sd a0,-8(sp); ebreak
+    let mut vm = vm_with_code(&[0x00150513, 0xfea13c23, 0x00150513, 0xfea13c23, 0x00100073]);
+    vm.setup_stack(4096).unwrap();
+    vm.start_recording(100);
+    assert!(matches!(vm.run(), Err(VmErr::EBreak)));
+
+    let slot = Addr(vm.reg(Sp) - 8);
+    let end = Addr(slot.0 + 8);
+    assert!(vm.reverse_to_write(slot, end));
+    assert_eq!(vm.pc(), Addr(0x100c));
+    assert_eq!(vm.reg(A0), 2);
+    assert_eq!(vm.mem.read_into::<u64>(slot, 0).unwrap(), 1);
+
+    assert!(vm.reverse_to_write(slot, end));
+    assert_eq!(vm.pc(), Addr(0x1004));
+    assert_eq!(vm.reg(A0), 1);
+
+    // No more writes, so we end up at the start.
+    assert!(!vm.reverse_to_write(slot, end));
+    assert_eq!(vm.pc(), Addr(0x1000));
+    assert_eq!(vm.stats.instrs, 0);
+
+    assert!(vm.seek(4));
+    assert_eq!(vm.pc(), Addr(0x1010));
+    assert_eq!(vm.mem.read_into::<u64>(slot, 0).unwrap(), 2);
+
+    // Only the ebreak is left.
+    assert!(vm.replay_step());
+    assert!(!vm.replay_step());
+    assert_eq!(vm.stats.instrs, 5);
+}
+
+#[test]
+fn test_block_invalidation() {
+    // addi a0,a0,1; addi a0,a0,1; ebreak
+    let code = [0x00150513, 0x00150513, 0x00100073];
+    let mut vm = vm_with_code(&code);
+    assert!(matches!(vm.run(), Err(VmErr::EBreak)));
+    assert_eq!(vm.reg(A0), 2);
+
+    // Patching the code is seen, even though the block was decoded.
+    vm.mem
+        .write(Addr(0x1004), &0x00250513u32.to_le_bytes(), 0)
+        .unwrap();
+    vm.set_pc(Addr(0x1000));
+    assert!(matches!(vm.run(), Err(VmErr::EBreak)));
+    assert_eq!(vm.reg(A0), 5);
+
+    // Mapping more code keeps the blocks already decoded, and writing
+    // to it only drops its own.
+    vm.mem
+        .mmap_bytes(Addr(0x2000), &code_bytes(&code), PERM_EXEC)
+        .unwrap();
+    vm.set_reg(A0, 0);
+    vm.set_pc(Addr(0x2000));
+    assert!(matches!(vm.run(), Err(VmErr::EBreak)));
+    assert_eq!(vm.reg(A0), 2);
+    assert!(vm.blocks.contains_key(&0x1000));
+    vm.mem
+        .write(Addr(0x2000), &0x00250513u32.to_le_bytes(), 0)
+        .unwrap();
+    vm.set_pc(Addr(0x2000));
+    assert!(matches!(vm.run(), Err(VmErr::EBreak)));
+    assert_eq!(vm.reg(A0), 5);
+    assert!(vm.blocks.contains_key(&0x1000));
+
+    // As is a breakpoint in the middle of a block.
+    vm.add_breakpoint(Addr(0x1004));
+    vm.set_pc(Addr(0x1000));
+    assert!(matches!(vm.run(), Err(VmErr::Breakpoint)));
+    assert_eq!(vm.pc(), Addr(0x1004));
+    assert_eq!(vm.reg(A0), 6);
+}
+
+#[test]
+fn test_csr_instrs() {
+    // csrw sscratch,a0; csrrw a1,sscratch,a2; csrrs a3,sscratch,a4;
+    // csrrc a5,sscratch,a0; csrrwi a6,sscratch,5; csrsi sscratch,2;
+    // csrci sstatus,2; csrr t0,sscratch; rdinstret t1; csrw cycle,a0
+    let mut vm = vm_with_code(&[
+        0x14051073, 0x140615f3, 0x140726f3, 0x140537f3, 0x1402d873, 0x14016073, 0x10017073,
+        0x140022f3, 0xc0202373, 0xc0051073,
+    ]);
+    vm.set_reg(A0, 0x101);
+    vm.set_reg(A2, 0x0f);
+    vm.set_reg(A4, 0x300);
+    assert!(vm.interrupts_enabled());
+
+    // cycle is read only.
+    assert!(matches!(vm.run(), Err(VmErr::IllegalCsr(0xc00))));
+    assert_eq!(vm.pc(), Addr(0x1024));
+    assert_eq!(vm.reg(A1), 0x101);
+    assert_eq!(vm.reg(A3), 0x0f);
+    assert_eq!(vm.reg(A5), 0x30f);
+    assert_eq!(vm.reg(A6), 0x20e);
+    assert_eq!(vm.reg(T0), 7);
+    assert_eq!(vm.reg(T1), 8);
+    assert!(!vm.interrupts_enabled());
+}
+
+#[test]
+fn test_bitmanip_instrs() {
+    // sh2add a2,a0,a1; add.uw a3,a0,a1; andn a4,a0,a1; clz a5,a1;
+    // ctzw t0,a1; cpop t1,a0; max t2,a0,a1; sext.b t3,a0; rev8 t4,a1;
+    // orc.b t5,a0; rolw t6,a0,a1; czero.eqz a6,a1,a0;
+    // czero.nez a7,a1,a0; ebreak
+    let mut vm = vm_with_code(&[
+        0x20b54633, 0x08b506bb, 0x40b57733, 0x60059793, 0x6015929b, 0x60251313, 0x0ab563b3,
+        0x60451e13, 0x6b85de93, 0x28755f13, 0x60b51fbb, 0x0ea5d833, 0x0ea5f8b3, 0x00100073,
+    ]);
+    vm.set_reg(A0, 0xffff_ffff_8000_0011);
+    vm.set_reg(A1, 0x10);
+    assert!(matches!(vm.run(), Err(VmErr::EBreak)));
+
+    // Zba
+    assert_eq!(vm.reg(A2), 0xffff_fffe_0000_0054);
+    assert_eq!(vm.reg(A3), 0x8000_0021);
+
+    // Zbb
+    assert_eq!(vm.reg(A4), 0xffff_ffff_8000_0001);
+    assert_eq!(vm.reg(A5), 59);
+    assert_eq!(vm.reg(T0), 4);
+    assert_eq!(vm.reg(T1), 35);
+    assert_eq!(vm.reg(T2), 0x10);
+    assert_eq!(vm.reg(T3), 0x11);
+    assert_eq!(vm.reg(T4), 0x1000_0000_0000_0000);
+    assert_eq!(vm.reg(T5), 0xffff_ffff_ff00_00ff);
+    assert_eq!(vm.reg(T6), 0x11_8000);
+
+    // Zicond
+    assert_eq!(vm.reg(A6), 0x10);
+    assert_eq!(vm.reg(A7), 0);
+}
+
+#[test]
+fn test_lr_sc() {
+    // lr.w a1,(a0); addi a1,a1,1; sc.w a2,a1,(a0); ebreak
+    let mut vm = vm_with_code(&[0x100525af, 0x00158593, 0x18b5262f, 0x00100073]);
+    let ptr = vm.mem.alloc(4).unwrap();
+    vm.mem.write(ptr, &0u32.to_le_bytes(), PERM_WRITE).unwrap();
+    vm.set_reg(A0, ptr.0);
+    let run = |vm: &mut VM| {
+        vm.set_pc(Addr(0x1000));
+        assert!(matches!(vm.run(), Err(VmErr::EBreak)));
+        (vm.reg(A2), vm.mem.read_into::<u32>(ptr, 0).unwrap())
+    };
+    assert_eq!(run(&mut vm), (0, 1));
+
+    // Another hart writes between the lr and sc.
+    vm.set_pc(Addr(0x1000));
+    vm.step().unwrap();
+    vm.mem.write(ptr, &10u32.to_le_bytes(), 0).unwrap();
+    assert!(matches!(vm.run(), Err(VmErr::EBreak)));
+    assert_eq!(vm.reg(A2), 1);
+    assert_eq!(vm.mem.read_into::<u32>(ptr, 0).unwrap(), 10);
+
+    vm.fail_next_sc(1);
+    assert_eq!(run(&mut vm), (1, 10));
+    assert_eq!(run(&mut vm), (0, 11));
+
+    // The sc used up the reservation.
+    vm.set_pc(Addr(0x1008));
+    assert!(matches!(vm.run(), Err(VmErr::EBreak)));
+    assert_eq!(vm.reg(A2), 1);
+}